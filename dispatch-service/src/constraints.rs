@@ -0,0 +1,188 @@
+// Constraint groups limit how many of their member targets may be executing commands at
+// once, eg to keep at most 10% of a fleet deploying at any time. Targets join groups by
+// listing them in ReceiveCommands, and take an execution slot in each of their groups in
+// StartCommand, giving it back in CompleteCommand.
+//
+// Slots are recorded on the group record, so they are shared by every host. A slot can
+// leak if a host dies between taking it and recording the start on the batch, or if the
+// batch is deleted while executing, so slots whose command is no longer executing are
+// reclaimed whenever a group looks full. Groups which do not exist are ignored.
+
+use crate::database::{Database, DatabaseError};
+use crate::records::ConstraintGroupRecord;
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+// How often a polling target refreshes its membership of each of its groups.
+const MEMBERSHIP_REFRESH_MILLIS: usize = 60_000;
+// Targets which have not polled for this long no longer count as members. Must be longer
+// than MEMBERSHIP_REFRESH_MILLIS plus the longest possible poll.
+const MEMBERSHIP_TTL_MILLIS: usize = 300_000;
+// Slots younger than this are never reclaimed, since StartCommand takes the slot before
+// recording the start on the batch.
+const SLOT_RECLAIM_GRACE_MILLIS: usize = 10_000;
+
+#[derive(Default)]
+pub struct ConstraintGroups {
+    // When this host last refreshed each (group, target) membership.
+    membership_written: Mutex<HashMap<(String, String), usize>>,
+}
+
+impl ConstraintGroups {
+    // Records that the target is a member of the groups, unless that was done recently.
+    pub async fn refresh_membership(
+            &self,
+            database: &Database,
+            target_name: &str,
+            group_names: &[String],
+            now_epoch_millis: usize) -> Result<(), DatabaseError> {
+        for group_name in sorted(group_names) {
+            let key = (group_name.to_owned(), target_name.to_owned());
+            let fresh = self.membership_written()
+                .get(&key)
+                .is_some_and(|written| now_epoch_millis.saturating_sub(*written) < MEMBERSHIP_REFRESH_MILLIS);
+            if fresh {
+                continue;
+            }
+            database.update_constraint_group(group_name, |group| {
+                group.refresh_member(
+                    target_name, now_epoch_millis.saturating_sub(MEMBERSHIP_TTL_MILLIS), now_epoch_millis);
+            }).await?;
+            let mut membership_written = self.membership_written();
+            // Forget memberships which are no longer being refreshed, so this does not grow
+            // forever.
+            membership_written.retain(|_key, written| now_epoch_millis.saturating_sub(*written) < MEMBERSHIP_TTL_MILLIS);
+            membership_written.insert(key, now_epoch_millis);
+        }
+        Ok(())
+    }
+
+    fn membership_written(&self) -> MutexGuard<'_, HashMap<(String, String), usize>> {
+        self.membership_written.lock().expect("ConstraintGroups lock poisoned")
+    }
+}
+
+// True if any of the groups is too full for the target to start executing another command.
+pub async fn is_withheld(
+        database: &Database,
+        target_name: &str,
+        group_names: &[String],
+        now_epoch_millis: usize) -> Result<bool, DatabaseError> {
+    for group_name in sorted(group_names) {
+        if is_full(database, group_name, target_name, now_epoch_millis).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Takes a slot in every group for the command. Returns the names of the groups in which a
+// slot was taken, or None without holding any slots if one of the groups is full.
+pub async fn acquire(
+        database: &Database,
+        target_name: &str,
+        group_names: &[String],
+        batch_id: &str,
+        command_index: usize,
+        now_epoch_millis: usize) -> Result<Option<Vec<String>>, DatabaseError> {
+    let mut acquired = Vec::new();
+    // Always in the same order, so that two targets competing for the last slots of the
+    // same groups cannot each end up holding some.
+    for group_name in sorted(group_names) {
+        let mut taken = try_acquire(database, group_name, target_name, batch_id, command_index, now_epoch_millis).await?;
+        if taken == Some(false) && reclaim_slots(database, group_name, now_epoch_millis).await? {
+            taken = try_acquire(database, group_name, target_name, batch_id, command_index, now_epoch_millis).await?;
+        }
+        match taken {
+            Some(true) => acquired.push(group_name.to_owned()),
+            Some(false) => {
+                release(database, target_name, &acquired, batch_id, command_index).await?;
+                return Ok(None);
+            }
+            // No such group.
+            None => (),
+        }
+    }
+    Ok(Some(acquired))
+}
+
+// Gives back the command's slot in every group.
+pub async fn release(
+        database: &Database,
+        target_name: &str,
+        group_names: &[String],
+        batch_id: &str,
+        command_index: usize) -> Result<(), DatabaseError> {
+    for group_name in sorted(group_names) {
+        database.update_constraint_group(group_name, |group| {
+            group.release(target_name, batch_id, command_index);
+        }).await?;
+    }
+    Ok(())
+}
+
+async fn try_acquire(
+        database: &Database,
+        group_name: &str,
+        target_name: &str,
+        batch_id: &str,
+        command_index: usize,
+        now_epoch_millis: usize) -> Result<Option<bool>, DatabaseError> {
+    database.update_constraint_group(group_name, |group| {
+        group.acquire(target_name, batch_id, command_index, now_epoch_millis)
+    }).await
+}
+
+async fn is_full(
+        database: &Database,
+        group_name: &str,
+        target_name: &str,
+        now_epoch_millis: usize) -> Result<bool, DatabaseError> {
+    let full = |group: &ConstraintGroupRecord| group.is_full_for(target_name);
+    match database.read_constraint_group(group_name).await? {
+        Some(group) if full(&group) => {
+            Ok(!reclaim_slots(database, group_name, now_epoch_millis).await?
+                || database.read_constraint_group(group_name).await?.is_some_and(|group| full(&group)))
+        }
+        _ => Ok(false),
+    }
+}
+
+// Releases slots held by commands which are no longer executing. Returns true if any were
+// released.
+async fn reclaim_slots(
+        database: &Database,
+        group_name: &str,
+        now_epoch_millis: usize) -> Result<bool, DatabaseError> {
+    let group = match database.read_constraint_group(group_name).await? {
+        Some(group) => group,
+        None => return Ok(false),
+    };
+    let mut stale = Vec::new();
+    for (target_name, holders) in &group.holders {
+        for holder in holders {
+            if now_epoch_millis.saturating_sub(holder.acquired_epoch_millis) < SLOT_RECLAIM_GRACE_MILLIS {
+                continue;
+            }
+            let executing = database.read_batch(&holder.batch_id).await?
+                .is_some_and(|batch| batch.is_executing(holder.command_index));
+            if !executing {
+                stale.push((target_name.clone(), holder.clone()));
+            }
+        }
+    }
+    if stale.is_empty() {
+        return Ok(false);
+    }
+    database.update_constraint_group(group_name, |group| {
+        for (target_name, holder) in &stale {
+            group.reclaim(target_name, holder);
+        }
+    }).await?;
+    Ok(true)
+}
+
+fn sorted(group_names: &[String]) -> BTreeSet<&str> {
+    group_names.iter().map(String::as_str).collect()
+}
@@ -0,0 +1,182 @@
+// Cron expressions for schedules, evaluated in UTC. There are five space separated fields:
+// minute (0-59), hour (0-23), day of month (1-31), month (1-12) and day of week (0-7, where
+// both 0 and 7 are Sunday). Each field is a comma separated list of *, a value or a range
+// such as 1-5, and * and ranges may take a step, eg */15 or 8-18/2. As in classic cron,
+// when both day fields are restricted a day matches if either of them does.
+
+const MINUTE_MILLIS: usize = 60_000;
+const DAY_MINUTES: usize = 24 * 60;
+// Expressions which do not match within this many days never will, eg 0 0 30 2 *. Long
+// enough for February 29th across a skipped leap year.
+const MAX_SEARCH_DAYS: usize = 366 * 8;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    // One bit per allowed value of each field.
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    // False when the field is *, so that only the other day field counts.
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!("Expected 5 fields but found {}", fields.len()));
+        }
+        let mut days_of_week = parse_field(fields[4], 0, 7)?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days_of_month: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            days_of_week,
+            days_of_month_restricted: !fields[2].starts_with('*'),
+            days_of_week_restricted: !fields[4].starts_with('*'),
+        })
+    }
+
+    // The first whole minute after the given time at which a run is due, or None if the
+    // expression never matches.
+    pub fn next_after(&self, epoch_millis: usize) -> Option<usize> {
+        let mut minute = epoch_millis / MINUTE_MILLIS + 1;
+        let end = minute + MAX_SEARCH_DAYS * DAY_MINUTES;
+        while minute < end {
+            let days = minute / DAY_MINUTES;
+            let (_year, month, day) = civil_from_days(days as i64);
+            if !has(self.months, month as usize) || !self.matches_day(day as usize, (days + 4) % 7) {
+                minute = (days + 1) * DAY_MINUTES;
+                continue;
+            }
+            let (hour, minute_of_hour) = (minute % DAY_MINUTES / 60, minute % 60);
+            if !has(self.hours, hour) {
+                minute += 60 - minute_of_hour;
+                continue;
+            }
+            if has(self.minutes, minute_of_hour) {
+                return Some(minute * MINUTE_MILLIS);
+            }
+            minute += 1;
+        }
+        None
+    }
+
+    fn matches_day(&self, day_of_month: usize, day_of_week: usize) -> bool {
+        let by_month = has(self.days_of_month, day_of_month);
+        let by_week = has(self.days_of_week, day_of_week);
+        match (self.days_of_month_restricted, self.days_of_week_restricted) {
+            (true, true) => by_month || by_week,
+            (true, false) => by_month,
+            (false, true) => by_week,
+            (false, false) => true,
+        }
+    }
+}
+
+// Converts days since the epoch to a proleptic Gregorian calendar year, month (1-12) and
+// day of month (1-31). See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn has(bits: u64, value: usize) -> bool {
+    bits & (1 << value) != 0
+}
+
+fn parse_field(field: &str, min: usize, max: usize) -> Result<u64, String> {
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(parse_value(step, 1, max)?)),
+            None => (item, None),
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else if let Some((first, last)) = range.split_once('-') {
+            (parse_value(first, min, max)?, parse_value(last, min, max)?)
+        } else if step.is_some() {
+            return Err(format!("{} needs * or a range to take a step", item));
+        } else {
+            let value = parse_value(range, min, max)?;
+            (value, value)
+        };
+        if first > last {
+            return Err(format!("{} is an empty range", item));
+        }
+        for value in (first..=last).step_by(step.unwrap_or(1)) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn parse_value(value: &str, min: usize, max: usize) -> Result<usize, String> {
+    match value.parse() {
+        Ok(value) if (min..=max).contains(&value) => Ok(value),
+        _ => Err(format!("{} is not a number from {} to {}", value, min, max)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-01T00:00:00Z, a Monday.
+    const NEW_YEAR_2024: usize = 1_704_067_200_000;
+    const HOUR_MILLIS: usize = 60 * MINUTE_MILLIS;
+    const DAY_MILLIS: usize = 24 * HOUR_MILLIS;
+
+    fn next(expression: &str, epoch_millis: usize) -> Option<usize> {
+        Cron::parse(expression).unwrap().next_after(epoch_millis)
+    }
+
+    #[test]
+    fn finds_the_next_run() {
+        assert_eq!(next("* * * * *", NEW_YEAR_2024), Some(NEW_YEAR_2024 + MINUTE_MILLIS));
+        assert_eq!(next("* * * * *", NEW_YEAR_2024 + 1), Some(NEW_YEAR_2024 + MINUTE_MILLIS));
+        assert_eq!(next("30 3 * * *", NEW_YEAR_2024), Some(NEW_YEAR_2024 + 3 * HOUR_MILLIS + 30 * MINUTE_MILLIS));
+        assert_eq!(next("0 0 * * *", NEW_YEAR_2024), Some(NEW_YEAR_2024 + DAY_MILLIS));
+        assert_eq!(next("*/15 9-17/4 * * *", NEW_YEAR_2024 + 9 * HOUR_MILLIS + 50 * MINUTE_MILLIS),
+            Some(NEW_YEAR_2024 + 13 * HOUR_MILLIS));
+        // Saturday, with Sunday written as 7 or 0.
+        assert_eq!(next("0 2 * * 6,7", NEW_YEAR_2024), Some(NEW_YEAR_2024 + 5 * DAY_MILLIS + 2 * HOUR_MILLIS));
+        assert_eq!(next("0 2 * * 0", NEW_YEAR_2024), Some(NEW_YEAR_2024 + 6 * DAY_MILLIS + 2 * HOUR_MILLIS));
+        // Either day field matching is enough once both are restricted.
+        assert_eq!(next("0 0 15 * 3", NEW_YEAR_2024), Some(NEW_YEAR_2024 + 2 * DAY_MILLIS));
+        assert_eq!(next("0 0 1 3 *", NEW_YEAR_2024), Some(NEW_YEAR_2024 + (31 + 29) * DAY_MILLIS));
+        assert_eq!(next("0 0 29 2 *", NEW_YEAR_2024 + 60 * DAY_MILLIS),
+            Some(NEW_YEAR_2024 + (4 * 365 + 1 + 31 + 28) * DAY_MILLIS));
+        assert_eq!(next("0 0 30 2 *", NEW_YEAR_2024), None);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in ["", "* * * *", "* * * * * *", "60 * * * *", "* 24 * * *", "* * 0 * *",
+                "* * * 13 *", "* * * * 8", "5-1 * * * *", "5/2 * * * *", "*/0 * * * *", "a * * * *"] {
+            assert!(Cron::parse(expression).is_err(), "{} was accepted", expression);
+        }
+    }
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days((NEW_YEAR_2024 / DAY_MILLIS) as i64 + 59), (2024, 2, 29));
+    }
+}
@@ -1,30 +1,356 @@
-// DB operations needed:
-// - Create batch idempotency record
-//      - Report and do not edit if already exists by HK
-// - Create command definition record
-//      - Do nothing if already exists
-// - Create command batch record
-//      - Report and do not edit if already exists by HK
-// - Read connection record by target name (HK) and account id
+#[cfg(test)]
+#[macro_use]
+mod conformance;
+mod blocking;
+mod dynamo;
+mod file;
+mod local;
+mod sqlite;
 
-pub enum Database {
-    Local(LocalDatabase)
+pub use dynamo::{DynamoConfig, DynamoDatabase, DynamoError};
+pub use file::FileDatabase;
+pub use local::LocalDatabase;
+pub use sqlite::SqliteDatabase;
+
+use crate::records::{
+    BatchRecord,
+    BatchTombstoneRecord,
+    ConnectionRecord,
+    ConstraintGroupRecord,
+    IdempotencyRecord,
+    PollPresenceRecord,
+    ScheduleRecord,
+};
+
+use std::path::Path;
+
+use async_trait::async_trait;
+
+// A modification to a batch record, see Storage::update_batch.
+pub type BatchOp<'a> = dyn FnMut(&mut BatchRecord) + 'a;
+// A modification to a constraint group record, see Storage::update_constraint_group.
+pub type GroupOp<'a> = dyn FnMut(&mut ConstraintGroupRecord) + 'a;
+// A modification to a schedule record, see Storage::update_schedule.
+pub type ScheduleOp<'a> = dyn FnMut(&mut ScheduleRecord) + 'a;
+
+// Every record operation the handlers need. Implementations must provide the semantics
+// checked by the conformance suite in database/conformance.rs.
+//
+// Futures are not required to be Send, since requests are handled on per-core LocalSets.
+#[async_trait(?Send)]
+pub trait Storage: Send + Sync {
+    // Creates the batch record, returning false without modifying anything if a batch
+    // with the same id already exists.
+    async fn create_batch(&self, batch: BatchRecord) -> Result<bool, DatabaseError>;
+
+    async fn read_batch(&self, batch_id: &str) -> Result<Option<BatchRecord>, DatabaseError>;
+
+    // Atomically applies op to the batch record and persists the result. Returns false if
+    // the batch does not exist. Implementations using optimistic concurrency may call op
+    // again on a freshly read record if there was a concurrent write.
+    async fn update_batch(
+        &self,
+        batch_id: &str,
+        op: &mut BatchOp<'_>) -> Result<bool, DatabaseError>;
+
+    // Returns false if the batch did not exist.
+    async fn delete_batch(&self, batch_id: &str) -> Result<bool, DatabaseError>;
+
+    // The target's active batches, in dispatch order. Finished batches are left out by the
+    // storage itself, so polls stay cheap however many batches a target has run.
+    async fn list_active_batches(&self, target_name: &str) -> Result<Vec<BatchRecord>, DatabaseError>;
+
+    // Every batch which is not done yet or whose completion notification is pending, across
+    // all targets, in no particular order. Only used by background tasks, so may be slow.
+    async fn list_outstanding_batches(&self) -> Result<Vec<BatchRecord>, DatabaseError>;
+
+    // Creates or overwrites the tombstone for the record's batch.
+    async fn put_batch_tombstone(&self, record: BatchTombstoneRecord) -> Result<(), DatabaseError>;
+
+    async fn read_batch_tombstone(&self, batch_id: &str) -> Result<Option<BatchTombstoneRecord>, DatabaseError>;
+
+    // Deletes every tombstone left before the given time, returning how many were deleted.
+    async fn delete_batch_tombstones(&self, deleted_before_epoch_millis: usize) -> Result<usize, DatabaseError>;
+
+    // Creates the idempotency record unless one already exists with the same key. Returns
+    // the record stored under the key afterwards, which is the existing one if there was one.
+    async fn create_idempotency_record(
+        &self,
+        record: IdempotencyRecord) -> Result<IdempotencyRecord, DatabaseError>;
+
+    // Deletes every idempotency record created before the given time, returning how many
+    // were deleted.
+    async fn delete_idempotency_records(
+        &self,
+        created_before_epoch_millis: usize) -> Result<usize, DatabaseError>;
+
+    // Creates the connection record unless one already exists for the same target and
+    // account. Returns the record stored for them afterwards, which is the existing one if
+    // there was one.
+    async fn create_connection(&self, record: ConnectionRecord) -> Result<ConnectionRecord, DatabaseError>;
+
+    async fn read_connection(
+        &self,
+        target_name: &str,
+        account_id: &str) -> Result<Option<ConnectionRecord>, DatabaseError>;
+
+    // Creates or overwrites the presence record for the record's target and node.
+    async fn put_poll_presence(&self, record: PollPresenceRecord) -> Result<(), DatabaseError>;
+
+    // Every presence record for the target, including expired ones which have not been
+    // deleted yet.
+    async fn list_poll_presence(&self, target_name: &str) -> Result<Vec<PollPresenceRecord>, DatabaseError>;
+
+    // Deletes every presence record which expired before the given time, returning how
+    // many were deleted.
+    async fn delete_poll_presence(&self, expired_before_epoch_millis: usize) -> Result<usize, DatabaseError>;
+
+    // Creates the constraint group record, returning false without modifying anything if a
+    // group with the same name already exists.
+    async fn create_constraint_group(&self, group: ConstraintGroupRecord) -> Result<bool, DatabaseError>;
+
+    async fn read_constraint_group(&self, name: &str) -> Result<Option<ConstraintGroupRecord>, DatabaseError>;
+
+    // Same semantics as update_batch.
+    async fn update_constraint_group(
+        &self,
+        name: &str,
+        op: &mut GroupOp<'_>) -> Result<bool, DatabaseError>;
+
+    // Returns false if the group did not exist.
+    async fn delete_constraint_group(&self, name: &str) -> Result<bool, DatabaseError>;
+
+    // Creates the schedule record, returning false without modifying anything if a
+    // schedule with the same name already exists.
+    async fn create_schedule(&self, schedule: ScheduleRecord) -> Result<bool, DatabaseError>;
+
+    async fn read_schedule(&self, name: &str) -> Result<Option<ScheduleRecord>, DatabaseError>;
+
+    // Same semantics as update_batch.
+    async fn update_schedule(
+        &self,
+        name: &str,
+        op: &mut ScheduleOp<'_>) -> Result<bool, DatabaseError>;
+
+    // Returns false if the schedule did not exist.
+    async fn delete_schedule(&self, name: &str) -> Result<bool, DatabaseError>;
+
+    // Every schedule, in no particular order. Only used by background tasks, so may be
+    // slow.
+    async fn list_schedules(&self) -> Result<Vec<ScheduleRecord>, DatabaseError>;
 }
 
-impl Database {
-    pub fn local() -> Self {
-        Self::Local(LocalDatabase::new())
+pub struct Database {
+    storage: Box<dyn Storage>
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+    Http(hyper::Error),
+    Dynamo(DynamoError),
+    // A conditional write kept losing races against concurrent writers.
+    Contention,
+    // Persisted data could not be understood.
+    Corrupt(String),
+    // The database specification string was not recognized.
+    InvalidSpec(String),
+}
+
+impl From<std::io::Error> for DatabaseError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Sqlite(err)
     }
 }
 
-struct LocalDatabase {
+impl From<hyper::Error> for DatabaseError {
+    fn from(err: hyper::Error) -> Self {
+        Self::Http(err)
+    }
+}
 
+impl From<serde_json::Error> for DatabaseError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Corrupt(err.to_string())
+    }
 }
 
-impl LocalDatabase {
-    fn new() -> Self {
-        LocalDatabase {
+impl Database {
+    pub fn new(storage: impl Storage + 'static) -> Self {
+        Self {
+            storage: Box::new(storage)
+        }
+    }
+
+    pub fn local() -> Self {
+        Self::new(LocalDatabase::new())
+    }
+
+    // Opens a durable database stored in data_dir, recovering any state left behind by a
+    // previous process.
+    pub fn file(data_dir: impl AsRef<Path>) -> Result<Self, DatabaseError> {
+        Ok(Self::new(FileDatabase::open(data_dir.as_ref())?))
+    }
+
+    // Opens (creating if needed) a SQLite database file at path.
+    pub fn sqlite(path: impl AsRef<Path>) -> Result<Self, DatabaseError> {
+        Ok(Self::new(SqliteDatabase::open(path.as_ref())?))
+    }
+
+    // Uses an existing DynamoDB table. See the dynamo module for the expected table layout.
+    pub fn dynamo(config: DynamoConfig) -> Result<Self, DatabaseError> {
+        Ok(Self::new(DynamoDatabase::new(config)?))
+    }
 
+    // Opens the database described by spec, which is one of "local", "file:<data dir>",
+    // "sqlite:<db file>" or "dynamo:<table name>".
+    pub fn open(spec: &str) -> Result<Self, DatabaseError> {
+        if spec == "local" {
+            Ok(Self::local())
+        } else if let Some(data_dir) = spec.strip_prefix("file:") {
+            Self::file(data_dir)
+        } else if let Some(path) = spec.strip_prefix("sqlite:") {
+            Self::sqlite(path)
+        } else if let Some(table_name) = spec.strip_prefix("dynamo:") {
+            Self::dynamo(DynamoConfig::from_env(table_name)?)
+        } else {
+            Err(DatabaseError::InvalidSpec(spec.to_owned()))
         }
     }
-}
\ No newline at end of file
+
+    pub async fn create_batch(&self, batch: BatchRecord) -> Result<bool, DatabaseError> {
+        self.storage.create_batch(batch).await
+    }
+
+    pub async fn read_batch(&self, batch_id: &str) -> Result<Option<BatchRecord>, DatabaseError> {
+        self.storage.read_batch(batch_id).await
+    }
+
+    // Like Storage::update_batch, but passes along the result of op. Returns None if the
+    // batch does not exist. op should not have side effects beyond modifying the record,
+    // since it may be called more than once.
+    pub async fn update_batch<R>(
+            &self,
+            batch_id: &str,
+            mut op: impl FnMut(&mut BatchRecord) -> R) -> Result<Option<R>, DatabaseError> {
+        let mut result = None;
+        let found = self.storage.update_batch(batch_id, &mut |batch| result = Some(op(batch))).await?;
+        Ok(result.filter(|_| found))
+    }
+
+    pub async fn delete_batch(&self, batch_id: &str) -> Result<bool, DatabaseError> {
+        self.storage.delete_batch(batch_id).await
+    }
+
+    pub async fn list_active_batches(&self, target_name: &str) -> Result<Vec<BatchRecord>, DatabaseError> {
+        self.storage.list_active_batches(target_name).await
+    }
+
+    pub async fn list_outstanding_batches(&self) -> Result<Vec<BatchRecord>, DatabaseError> {
+        self.storage.list_outstanding_batches().await
+    }
+
+    pub async fn put_batch_tombstone(&self, record: BatchTombstoneRecord) -> Result<(), DatabaseError> {
+        self.storage.put_batch_tombstone(record).await
+    }
+
+    pub async fn read_batch_tombstone(&self, batch_id: &str) -> Result<Option<BatchTombstoneRecord>, DatabaseError> {
+        self.storage.read_batch_tombstone(batch_id).await
+    }
+
+    pub async fn delete_batch_tombstones(&self, deleted_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        self.storage.delete_batch_tombstones(deleted_before_epoch_millis).await
+    }
+
+    pub async fn create_idempotency_record(
+            &self,
+            record: IdempotencyRecord) -> Result<IdempotencyRecord, DatabaseError> {
+        self.storage.create_idempotency_record(record).await
+    }
+
+    pub async fn delete_idempotency_records(
+            &self,
+            created_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        self.storage.delete_idempotency_records(created_before_epoch_millis).await
+    }
+
+    #[allow(dead_code)] // TODO: connection tracking
+    pub async fn create_connection(&self, record: ConnectionRecord) -> Result<ConnectionRecord, DatabaseError> {
+        self.storage.create_connection(record).await
+    }
+
+    #[allow(dead_code)] // TODO: connection tracking
+    pub async fn read_connection(
+            &self,
+            target_name: &str,
+            account_id: &str) -> Result<Option<ConnectionRecord>, DatabaseError> {
+        self.storage.read_connection(target_name, account_id).await
+    }
+
+    pub async fn put_poll_presence(&self, record: PollPresenceRecord) -> Result<(), DatabaseError> {
+        self.storage.put_poll_presence(record).await
+    }
+
+    pub async fn list_poll_presence(&self, target_name: &str) -> Result<Vec<PollPresenceRecord>, DatabaseError> {
+        self.storage.list_poll_presence(target_name).await
+    }
+
+    pub async fn delete_poll_presence(&self, expired_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        self.storage.delete_poll_presence(expired_before_epoch_millis).await
+    }
+
+    pub async fn create_constraint_group(&self, group: ConstraintGroupRecord) -> Result<bool, DatabaseError> {
+        self.storage.create_constraint_group(group).await
+    }
+
+    pub async fn read_constraint_group(&self, name: &str) -> Result<Option<ConstraintGroupRecord>, DatabaseError> {
+        self.storage.read_constraint_group(name).await
+    }
+
+    // Like update_batch, but for constraint groups.
+    pub async fn update_constraint_group<R>(
+            &self,
+            name: &str,
+            mut op: impl FnMut(&mut ConstraintGroupRecord) -> R) -> Result<Option<R>, DatabaseError> {
+        let mut result = None;
+        let found = self.storage.update_constraint_group(name, &mut |group| result = Some(op(group))).await?;
+        Ok(result.filter(|_| found))
+    }
+
+    pub async fn delete_constraint_group(&self, name: &str) -> Result<bool, DatabaseError> {
+        self.storage.delete_constraint_group(name).await
+    }
+
+    pub async fn create_schedule(&self, schedule: ScheduleRecord) -> Result<bool, DatabaseError> {
+        self.storage.create_schedule(schedule).await
+    }
+
+    pub async fn read_schedule(&self, name: &str) -> Result<Option<ScheduleRecord>, DatabaseError> {
+        self.storage.read_schedule(name).await
+    }
+
+    // Like update_batch, but for schedules.
+    pub async fn update_schedule<R>(
+            &self,
+            name: &str,
+            mut op: impl FnMut(&mut ScheduleRecord) -> R) -> Result<Option<R>, DatabaseError> {
+        let mut result = None;
+        let found = self.storage.update_schedule(name, &mut |schedule| result = Some(op(schedule))).await?;
+        Ok(result.filter(|_| found))
+    }
+
+    pub async fn delete_schedule(&self, name: &str) -> Result<bool, DatabaseError> {
+        self.storage.delete_schedule(name).await
+    }
+
+    pub async fn list_schedules(&self) -> Result<Vec<ScheduleRecord>, DatabaseError> {
+        self.storage.list_schedules().await
+    }
+}
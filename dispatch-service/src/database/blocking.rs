@@ -0,0 +1,39 @@
+// Helpers for storage backends built on blocking file I/O. The workers run every request on
+// a single thread, so fsyncs and SQLite calls (which may wait out another process's lock)
+// are moved onto tokio's blocking thread pool instead of running inline.
+
+use super::DatabaseError;
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
+
+// Exclusive access to a backend's writable state, released when dropped. Unlike a tokio
+// MutexGuard it does not borrow the backend, so it can move into a blocking task. That keeps
+// the state locked until the task finishes, even if the request waiting on it is dropped.
+pub(super) struct Permit(Arc<Semaphore>);
+
+impl Permit {
+    // The semaphore must have been created with a single permit.
+    pub(super) async fn acquire(semaphore: &Arc<Semaphore>) -> Self {
+        semaphore.acquire().await.forget();
+        Self(semaphore.clone())
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.add_permits(1);
+    }
+}
+
+// Runs the function on the blocking thread pool.
+pub(super) async fn run<T, F>(f: F) -> Result<T, DatabaseError>
+        where T: Send + 'static, F: FnOnce() -> Result<T, DatabaseError> + Send + 'static {
+    spawn_blocking(f).await.map_err(std::io::Error::from)?
+}
+
+pub(super) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("Storage lock poisoned")
+}
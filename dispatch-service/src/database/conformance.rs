@@ -0,0 +1,556 @@
+// Semantics every Storage implementation must provide. Backends run the whole suite with
+// storage_conformance_tests!, passing an expression which evaluates to Some(storage) with
+// fresh, empty storage, or None if the backend is unavailable and the tests should be
+// skipped.
+
+use super::Storage;
+use crate::records::{
+    AttemptState,
+    BatchRecord,
+    BatchState,
+    BatchTombstoneRecord,
+    CommandDefinition,
+    ConnectionRecord,
+    CompleteNotification,
+    ConstraintGroupRecord,
+    GroupLimit,
+    IdempotencyRecord,
+    NotificationChannel,
+    NotificationState,
+    PollPresenceRecord,
+    RetryPolicy,
+    ScheduleRecord,
+    StartOutcome,
+};
+
+use std::future::Future;
+use std::thread;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+macro_rules! storage_conformance_tests {
+    ($make_storage:expr) => {
+        mod conformance {
+            use super::*;
+
+            storage_conformance_tests!(@cases $make_storage;
+                create_is_conditional,
+                missing_batches,
+                update_persists,
+                delete_removes,
+                list_in_dispatch_order,
+                list_outstanding_batches,
+                concurrent_updates_on_one_task,
+                concurrent_updates_across_threads,
+                concurrent_creates,
+                idempotency_records_are_conditional,
+                delete_idempotency_records,
+                connections_are_conditional,
+                batch_tombstones,
+                poll_presence,
+                constraint_groups,
+                schedules);
+        }
+    };
+    (@cases $make_storage:expr; $($case:ident),*) => {
+        $(
+            #[test]
+            fn $case() {
+                if let Some(storage) = $make_storage {
+                    crate::database::conformance::$case(storage);
+                }
+            }
+        )*
+    };
+}
+
+pub fn create_is_conditional(storage: impl Storage) {
+    block_on(async {
+        let batch = test_batch("target");
+        assert!(storage.create_batch(batch.clone()).await.unwrap());
+        start(&storage, &batch.id).await;
+
+        let mut duplicate = test_batch("other-target");
+        duplicate.id = batch.id.clone();
+        assert!(!storage.create_batch(duplicate).await.unwrap());
+
+        let stored = storage.read_batch(&batch.id).await.unwrap().unwrap();
+        assert_eq!(stored.target_name, "target");
+        assert_eq!(heartbeats(&stored), Some(0), "Existing batch was overwritten");
+    });
+}
+
+pub fn missing_batches(storage: impl Storage) {
+    block_on(async {
+        assert!(storage.read_batch("missing").await.unwrap().is_none());
+        let mut called = false;
+        assert!(!storage.update_batch("missing", &mut |_| called = true).await.unwrap());
+        assert!(!called);
+        assert!(!storage.delete_batch("missing").await.unwrap());
+        assert!(storage.list_active_batches("missing").await.unwrap().is_empty());
+    });
+}
+
+pub fn update_persists(storage: impl Storage) {
+    block_on(async {
+        let batch = test_batch("target");
+        assert!(storage.create_batch(batch.clone()).await.unwrap());
+        let token = start(&storage, &batch.id).await;
+        for _ in 0..3 {
+            assert!(storage.update_batch(&batch.id, &mut |batch| {
+                batch.heartbeat(&token, 3000);
+            }).await.unwrap());
+        }
+        let stored = storage.read_batch(&batch.id).await.unwrap().unwrap();
+        assert_eq!(heartbeats(&stored), Some(3));
+        assert_eq!(stored.commands[0].definition.retry_policy, batch.commands[0].definition.retry_policy);
+        assert_eq!(stored.commands[0].definition.execution_timeout_millis, Some(600_000));
+        assert_eq!(stored.commands[1].definition.execution_timeout_millis, None);
+        assert!(stored.commands[1].definition.parallel_with_previous);
+        let listed = storage.list_active_batches("target").await.unwrap();
+        assert_eq!(heartbeats(&listed[0]), Some(3));
+    });
+}
+
+pub fn delete_removes(storage: impl Storage) {
+    block_on(async {
+        let batch = test_batch("target");
+        let other = test_batch("target");
+        assert!(storage.create_batch(batch.clone()).await.unwrap());
+        assert!(storage.create_batch(other.clone()).await.unwrap());
+
+        assert!(storage.delete_batch(&batch.id).await.unwrap());
+        assert!(storage.read_batch(&batch.id).await.unwrap().is_none());
+        assert!(!storage.update_batch(&batch.id, &mut |_| ()).await.unwrap());
+        assert_eq!(batch_ids(storage.list_active_batches("target").await.unwrap()), vec![other.id.clone()]);
+        assert!(!storage.delete_batch(&batch.id).await.unwrap());
+        assert!(storage.read_batch(&other.id).await.unwrap().is_some());
+    });
+}
+
+pub fn list_in_dispatch_order(storage: impl Storage) {
+    block_on(async {
+        let mut expected = Vec::new();
+        for index in 0..6 {
+            let target_name = if index % 2 == 0 { "first" } else { "second" };
+            let batch = test_batch(target_name);
+            if index % 2 == 0 {
+                expected.push(batch.id.clone());
+            }
+            assert!(storage.create_batch(batch).await.unwrap());
+            // Some backends only order dispatches to the millisecond.
+            thread::sleep(Duration::from_millis(2));
+        }
+        // Finished batches are no longer listed.
+        let (done, cancelled) = (test_batch("first"), test_batch("first"));
+        for batch in [&done, &cancelled] {
+            assert!(storage.create_batch(batch.clone()).await.unwrap());
+        }
+        assert!(storage.update_batch(&done.id, &mut |batch| {
+            batch.state = BatchState::Done { succeeded: true };
+        }).await.unwrap());
+        assert!(storage.update_batch(&cancelled.id, &mut |batch| {
+            batch.cancel("operator", "bad rollout", 3000);
+        }).await.unwrap());
+        assert_eq!(batch_ids(storage.list_active_batches("first").await.unwrap()), expected);
+        assert_eq!(storage.list_active_batches("second").await.unwrap().len(), 3);
+        assert!(storage.list_active_batches("third").await.unwrap().is_empty());
+    });
+}
+
+pub fn list_outstanding_batches(storage: impl Storage) {
+    block_on(async {
+        let (first, second, done) = (test_batch("first"), test_batch("second"), test_batch("first"));
+        let cancelled = test_batch("second");
+        let (mut notifying, mut notified) = (test_batch("first"), test_batch("first"));
+        for batch in [&mut notifying, &mut notified] {
+            batch.complete_notification = Some(CompleteNotification {
+                channel: NotificationChannel::SNS { target_arn: "arn:aws:sns:us-west-2:123456789012:done".to_owned() },
+                state: NotificationState::Pending { failures: 0 },
+            });
+        }
+        for batch in [&first, &second, &done, &cancelled, &notifying, &notified] {
+            assert!(storage.create_batch(batch.clone()).await.unwrap());
+        }
+        for batch_id in [&notifying.id, &notified.id] {
+            assert!(storage.update_batch(batch_id, &mut |batch| {
+                batch.cancel("operator", "bad rollout", 3000);
+            }).await.unwrap());
+        }
+        assert!(storage.update_batch(&notified.id, &mut |batch| {
+            batch.record_notification(true, 1, 4000);
+        }).await.unwrap());
+        assert!(storage.update_batch(&done.id, &mut |batch| {
+            batch.state = BatchState::Done { succeeded: true };
+        }).await.unwrap());
+        assert!(storage.update_batch(&cancelled.id, &mut |batch| {
+            batch.cancel("operator", "bad rollout", 3000);
+        }).await.unwrap());
+        let stored = storage.read_batch(&cancelled.id).await.unwrap().unwrap();
+        assert_eq!(stored.state, BatchState::Cancelled);
+        assert_eq!(stored.cancellation.map(|cancellation| cancellation.reason), Some("bad rollout".to_owned()));
+        // Finished batches stay listed until their completion notification is sent.
+        let mut listed = batch_ids(storage.list_outstanding_batches().await.unwrap());
+        listed.sort();
+        let mut expected = vec![first.id, second.id, notifying.id];
+        expected.sort();
+        assert_eq!(listed, expected);
+    });
+}
+
+pub fn concurrent_updates_on_one_task(storage: impl Storage) {
+    block_on(async {
+        let batch = test_batch("target");
+        assert!(storage.create_batch(batch.clone()).await.unwrap());
+        let token = start(&storage, &batch.id).await;
+        let updates = (0..8).map(|_| {
+            let token = token.clone();
+            let batch_id = batch.id.clone();
+            let storage = &storage;
+            async move {
+                storage.update_batch(&batch_id, &mut |batch| {
+                    batch.heartbeat(&token, 3000);
+                }).await
+            }
+        });
+        for result in futures::future::join_all(updates).await {
+            assert!(result.unwrap());
+        }
+        let stored = storage.read_batch(&batch.id).await.unwrap().unwrap();
+        assert_eq!(heartbeats(&stored), Some(8), "Concurrent updates were lost");
+    });
+}
+
+pub fn concurrent_updates_across_threads(storage: impl Storage) {
+    const THREADS: usize = 4;
+    const UPDATES_PER_THREAD: usize = 5;
+    let batch = test_batch("target");
+    let token = block_on(async {
+        assert!(storage.create_batch(batch.clone()).await.unwrap());
+        start(&storage, &batch.id).await
+    });
+    thread::scope(|scope| {
+        for _ in 0..THREADS {
+            scope.spawn(|| block_on(async {
+                for _ in 0..UPDATES_PER_THREAD {
+                    assert!(storage.update_batch(&batch.id, &mut |batch| {
+                        batch.heartbeat(&token, 3000);
+                    }).await.unwrap());
+                }
+            }));
+        }
+    });
+    let stored = block_on(storage.read_batch(&batch.id)).unwrap().unwrap();
+    assert_eq!(heartbeats(&stored), Some(THREADS * UPDATES_PER_THREAD), "Concurrent updates were lost");
+}
+
+pub fn concurrent_creates(storage: impl Storage) {
+    const THREADS: usize = 4;
+    let batch_id = format!("{}", Uuid::new_v4().to_hyphenated());
+    let winners = thread::scope(|scope| {
+        let handles = (0..THREADS)
+            .map(|index| {
+                let storage = &storage;
+                let batch_id = batch_id.clone();
+                scope.spawn(move || {
+                    let mut batch = test_batch(&format!("target-{}", index));
+                    batch.id = batch_id;
+                    block_on(storage.create_batch(batch)).unwrap().then_some(index)
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .filter_map(|handle| handle.join().expect("Create thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(winners.len(), 1, "Exactly one create should win");
+    let stored = block_on(storage.read_batch(&batch_id)).unwrap().unwrap();
+    assert_eq!(stored.target_name, format!("target-{}", winners[0]));
+}
+
+pub fn idempotency_records_are_conditional(storage: impl Storage) {
+    const THREADS: usize = 4;
+    let key = format!("{}", Uuid::new_v4().to_hyphenated());
+    let record = |index: usize| IdempotencyRecord {
+        key: key.clone(),
+        request_hash: format!("hash-{}", index),
+        batch_id: format!("batch-{}", index),
+        created_epoch_millis: 1000 + index,
+    };
+    let stored = thread::scope(|scope| {
+        let handles = (0..THREADS)
+            .map(|index| {
+                let storage = &storage;
+                let record = record(index);
+                scope.spawn(move || block_on(storage.create_idempotency_record(record)).unwrap())
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|handle| handle.join().expect("Create thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    assert!(stored.iter().all(|winner| *winner == stored[0]), "Every create should see the winner");
+    assert!((0..THREADS).any(|index| stored[0] == record(index)));
+
+    let mut other = record(0);
+    other.key = format!("{}", Uuid::new_v4().to_hyphenated());
+    assert_eq!(block_on(storage.create_idempotency_record(other.clone())).unwrap(), other);
+    assert_eq!(block_on(storage.create_idempotency_record(record(THREADS))).unwrap(), stored[0]);
+}
+
+pub fn connections_are_conditional(storage: impl Storage) {
+    const THREADS: usize = 4;
+    let target_name = format!("target#{}", Uuid::new_v4().to_hyphenated());
+    let record = |account_id: &str, index: usize| ConnectionRecord {
+        target_name: target_name.clone(),
+        account_id: account_id.to_owned(),
+        created_epoch_millis: 1000 + index,
+    };
+    let stored = thread::scope(|scope| {
+        let handles = (0..THREADS)
+            .map(|index| {
+                let storage = &storage;
+                let record = record("account", index);
+                scope.spawn(move || block_on(storage.create_connection(record)).unwrap())
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|handle| handle.join().expect("Create thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    assert!(stored.iter().all(|winner| *winner == stored[0]), "Every create should see the winner");
+    assert!((0..THREADS).any(|index| stored[0] == record("account", index)));
+
+    block_on(async {
+        assert_eq!(storage.create_connection(record("account", THREADS)).await.unwrap(), stored[0]);
+        assert_eq!(storage.read_connection(&target_name, "account").await.unwrap(), Some(stored[0].clone()));
+        // Both parts of the key count, even when one could be mistaken for part of the other.
+        let other = record("other", 0);
+        assert_eq!(storage.create_connection(other.clone()).await.unwrap(), other);
+        let (prefix, suffix) = target_name.split_once('#').unwrap();
+        let shifted = ConnectionRecord {
+            target_name: prefix.to_owned(),
+            account_id: format!("{}#account", suffix),
+            created_epoch_millis: 5000,
+        };
+        assert_eq!(storage.create_connection(shifted.clone()).await.unwrap(), shifted);
+        assert_eq!(storage.read_connection(&target_name, "account").await.unwrap(), Some(stored[0].clone()));
+        assert!(storage.read_connection(&target_name, "missing").await.unwrap().is_none());
+    });
+}
+
+pub fn delete_idempotency_records(storage: impl Storage) {
+    block_on(async {
+        let records = [1000, 2000, 3000].iter()
+            .map(|created_epoch_millis| IdempotencyRecord {
+                key: format!("{}", Uuid::new_v4().to_hyphenated()),
+                request_hash: "hash".to_owned(),
+                batch_id: format!("{}", Uuid::new_v4().to_hyphenated()),
+                created_epoch_millis: *created_epoch_millis,
+            })
+            .collect::<Vec<_>>();
+        for record in &records {
+            storage.create_idempotency_record(record.clone()).await.unwrap();
+        }
+        assert_eq!(storage.delete_idempotency_records(2500).await.unwrap(), 2);
+        assert_eq!(storage.delete_idempotency_records(2500).await.unwrap(), 0);
+
+        // Deleted keys can be used again, and the remaining record is untouched.
+        for (index, record) in records.iter().enumerate() {
+            let mut retry = record.clone();
+            retry.batch_id = "retry".to_owned();
+            let stored = storage.create_idempotency_record(retry.clone()).await.unwrap();
+            assert_eq!(stored, if index < 2 { retry } else { record.clone() });
+        }
+    });
+}
+
+pub fn batch_tombstones(storage: impl Storage) {
+    block_on(async {
+        let records = [1000, 2000, 3000].iter()
+            .map(|deleted_epoch_millis| BatchTombstoneRecord {
+                batch_id: format!("{}", Uuid::new_v4().to_hyphenated()),
+                deleted_epoch_millis: *deleted_epoch_millis,
+            })
+            .collect::<Vec<_>>();
+        for record in &records {
+            storage.put_batch_tombstone(record.clone()).await.unwrap();
+        }
+        assert_eq!(storage.read_batch_tombstone(&records[0].batch_id).await.unwrap(), Some(records[0].clone()));
+        assert_eq!(storage.read_batch_tombstone("missing").await.unwrap(), None);
+
+        // Putting a tombstone again overwrites it.
+        let mut replaced = records[1].clone();
+        replaced.deleted_epoch_millis = 2600;
+        storage.put_batch_tombstone(replaced.clone()).await.unwrap();
+        assert_eq!(storage.read_batch_tombstone(&replaced.batch_id).await.unwrap(), Some(replaced.clone()));
+
+        assert_eq!(storage.delete_batch_tombstones(2500).await.unwrap(), 1);
+        assert_eq!(storage.delete_batch_tombstones(2500).await.unwrap(), 0);
+        assert_eq!(storage.read_batch_tombstone(&records[0].batch_id).await.unwrap(), None);
+        assert_eq!(storage.read_batch_tombstone(&replaced.batch_id).await.unwrap(), Some(replaced));
+        assert_eq!(storage.read_batch_tombstone(&records[2].batch_id).await.unwrap(), Some(records[2].clone()));
+    });
+}
+
+pub fn poll_presence(storage: impl Storage) {
+    block_on(async {
+        let target_name = format!("{}", Uuid::new_v4().to_hyphenated());
+        let other_target_name = format!("{}", Uuid::new_v4().to_hyphenated());
+        let record = |target_name: &str, node_address: &str, expires_epoch_millis| PollPresenceRecord {
+            target_name: target_name.to_owned(),
+            node_address: node_address.to_owned(),
+            expires_epoch_millis,
+        };
+        storage.put_poll_presence(record(&target_name, "10.0.0.1:43316", 1000)).await.unwrap();
+        storage.put_poll_presence(record(&target_name, "10.0.0.2:43316", 2000)).await.unwrap();
+        storage.put_poll_presence(record(&other_target_name, "10.0.0.1:43316", 1000)).await.unwrap();
+        // Refreshing overwrites the previous expiry.
+        storage.put_poll_presence(record(&target_name, "10.0.0.1:43316", 3000)).await.unwrap();
+
+        let mut listed = storage.list_poll_presence(&target_name).await.unwrap();
+        listed.sort_by(|a, b| a.node_address.cmp(&b.node_address));
+        assert_eq!(listed, vec![
+            record(&target_name, "10.0.0.1:43316", 3000),
+            record(&target_name, "10.0.0.2:43316", 2000),
+        ]);
+        assert!(storage.list_poll_presence("missing").await.unwrap().is_empty());
+
+        assert_eq!(storage.delete_poll_presence(2500).await.unwrap(), 2);
+        assert_eq!(storage.list_poll_presence(&target_name).await.unwrap(),
+            vec![record(&target_name, "10.0.0.1:43316", 3000)]);
+        assert!(storage.list_poll_presence(&other_target_name).await.unwrap().is_empty());
+    });
+}
+
+pub fn constraint_groups(storage: impl Storage) {
+    block_on(async {
+        let name = format!("{}", Uuid::new_v4().to_hyphenated());
+        assert!(storage.read_constraint_group(&name).await.unwrap().is_none());
+        let mut called = false;
+        assert!(!storage.update_constraint_group(&name, &mut |_| called = true).await.unwrap());
+        assert!(!called);
+
+        let group = ConstraintGroupRecord::new(name.clone(), GroupLimit::Count { count: 1 });
+        assert!(storage.create_constraint_group(group.clone()).await.unwrap());
+        assert!(storage.update_constraint_group(&name, &mut |group| {
+            assert!(group.acquire("target", "batch", 0, 1000));
+        }).await.unwrap());
+        let mut duplicate = group.clone();
+        duplicate.limit = GroupLimit::Percentage { percentage: 50 };
+        assert!(!storage.create_constraint_group(duplicate).await.unwrap());
+
+        let stored = storage.read_constraint_group(&name).await.unwrap().unwrap();
+        assert_eq!(stored.limit, GroupLimit::Count { count: 1 }, "Existing group was overwritten");
+        assert!(stored.holds("target", "batch", 0));
+
+        assert!(storage.delete_constraint_group(&name).await.unwrap());
+        assert!(!storage.delete_constraint_group(&name).await.unwrap());
+        assert!(storage.read_constraint_group(&name).await.unwrap().is_none());
+    });
+}
+
+pub fn schedules(storage: impl Storage) {
+    block_on(async {
+        let (nightly, hourly) = ("nightly".to_owned(), "hourly".to_owned());
+        assert!(storage.read_schedule(&nightly).await.unwrap().is_none());
+        let mut called = false;
+        assert!(!storage.update_schedule(&nightly, &mut |_| called = true).await.unwrap());
+        assert!(!called);
+
+        let schedule = ScheduleRecord {
+            name: nightly.clone(),
+            target_name: "target".to_owned(),
+            cron: "0 3 * * *".to_owned(),
+            commands: test_batch("target").commands.into_iter().map(|command| command.definition).collect(),
+            priority: -10,
+            last_run_epoch_millis: 1000,
+        };
+        assert!(storage.create_schedule(schedule.clone()).await.unwrap());
+        assert!(storage.update_schedule(&nightly, &mut |schedule| schedule.last_run_epoch_millis = 2000).await.unwrap());
+        let mut duplicate = schedule.clone();
+        duplicate.cron = "0 4 * * *".to_owned();
+        assert!(!storage.create_schedule(duplicate).await.unwrap());
+        let mut other = schedule.clone();
+        other.name = hourly.clone();
+        assert!(storage.create_schedule(other).await.unwrap());
+
+        let stored = storage.read_schedule(&nightly).await.unwrap().unwrap();
+        assert_eq!(stored.cron, "0 3 * * *", "Existing schedule was overwritten");
+        assert_eq!(stored.last_run_epoch_millis, 2000);
+        assert_eq!(stored.priority, -10);
+        assert_eq!(stored.commands.len(), 2);
+        assert!(stored.commands[1].parallel_with_previous);
+        let mut listed = storage.list_schedules().await.unwrap().into_iter()
+            .map(|schedule| schedule.name)
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(listed, vec![hourly.clone(), nightly.clone()]);
+
+        assert!(storage.delete_schedule(&nightly).await.unwrap());
+        assert!(!storage.delete_schedule(&nightly).await.unwrap());
+        assert!(storage.read_schedule(&nightly).await.unwrap().is_none());
+        assert_eq!(storage.list_schedules().await.unwrap().len(), 1);
+    });
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .expect("Failed to build tokio runtime")
+        .block_on(future)
+}
+
+fn test_batch(target_name: &str) -> BatchRecord {
+    let definitions = vec![
+        CommandDefinition {
+            name: "deploy".to_owned(),
+            data: "v2".to_owned(),
+            max_retries: 1,
+            success_required: true,
+            retry_policy: RetryPolicy::Exponential {
+                initial_delay_millis: 100,
+                max_delay_millis: 1000,
+                jitter: true,
+            },
+            execution_timeout_millis: Some(600_000),
+            parallel_with_previous: false,
+        },
+        CommandDefinition {
+            name: "verify".to_owned(),
+            data: String::new(),
+            max_retries: 0,
+            success_required: false,
+            retry_policy: RetryPolicy::Immediate,
+            execution_timeout_millis: None,
+            parallel_with_previous: true,
+        },
+    ];
+    BatchRecord::new(format!("{}", Uuid::new_v4().to_hyphenated()), target_name.to_owned(), definitions, 1000)
+}
+
+// Starts the first command of the batch, returning the attempt token.
+async fn start(storage: &impl Storage, batch_id: &str) -> String {
+    let mut outcome = None;
+    assert!(storage.update_batch(batch_id, &mut |batch| {
+        outcome = Some(batch.start(0, "nonce", &[], |_| format!("{}", Uuid::new_v4()), 2000));
+    }).await.unwrap());
+    match outcome {
+        Some(StartOutcome::Started { token }) => token,
+        _ => panic!("Expected the first command to start"),
+    }
+}
+
+fn heartbeats(batch: &BatchRecord) -> Option<usize> {
+    match &batch.commands[0].attempts[0].state {
+        AttemptState::Started { heartbeats, .. } => Some(*heartbeats),
+        _ => None,
+    }
+}
+
+fn batch_ids(batches: Vec<BatchRecord>) -> Vec<String> {
+    batches.into_iter().map(|batch| batch.id).collect()
+}
@@ -0,0 +1,868 @@
+// Storage in a DynamoDB table, which lets many dispatch-service hosts share state. Also
+// works against DynamoDB Local and other stand-ins that speak the DynamoDB JSON protocol.
+//
+// All records live in one table with a string hash key named "pk". Batches are stored as
+// a single item holding the JSON encoded record, plus a "version" attribute which is
+// incremented on every write. Creates are conditional on the item not existing yet, and
+// updates are conditional on the version being unchanged since the record was read, so
+// concurrent writers on different hosts can never overwrite each other.
+//
+// Idempotency records are stored the same way under their own key prefix, but are never
+// updated so have no version. Deleting expired idempotency records scans the whole table,
+// which is acceptable since it only happens in the background every so often.
+//
+// Constraint groups and schedules are versioned items just like batches, under their own
+// key prefixes. Batch tombstones are unversioned items like idempotency records, and are
+// swept the same way. Connection records are unversioned too, and created conditionally
+// like idempotency records.
+//
+// Poll presence for a target is a single item, with one "node#<address>" attribute per
+// polling host holding the time the record expires. That keeps presence reads strongly
+// consistent, and each host only ever writes its own attribute.
+//
+// Listing a target's batches uses a global secondary index named "target_batches", with
+// "target_name" as its hash key and "dispatch_key" as its range key. Both attributes are
+// removed once the batch is no longer active, so the index only holds active batches.
+//
+// Outstanding batches and schedules are listed through a second global secondary index
+// named "listings", with a "listing" attribute as its hash key. Batches only have the
+// attribute while they are outstanding, and schedules always have it.
+//
+// GSI reads are eventually consistent, so a batch may take a moment to be listed after
+// dispatch, or be listed in a stale state. Polls woken for a batch read it directly instead
+// of waiting for the index to catch up.
+
+use super::{BatchOp, DatabaseError, GroupOp, ScheduleOp, Storage};
+use crate::aws::{self, AwsCredentials};
+use crate::operations::now_epoch_millis;
+use crate::records::{
+    BatchRecord,
+    BatchState,
+    BatchTombstoneRecord,
+    ConnectionRecord,
+    ConstraintGroupRecord,
+    IdempotencyRecord,
+    PollPresenceRecord,
+    ScheduleRecord,
+};
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::time::delay_for;
+use uuid::Uuid;
+
+const TARGET_BATCHES_INDEX: &str = "target_batches";
+const LISTINGS_INDEX: &str = "listings";
+// Values of the listing attribute.
+const OUTSTANDING_BATCHES_LISTING: &str = "outstanding_batches";
+const SCHEDULES_LISTING: &str = "schedules";
+const API_VERSION: &str = "DynamoDB_20120810";
+// How many times a conditional update is retried when it races with another writer.
+const MAX_UPDATE_ATTEMPTS: usize = 10;
+// Writers which lost a race wait a random time up to this, doubling with every attempt,
+// before trying again. Without the wait, busy batches can keep losing until they run out
+// of attempts.
+const UPDATE_BACKOFF_BASE: Duration = Duration::from_millis(5);
+const UPDATE_BACKOFF_MAX: Duration = Duration::from_millis(500);
+
+pub struct DynamoDatabase {
+    client: Client<HttpsConnector<HttpConnector>>,
+    config: DynamoConfig,
+    host: String,
+}
+
+#[derive(Clone)]
+pub struct DynamoConfig {
+    pub table_name: String,
+    pub region: String,
+    // Base URL of the DynamoDB API, eg https://dynamodb.us-west-2.amazonaws.com.
+    pub endpoint: String,
+    pub credentials: AwsCredentials,
+}
+
+#[derive(Debug)]
+pub struct DynamoError {
+    // Exception name, eg ConditionalCheckFailedException.
+    pub kind: String,
+    pub message: String,
+}
+
+impl DynamoConfig {
+    // Reads the region, endpoint and credentials from the standard AWS environment
+    // variables. DISPATCH_DYNAMO_ENDPOINT overrides the endpoint, eg for DynamoDB Local.
+    pub fn from_env(table_name: &str) -> Result<Self, DatabaseError> {
+        let region = aws::region_from_env();
+        let endpoint = std::env::var("DISPATCH_DYNAMO_ENDPOINT")
+            .unwrap_or_else(|_| format!("https://dynamodb.{}.amazonaws.com", region));
+        Ok(Self {
+            table_name: table_name.to_owned(),
+            region,
+            endpoint,
+            credentials: AwsCredentials::from_env().map_err(DatabaseError::InvalidSpec)?,
+        })
+    }
+}
+
+impl DynamoError {
+    fn is_conditional_check_failed(&self) -> bool {
+        self.kind == "ConditionalCheckFailedException"
+    }
+}
+
+impl DynamoDatabase {
+    pub(super) fn new(config: DynamoConfig) -> Result<Self, DatabaseError> {
+        let host = config.endpoint.parse::<Uri>()
+            .ok()
+            .and_then(|uri| uri.authority().map(|authority| authority.to_string()))
+            .ok_or_else(|| DatabaseError::InvalidSpec(format!(
+                "Invalid DynamoDB endpoint {}", config.endpoint)))?;
+        Ok(Self {
+            client: Client::builder().build(HttpsConnector::new()),
+            config,
+            host,
+        })
+    }
+
+    async fn read_versioned<T: DeserializeOwned>(&self, pk: &str) -> Result<Option<(T, u64)>, DatabaseError> {
+        let response = self.call("GetItem", json!({
+            "TableName": self.config.table_name,
+            "Key": { "pk": { "S": pk } },
+            "ConsistentRead": true,
+        })).await?;
+        let item = match response.get("Item") {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        let version = item.pointer("/version/N")
+            .and_then(Value::as_str)
+            .and_then(|version| version.parse().ok())
+            .ok_or_else(|| DatabaseError::Corrupt(format!("Item {} has no version", pk)))?;
+        Ok(Some((decode_record(item)?, version)))
+    }
+
+    // Applies op to the versioned record stored under pk, retrying against fresh reads
+    // when racing with other writers. Returns false if there is no such record. Removes the
+    // attributes stale_attributes returns for the updated record, eg to drop it from an index.
+    async fn update_versioned<T: Serialize + DeserializeOwned>(
+            &self,
+            pk: &str,
+            op: &mut dyn FnMut(&mut T),
+            stale_attributes: fn(&T) -> Vec<&'static str>) -> Result<bool, DatabaseError> {
+        for attempt in 0..MAX_UPDATE_ATTEMPTS {
+            if attempt > 0 {
+                delay_for(update_backoff(attempt)).await;
+            }
+            let (mut record, version) = match self.read_versioned::<T>(pk).await? {
+                Some(versioned) => versioned,
+                None => return Ok(false),
+            };
+            op(&mut record);
+            let mut update_expression = "SET #record = :record, #version = :next_version".to_owned();
+            let mut attribute_names = json!({ "#record": "record", "#version": "version" });
+            let stale = stale_attributes(&record);
+            if !stale.is_empty() {
+                let placeholders = stale.iter().map(|name| format!("#{}", name)).collect::<Vec<_>>();
+                update_expression = format!("{} REMOVE {}", update_expression, placeholders.join(", "));
+                for (placeholder, name) in placeholders.into_iter().zip(stale) {
+                    attribute_names[placeholder] = json!(name);
+                }
+            }
+            let put = self.call("UpdateItem", json!({
+                "TableName": self.config.table_name,
+                "Key": { "pk": { "S": pk } },
+                "UpdateExpression": update_expression,
+                "ConditionExpression": "#version = :version",
+                "ExpressionAttributeNames": attribute_names,
+                "ExpressionAttributeValues": {
+                    ":record": { "S": serde_json::to_string(&record)? },
+                    ":version": { "N": version.to_string() },
+                    ":next_version": { "N": (version + 1).to_string() },
+                },
+            })).await;
+            match put {
+                Ok(_) => return Ok(true),
+                // Somebody else wrote (or deleted) the record in the meantime. Try again
+                // against their write.
+                Err(DatabaseError::Dynamo(err)) if err.is_conditional_check_failed() => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(DatabaseError::Contention)
+    }
+
+    // Deletes the item, returning false if it did not exist.
+    async fn delete_item(&self, pk: &str) -> Result<bool, DatabaseError> {
+        let response = self.call("DeleteItem", json!({
+            "TableName": self.config.table_name,
+            "Key": { "pk": { "S": pk } },
+            "ReturnValues": "ALL_OLD",
+        })).await?;
+        Ok(response.get("Attributes").is_some())
+    }
+
+    // Every record in the index whose hash key attribute has the value, in range key order.
+    async fn query_records<T: DeserializeOwned>(
+            &self,
+            index: &str,
+            attribute: &str,
+            value: &str) -> Result<Vec<T>, DatabaseError> {
+        let mut records = Vec::new();
+        let mut start_key = None;
+        loop {
+            let mut request = json!({
+                "TableName": self.config.table_name,
+                "IndexName": index,
+                "KeyConditionExpression": "#key = :value",
+                "ExpressionAttributeNames": { "#key": attribute },
+                "ExpressionAttributeValues": { ":value": { "S": value } },
+            });
+            if let Some(start_key) = start_key.take() {
+                request["ExclusiveStartKey"] = start_key;
+            }
+            let mut response = self.call("Query", request).await?;
+            if let Some(items) = response.get("Items").and_then(Value::as_array) {
+                for item in items {
+                    records.push(decode_record(item)?);
+                }
+            }
+            match response.get_mut("LastEvaluatedKey") {
+                Some(last_key) => start_key = Some(last_key.take()),
+                None => return Ok(records),
+            }
+        }
+    }
+
+    // Deletes every item under the key prefix whose numeric attribute is below before,
+    // returning how many were deleted. Scans the whole table.
+    async fn delete_items_before(&self, prefix: &str, attribute: &str, before: usize) -> Result<usize, DatabaseError> {
+        let mut deleted = 0;
+        let mut start_key = None;
+        loop {
+            let mut request = json!({
+                "TableName": self.config.table_name,
+                "ProjectionExpression": "pk",
+                "FilterExpression": "begins_with(pk, :prefix) AND #attribute < :before",
+                "ExpressionAttributeNames": { "#attribute": attribute },
+                "ExpressionAttributeValues": {
+                    ":prefix": { "S": prefix },
+                    ":before": { "N": before.to_string() },
+                },
+                "ConsistentRead": true,
+            });
+
+            if let Some(start_key) = start_key.take() {
+                request["ExclusiveStartKey"] = start_key;
+            }
+            let mut response = self.call("Scan", request).await?;
+            let keys = response.get("Items")
+                .and_then(Value::as_array)
+                .map(|items| items.iter()
+                    .filter_map(|item| item.get("pk").cloned())
+                    .collect::<Vec<_>>())
+                .unwrap_or_default();
+            for key in keys {
+                // Another host may be sweeping at the same time, so only count the records
+                // which this call actually deleted.
+                let response = self.call("DeleteItem", json!({
+                    "TableName": self.config.table_name,
+                    "Key": { "pk": key },
+                    "ReturnValues": "ALL_OLD",
+                })).await?;
+                if response.get("Attributes").is_some() {
+                    deleted += 1;
+                }
+            }
+            match response.get_mut("LastEvaluatedKey") {
+                Some(last_key) => start_key = Some(last_key.take()),
+                None => return Ok(deleted),
+            }
+        }
+    }
+
+    // Invokes a DynamoDB API operation, returning the parsed response body.
+    async fn call(&self, operation: &str, body: Value) -> Result<Value, DatabaseError> {
+        let body = body.to_string();
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.0".to_owned()),
+            ("host", self.host.clone()),
+            ("x-amz-target", format!("{}.{}", API_VERSION, operation)),
+        ];
+        aws::sign(&self.config.credentials, &self.config.region, "dynamodb", &mut headers, &body,
+            now_epoch_millis() / 1000);
+
+        let mut request = Request::post(&self.config.endpoint);
+        for (name, value) in &headers {
+            request = request.header(*name, value.as_str());
+        }
+        let request = request
+            .body(Body::from(body))
+            .map_err(|err| DatabaseError::Corrupt(format!("Failed to build DynamoDB request: {}", err)))?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await?;
+        let response: Value = serde_json::from_slice(&bytes)?;
+        if status.is_success() {
+            return Ok(response);
+        }
+        let kind = response.get("__type")
+            .and_then(Value::as_str)
+            .map(|kind| kind.rsplit('#').next().unwrap_or(kind))
+            .unwrap_or("Unknown");
+        let message = response.get("message")
+            .or_else(|| response.get("Message"))
+            .and_then(Value::as_str)
+            .unwrap_or("");
+        Err(DatabaseError::Dynamo(DynamoError {
+            kind: kind.to_owned(),
+            message: message.to_owned(),
+        }))
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for DynamoDatabase {
+    async fn create_batch(&self, batch: BatchRecord) -> Result<bool, DatabaseError> {
+        let mut item = json!({
+            "pk": { "S": batch_key(&batch.id) },
+            "version": { "N": "0" },
+            "record": { "S": serde_json::to_string(&batch)? },
+        });
+        if batch.state == BatchState::Active {
+            item["target_name"] = json!({ "S": batch.target_name });
+            item["dispatch_key"] = json!({ "S": format!("{:016}#{}", now_epoch_millis(), batch.id) });
+        }
+        if batch.is_outstanding() {
+            item["listing"] = json!({ "S": OUTSTANDING_BATCHES_LISTING });
+        }
+        let result = self.call("PutItem", json!({
+            "TableName": self.config.table_name,
+            "Item": item,
+            "ConditionExpression": "attribute_not_exists(pk)",
+        })).await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(DatabaseError::Dynamo(err)) if err.is_conditional_check_failed() => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn read_batch(&self, batch_id: &str) -> Result<Option<BatchRecord>, DatabaseError> {
+        Ok(self.read_versioned(&batch_key(batch_id)).await?.map(|(batch, _version)| batch))
+    }
+
+    async fn update_batch(
+            &self,
+            batch_id: &str,
+            op: &mut BatchOp<'_>) -> Result<bool, DatabaseError> {
+        self.update_versioned(&batch_key(batch_id), op, stale_batch_attributes).await
+    }
+
+    async fn delete_batch(&self, batch_id: &str) -> Result<bool, DatabaseError> {
+        self.delete_item(&batch_key(batch_id)).await
+    }
+
+    async fn list_active_batches(&self, target_name: &str) -> Result<Vec<BatchRecord>, DatabaseError> {
+        let batches = self.query_records::<BatchRecord>(TARGET_BATCHES_INDEX, "target_name", target_name).await?;
+        // The index may not have caught up with batches which just finished.
+        Ok(batches.into_iter().filter(|batch| batch.state == BatchState::Active).collect())
+    }
+
+    async fn list_outstanding_batches(&self) -> Result<Vec<BatchRecord>, DatabaseError> {
+        let batches = self.query_records::<BatchRecord>(LISTINGS_INDEX, "listing", OUTSTANDING_BATCHES_LISTING).await?;
+        Ok(batches.into_iter().filter(BatchRecord::is_outstanding).collect())
+    }
+
+    async fn put_batch_tombstone(&self, record: BatchTombstoneRecord) -> Result<(), DatabaseError> {
+        self.call("PutItem", json!({
+            "TableName": self.config.table_name,
+            "Item": {
+                "pk": { "S": batch_tombstone_key(&record.batch_id) },
+                "deleted_epoch_millis": { "N": record.deleted_epoch_millis.to_string() },
+                "record": { "S": serde_json::to_string(&record)? },
+            },
+        })).await?;
+        Ok(())
+    }
+
+    async fn read_batch_tombstone(&self, batch_id: &str) -> Result<Option<BatchTombstoneRecord>, DatabaseError> {
+        let response = self.call("GetItem", json!({
+            "TableName": self.config.table_name,
+            "Key": { "pk": { "S": batch_tombstone_key(batch_id) } },
+            "ConsistentRead": true,
+        })).await?;
+        response.get("Item").map(decode_record).transpose()
+    }
+
+    async fn delete_batch_tombstones(&self, deleted_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        self.delete_items_before(&batch_tombstone_key(""), "deleted_epoch_millis", deleted_before_epoch_millis).await
+    }
+
+    async fn create_idempotency_record(
+            &self,
+            record: IdempotencyRecord) -> Result<IdempotencyRecord, DatabaseError> {
+        let key = idempotency_key(&record.key);
+        let result = self.call("PutItem", json!({
+            "TableName": self.config.table_name,
+            "Item": {
+                "pk": { "S": key },
+                "created_epoch_millis": { "N": record.created_epoch_millis.to_string() },
+                "record": { "S": serde_json::to_string(&record)? },
+            },
+            "ConditionExpression": "attribute_not_exists(pk)",
+        })).await;
+        match result {
+            Ok(_) => return Ok(record),
+            Err(DatabaseError::Dynamo(err)) if err.is_conditional_check_failed() => (),
+            Err(err) => return Err(err),
+        }
+        let response = self.call("GetItem", json!({
+            "TableName": self.config.table_name,
+            "Key": { "pk": { "S": key } },
+            "ConsistentRead": true,
+        })).await?;
+        let item = response.get("Item")
+            .ok_or_else(|| DatabaseError::Corrupt(format!(
+                "Idempotency record {} vanished after a conflicting write", record.key)))?;
+        decode_record(item)
+    }
+
+    async fn delete_idempotency_records(
+            &self,
+            created_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        self.delete_items_before(&idempotency_key(""), "created_epoch_millis", created_before_epoch_millis).await
+    }
+
+    async fn create_connection(&self, record: ConnectionRecord) -> Result<ConnectionRecord, DatabaseError> {
+        let result = self.call("PutItem", json!({
+            "TableName": self.config.table_name,
+            "Item": {
+                "pk": { "S": connection_key(&record.target_name, &record.account_id) },
+                "record": { "S": serde_json::to_string(&record)? },
+            },
+            "ConditionExpression": "attribute_not_exists(pk)",
+        })).await;
+        match result {
+            Ok(_) => return Ok(record),
+            Err(DatabaseError::Dynamo(err)) if err.is_conditional_check_failed() => (),
+            Err(err) => return Err(err),
+        }
+        self.read_connection(&record.target_name, &record.account_id).await?
+            .ok_or_else(|| DatabaseError::Corrupt(format!(
+                "Connection {} {} vanished after a conflicting write", record.target_name, record.account_id)))
+    }
+
+    async fn read_connection(
+            &self,
+            target_name: &str,
+            account_id: &str) -> Result<Option<ConnectionRecord>, DatabaseError> {
+        let response = self.call("GetItem", json!({
+            "TableName": self.config.table_name,
+            "Key": { "pk": { "S": connection_key(target_name, account_id) } },
+            "ConsistentRead": true,
+        })).await?;
+        response.get("Item").map(decode_record).transpose()
+    }
+
+    async fn put_poll_presence(&self, record: PollPresenceRecord) -> Result<(), DatabaseError> {
+        self.call("UpdateItem", json!({
+            "TableName": self.config.table_name,
+            "Key": { "pk": { "S": poll_presence_key(&record.target_name) } },
+            "UpdateExpression": "SET #node = :expires",
+            "ExpressionAttributeNames": { "#node": node_attribute(&record.node_address) },
+            "ExpressionAttributeValues": {
+                ":expires": { "N": record.expires_epoch_millis.to_string() },
+            },
+        })).await?;
+        Ok(())
+    }
+
+    async fn list_poll_presence(&self, target_name: &str) -> Result<Vec<PollPresenceRecord>, DatabaseError> {
+        let response = self.call("GetItem", json!({
+            "TableName": self.config.table_name,
+            "Key": { "pk": { "S": poll_presence_key(target_name) } },
+            "ConsistentRead": true,
+        })).await?;
+        Ok(response.get("Item")
+            .map(|item| decode_poll_presence(target_name, item))
+            .unwrap_or_default())
+    }
+
+    async fn delete_poll_presence(&self, expired_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        let prefix = poll_presence_key("");
+        let mut deleted = 0;
+        let mut start_key = None;
+        loop {
+            let mut request = json!({
+                "TableName": self.config.table_name,
+                "FilterExpression": "begins_with(pk, :prefix)",
+                "ExpressionAttributeValues": { ":prefix": { "S": prefix } },
+                "ConsistentRead": true,
+            });
+            if let Some(start_key) = start_key.take() {
+                request["ExclusiveStartKey"] = start_key;
+            }
+            let mut response = self.call("Scan", request).await?;
+            let expired = response.get("Items")
+                .and_then(Value::as_array)
+                .map(|items| items.iter()
+                    .filter_map(|item| item.pointer("/pk/S").and_then(Value::as_str).map(|pk| (pk, item)))
+                    .flat_map(|(pk, item)| decode_poll_presence(&pk[prefix.len()..], item))
+                    .filter(|record| record.expires_epoch_millis < expired_before_epoch_millis)
+                    .collect::<Vec<_>>())
+                .unwrap_or_default();
+            for record in expired {
+                // Only remove the attribute if the host has not refreshed it since the scan.
+                let result = self.call("UpdateItem", json!({
+                    "TableName": self.config.table_name,
+                    "Key": { "pk": { "S": poll_presence_key(&record.target_name) } },
+                    "UpdateExpression": "REMOVE #node",
+                    "ConditionExpression": "#node = :expires",
+                    "ExpressionAttributeNames": { "#node": node_attribute(&record.node_address) },
+                    "ExpressionAttributeValues": {
+                        ":expires": { "N": record.expires_epoch_millis.to_string() },
+                    },
+                })).await;
+                match result {
+                    Ok(_) => deleted += 1,
+                    Err(DatabaseError::Dynamo(err)) if err.is_conditional_check_failed() => (),
+                    Err(err) => return Err(err),
+                }
+            }
+            match response.get_mut("LastEvaluatedKey") {
+                Some(last_key) => start_key = Some(last_key.take()),
+                None => return Ok(deleted),
+            }
+        }
+    }
+
+    async fn create_constraint_group(&self, group: ConstraintGroupRecord) -> Result<bool, DatabaseError> {
+        let result = self.call("PutItem", json!({
+            "TableName": self.config.table_name,
+            "Item": {
+                "pk": { "S": constraint_group_key(&group.name) },
+                "version": { "N": "0" },
+                "record": { "S": serde_json::to_string(&group)? },
+            },
+            "ConditionExpression": "attribute_not_exists(pk)",
+        })).await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(DatabaseError::Dynamo(err)) if err.is_conditional_check_failed() => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn read_constraint_group(&self, name: &str) -> Result<Option<ConstraintGroupRecord>, DatabaseError> {
+        Ok(self.read_versioned(&constraint_group_key(name)).await?.map(|(group, _version)| group))
+    }
+
+    async fn update_constraint_group(
+            &self,
+            name: &str,
+            op: &mut GroupOp<'_>) -> Result<bool, DatabaseError> {
+        self.update_versioned(&constraint_group_key(name), op, |_| Vec::new()).await
+    }
+
+    async fn delete_constraint_group(&self, name: &str) -> Result<bool, DatabaseError> {
+        self.delete_item(&constraint_group_key(name)).await
+    }
+
+    async fn create_schedule(&self, schedule: ScheduleRecord) -> Result<bool, DatabaseError> {
+        let result = self.call("PutItem", json!({
+            "TableName": self.config.table_name,
+            "Item": {
+                "pk": { "S": schedule_key(&schedule.name) },
+                "version": { "N": "0" },
+                "listing": { "S": SCHEDULES_LISTING },
+                "record": { "S": serde_json::to_string(&schedule)? },
+            },
+            "ConditionExpression": "attribute_not_exists(pk)",
+        })).await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(DatabaseError::Dynamo(err)) if err.is_conditional_check_failed() => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn read_schedule(&self, name: &str) -> Result<Option<ScheduleRecord>, DatabaseError> {
+        Ok(self.read_versioned(&schedule_key(name)).await?.map(|(schedule, _version)| schedule))
+    }
+
+    async fn update_schedule(
+            &self,
+            name: &str,
+            op: &mut ScheduleOp<'_>) -> Result<bool, DatabaseError> {
+        self.update_versioned(&schedule_key(name), op, |_| Vec::new()).await
+    }
+
+    async fn delete_schedule(&self, name: &str) -> Result<bool, DatabaseError> {
+        self.delete_item(&schedule_key(name)).await
+    }
+
+    async fn list_schedules(&self) -> Result<Vec<ScheduleRecord>, DatabaseError> {
+        self.query_records(LISTINGS_INDEX, "listing", SCHEDULES_LISTING).await
+    }
+}
+
+// Full jitter exponential backoff.
+fn update_backoff(attempt: usize) -> Duration {
+    let max = UPDATE_BACKOFF_BASE
+        .checked_mul(1 << attempt.min(16))
+        .map_or(UPDATE_BACKOFF_MAX, |max| max.min(UPDATE_BACKOFF_MAX));
+    let fraction = (Uuid::new_v4().as_u128() % 1024) as u32;
+    max * fraction / 1024
+}
+
+// Index attributes a batch should no longer have. Batches never become active or
+// outstanding again, so nothing has to be added back.
+fn stale_batch_attributes(batch: &BatchRecord) -> Vec<&'static str> {
+    let mut stale = Vec::new();
+    if batch.state != BatchState::Active {
+        stale.extend(["target_name", "dispatch_key"]);
+    }
+    if !batch.is_outstanding() {
+        stale.push("listing");
+    }
+    stale
+}
+
+fn batch_key(batch_id: &str) -> String {
+    format!("batch#{}", batch_id)
+}
+
+fn idempotency_key(key: &str) -> String {
+    format!("idempotency#{}", key)
+}
+
+fn batch_tombstone_key(batch_id: &str) -> String {
+    format!("tombstone#{}", batch_id)
+}
+
+fn constraint_group_key(name: &str) -> String {
+    format!("group#{}", name)
+}
+
+fn schedule_key(name: &str) -> String {
+    format!("schedule#{}", name)
+}
+
+// JSON encodes the pair, so that a # in either part cannot make two pairs collide.
+fn connection_key(target_name: &str, account_id: &str) -> String {
+    format!("connection#{}", json!([target_name, account_id]))
+}
+
+fn poll_presence_key(target_name: &str) -> String {
+    format!("poll#{}", target_name)
+}
+
+fn node_attribute(node_address: &str) -> String {
+    format!("node#{}", node_address)
+}
+
+fn decode_poll_presence(target_name: &str, item: &Value) -> Vec<PollPresenceRecord> {
+    item.as_object()
+        .map(|attributes| attributes.iter()
+            .filter_map(|(name, value)| Some(PollPresenceRecord {
+                target_name: target_name.to_owned(),
+                node_address: name.strip_prefix("node#")?.to_owned(),
+                expires_epoch_millis: value.get("N")?.as_str()?.parse().ok()?,
+            }))
+            .collect())
+        .unwrap_or_default()
+}
+
+fn decode_record<T: DeserializeOwned>(item: &Value) -> Result<T, DatabaseError> {
+    let record = item.pointer("/record/S")
+        .and_then(Value::as_str)
+        .ok_or_else(|| DatabaseError::Corrupt("Item has no record attribute".to_owned()))?;
+    Ok(serde_json::from_str(record)?)
+}
+
+// These tests need a DynamoDB stand-in such as DynamoDB Local, and are skipped unless
+// DYNAMODB_LOCAL_ENDPOINT is set, eg to http://localhost:8000.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+    use crate::operations::Context;
+    use crate::operations::testing::{call, context};
+    use crate::records::{CommandDefinition, RetryPolicy};
+
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use uuid::Uuid;
+
+    // Creates a fresh table in DynamoDB Local, or returns None if DYNAMODB_LOCAL_ENDPOINT is
+    // not set.
+    fn test_database() -> Option<DynamoDatabase> {
+        let endpoint = std::env::var("DYNAMODB_LOCAL_ENDPOINT").ok()?;
+        let config = DynamoConfig {
+            table_name: format!("dispatch-test-{}", Uuid::new_v4().to_simple()),
+            region: "us-west-2".to_owned(),
+            endpoint,
+            credentials: AwsCredentials {
+                access_key_id: "test".to_owned(),
+                secret_access_key: "test".to_owned(),
+                session_token: None,
+            },
+        };
+        // The table is created with its own client, since pooled connections do not
+        // outlive the runtime they were made on.
+        let setup = DynamoDatabase::new(config.clone()).expect("Failed to create DynamoDatabase");
+        let create_table = setup.call("CreateTable", json!({
+            "TableName": config.table_name,
+            "BillingMode": "PAY_PER_REQUEST",
+            "AttributeDefinitions": [
+                { "AttributeName": "pk", "AttributeType": "S" },
+                { "AttributeName": "target_name", "AttributeType": "S" },
+                { "AttributeName": "dispatch_key", "AttributeType": "S" },
+                { "AttributeName": "listing", "AttributeType": "S" },
+            ],
+            "KeySchema": [{ "AttributeName": "pk", "KeyType": "HASH" }],
+            "GlobalSecondaryIndexes": [{
+                "IndexName": TARGET_BATCHES_INDEX,
+                "KeySchema": [
+                    { "AttributeName": "target_name", "KeyType": "HASH" },
+                    { "AttributeName": "dispatch_key", "KeyType": "RANGE" },
+                ],
+                "Projection": { "ProjectionType": "ALL" },
+            }, {
+                "IndexName": LISTINGS_INDEX,
+                "KeySchema": [{ "AttributeName": "listing", "KeyType": "HASH" }],
+                "Projection": { "ProjectionType": "ALL" },
+            }],
+        }));
+        tokio::runtime::Runtime::new()
+            .expect("Failed to build tokio runtime")
+            .block_on(create_table)
+            .expect("Failed to create test table");
+        Some(DynamoDatabase::new(config).expect("Failed to create DynamoDatabase"))
+    }
+
+    storage_conformance_tests!(test_database());
+
+    // The ids of the batches the index lists under the hash key, without the filtering
+    // list_active_batches and list_outstanding_batches do on top.
+    async fn indexed_batch_ids(database: &DynamoDatabase, index: &str, attribute: &str, value: &str) -> Vec<String> {
+        let batches = database.query_records::<BatchRecord>(index, attribute, value).await.unwrap();
+        batches.into_iter().map(|batch| batch.id).collect()
+    }
+
+    #[test]
+    fn finished_batches_leave_the_indexes() {
+        let database = match test_database() {
+            Some(database) => database,
+            None => return,
+        };
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let definition = CommandDefinition {
+                name: "deploy".to_owned(),
+                data: "v2".to_owned(),
+                max_retries: 0,
+                success_required: true,
+                retry_policy: RetryPolicy::Immediate,
+                execution_timeout_millis: None,
+                parallel_with_previous: false,
+            };
+            let (active, done) = ("active".to_owned(), "done".to_owned());
+            for batch_id in [&active, &done] {
+                let batch = BatchRecord::new(batch_id.clone(), "target".to_owned(), vec![definition.clone()], 1000);
+                assert!(database.create_batch(batch).await.unwrap());
+            }
+            assert!(database.update_batch(&done, &mut |batch| {
+                batch.state = BatchState::Done { succeeded: true };
+            }).await.unwrap());
+
+            assert_eq!(indexed_batch_ids(&database, TARGET_BATCHES_INDEX, "target_name", "target").await,
+                vec![active.clone()]);
+            assert_eq!(indexed_batch_ids(&database, LISTINGS_INDEX, "listing", OUTSTANDING_BATCHES_LISTING).await,
+                vec![active]);
+            // Still readable directly.
+            assert!(database.read_batch(&done).await.unwrap().is_some());
+        });
+    }
+
+    fn receive(timeout_millis: usize, exclude_batches: &[&str]) -> Value {
+        json!({
+            "target_name": "target",
+            "exclude_batches": exclude_batches,
+            "group_membership": [],
+            "timeout_millis": timeout_millis,
+        })
+    }
+
+    #[test]
+    fn dispatches_wake_polls_immediately() {
+        let database = match test_database() {
+            Some(database) => database,
+            None => return,
+        };
+        // Writes items directly, bypassing create_batch.
+        let raw = DynamoDatabase::new(database.config.clone()).unwrap();
+        let context = Arc::new(Context {
+            database: Arc::new(Database::new(database)),
+            ..context()
+        });
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let started = Instant::now();
+            let ((status, received), (_, dispatched)) = futures::join!(
+                call(&context, "/api/dispatch/receive_commands", receive(10_000, &[])),
+                async {
+                    delay_for(Duration::from_millis(100)).await;
+                    call(&context, "/api/dispatch/dispatch_commands", json!({
+                        "target_name": "target",
+                        "nonce": "nonce",
+                        "commands": [{ "name": "deploy", "data": "v2", "max_retries": 0, "success_required": true }],
+                    })).await
+                });
+            assert_eq!(status, 200);
+            assert_eq!(received["command_batches"][0]["id"], dispatched["batch_id"]);
+            assert!(started.elapsed() < Duration::from_secs(5));
+            let first = dispatched["batch_id"].as_str().unwrap().to_owned();
+
+            // A batch the target_batches index has not caught up with yet, as DynamoDB's
+            // eventually consistent index reads allow.
+            let definition = CommandDefinition {
+                name: "deploy".to_owned(),
+                data: "v3".to_owned(),
+                max_retries: 0,
+                success_required: true,
+                retry_policy: RetryPolicy::Immediate,
+                execution_timeout_millis: None,
+                parallel_with_previous: false,
+            };
+            let batch = BatchRecord::new(format!("{}", Uuid::new_v4().to_hyphenated()), "target".to_owned(),
+                vec![definition], now_epoch_millis());
+            raw.call("PutItem", json!({
+                "TableName": raw.config.table_name,
+                "Item": {
+                    "pk": { "S": batch_key(&batch.id) },
+                    "version": { "N": "0" },
+                    "record": { "S": serde_json::to_string(&batch).unwrap() },
+                },
+            })).await.unwrap();
+            let (_, received) = call(&context, "/api/dispatch/receive_commands", receive(0, &[&first])).await;
+            assert_eq!(received["command_batches"], json!([]));
+
+            let started = Instant::now();
+            let ((status, received), _) = futures::join!(
+                call(&context, "/api/dispatch/receive_commands", receive(10_000, &[&first])),
+                async {
+                    delay_for(Duration::from_millis(100)).await;
+                    context.polls.wake("target", Some(&batch.id))
+                });
+            assert_eq!(status, 200);
+            assert_eq!(received["command_batches"][0]["id"], batch.id.as_str());
+            assert!(started.elapsed() < Duration::from_secs(5));
+        });
+    }
+}
@@ -0,0 +1,587 @@
+// Durable single-node storage. All data is held in memory, and every write is first
+// appended to a write-ahead log (WAL) and fsynced before being applied. Every
+// SNAPSHOT_INTERVAL log entries, the full tables are written to a snapshot file and the
+// log is truncated.
+//
+// Recovery loads the latest snapshot and replays any log entries newer than it. A torn
+// final log entry, left behind by a crash in the middle of an append, is discarded since
+// that write was never acknowledged. Any other unreadable entry is treated as corruption
+// and fails startup rather than silently losing acknowledged writes.
+//
+// Poll presence records are only kept in memory, since polls do not survive a restart.
+
+use super::{BatchOp, DatabaseError, GroupOp, ScheduleOp, Storage};
+use super::blocking::{self, Permit};
+use super::local::LocalTables;
+use crate::records::{
+    BatchRecord,
+    BatchTombstoneRecord,
+    ConnectionRecord,
+    ConstraintGroupRecord,
+    IdempotencyRecord,
+    PollPresenceRecord,
+    ScheduleRecord,
+};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+const SNAPSHOT_INTERVAL: usize = 10_000;
+const WAL_FILE_NAME: &str = "wal.log";
+const SNAPSHOT_FILE_NAME: &str = "snapshot.json";
+const SNAPSHOT_TMP_FILE_NAME: &str = "snapshot.json.tmp";
+
+// Reads only lock the tables. Writes hold the permit from checking the tables until their
+// entry is logged and applied, with the logging done on the blocking thread pool.
+pub struct FileDatabase {
+    tables: Arc<Mutex<LocalTables>>,
+    log: Arc<Mutex<WalState>>,
+    permit: Arc<Semaphore>,
+}
+
+// Only used while holding the permit.
+struct WalState {
+    data_dir: PathBuf,
+    wal: File,
+    // Length of the log up to and including the last fully written entry.
+    wal_len: u64,
+    // Sequence number to assign to the next log entry.
+    next_sequence: u64,
+    entries_since_snapshot: usize,
+    snapshot_interval: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WalRecord {
+    sequence: u64,
+    entry: WalEntry,
+}
+
+#[derive(Serialize, Deserialize)]
+enum WalEntry {
+    // Insert or overwrite a batch.
+    PutBatch(Box<BatchRecord>),
+    DeleteBatch(String),
+    PutIdempotencyRecord(IdempotencyRecord),
+    DeleteIdempotencyRecords(Vec<String>),
+    // Insert or overwrite a constraint group.
+    PutConstraintGroup(ConstraintGroupRecord),
+    DeleteConstraintGroup(String),
+    // Insert or overwrite a batch tombstone.
+    PutBatchTombstone(BatchTombstoneRecord),
+    DeleteBatchTombstones(Vec<String>),
+    // Insert or overwrite a schedule.
+    PutSchedule(ScheduleRecord),
+    DeleteSchedule(String),
+    PutConnection(ConnectionRecord),
+}
+
+// Written from a borrow of the live tables, read back as owned ones.
+#[derive(Serialize, Deserialize)]
+struct Snapshot<T = LocalTables> {
+    // Sequence number of the last log entry reflected in the snapshot.
+    sequence: u64,
+    tables: T,
+}
+
+impl FileDatabase {
+    pub(super) fn open(data_dir: &Path) -> Result<Self, DatabaseError> {
+        Self::open_with_snapshot_interval(data_dir, SNAPSHOT_INTERVAL)
+    }
+
+    fn open_with_snapshot_interval(data_dir: &Path, snapshot_interval: usize) -> Result<Self, DatabaseError> {
+        fs::create_dir_all(data_dir)?;
+        let (mut tables, snapshot_sequence) = match read_snapshot(data_dir)? {
+            Some(snapshot) => (snapshot.tables, Some(snapshot.sequence)),
+            None => (LocalTables::default(), None),
+        };
+
+        let wal_path = data_dir.join(WAL_FILE_NAME);
+        let mut wal = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&wal_path)?;
+        let mut contents = Vec::new();
+        wal.read_to_end(&mut contents)?;
+
+        let mut next_sequence = snapshot_sequence.map_or(0, |sequence| sequence + 1);
+        let mut entries_since_snapshot = 0;
+        let mut wal_len = 0;
+        let mut lines = contents.split_inclusive(|byte| *byte == b'\n').peekable();
+        while let Some(line) = lines.next() {
+            let is_last = lines.peek().is_none();
+            let record = match serde_json::from_slice::<WalRecord>(line) {
+                Ok(record) if line.ends_with(b"\n") => record,
+                // Only an append interrupted before its newline was written is torn. A complete
+                // entry which fails to parse was acknowledged, so it is corruption like any other.
+                _ if is_last && !line.ends_with(b"\n") => break,
+                _ => return Err(DatabaseError::Corrupt(format!(
+                    "Unreadable write-ahead log entry at byte offset {} of {}",
+                    wal_len, wal_path.display()))),
+            };
+            wal_len += line.len() as u64;
+            entries_since_snapshot += 1;
+            if snapshot_sequence.is_some_and(|sequence| record.sequence <= sequence) {
+                // Already reflected in the snapshot, which was written right before the
+                // log was going to be truncated.
+                continue;
+            }
+            apply(&mut tables, record.entry);
+            next_sequence = record.sequence + 1;
+        }
+        // Drop the torn tail, if any, so that new entries are appended after the last good one.
+        wal.set_len(wal_len)?;
+        wal.sync_data()?;
+
+        Ok(Self {
+            tables: Arc::new(Mutex::new(tables)),
+            log: Arc::new(Mutex::new(WalState {
+                data_dir: data_dir.to_owned(),
+                wal,
+                wal_len,
+                next_sequence,
+                entries_since_snapshot,
+                snapshot_interval,
+            })),
+            permit: Arc::new(Semaphore::new(1)),
+        })
+    }
+
+    fn tables(&self) -> MutexGuard<'_, LocalTables> {
+        blocking::lock(&self.tables)
+    }
+
+    async fn acquire(&self) -> Permit {
+        Permit::acquire(&self.permit).await
+    }
+
+    // Durably logs the entry and then applies it to the tables.
+    async fn append(&self, permit: Permit, entry: WalEntry) -> Result<(), DatabaseError> {
+        let (tables, log) = (self.tables.clone(), self.log.clone());
+        blocking::run(move || {
+            let _permit = permit;
+            blocking::lock(&log).append(&tables, entry)
+        }).await
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for FileDatabase {
+    async fn create_batch(&self, batch: BatchRecord) -> Result<bool, DatabaseError> {
+        let permit = self.acquire().await;
+        if self.tables().read_batch(&batch.id).is_some() {
+            return Ok(false);
+        }
+        self.append(permit, WalEntry::PutBatch(Box::new(batch))).await?;
+        Ok(true)
+    }
+
+    async fn read_batch(&self, batch_id: &str) -> Result<Option<BatchRecord>, DatabaseError> {
+        Ok(self.tables().read_batch(batch_id))
+    }
+
+    async fn update_batch(
+            &self,
+            batch_id: &str,
+            op: &mut BatchOp<'_>) -> Result<bool, DatabaseError> {
+        let permit = self.acquire().await;
+        let mut batch = match self.tables().read_batch(batch_id) {
+            Some(batch) => batch,
+            None => return Ok(false),
+        };
+        op(&mut batch);
+        self.append(permit, WalEntry::PutBatch(Box::new(batch))).await?;
+        Ok(true)
+    }
+
+    async fn delete_batch(&self, batch_id: &str) -> Result<bool, DatabaseError> {
+        let permit = self.acquire().await;
+        if self.tables().read_batch(batch_id).is_none() {
+            return Ok(false);
+        }
+        self.append(permit, WalEntry::DeleteBatch(batch_id.to_owned())).await?;
+        Ok(true)
+    }
+
+    async fn list_active_batches(&self, target_name: &str) -> Result<Vec<BatchRecord>, DatabaseError> {
+        Ok(self.tables().list_active_batches(target_name))
+    }
+
+    async fn list_outstanding_batches(&self) -> Result<Vec<BatchRecord>, DatabaseError> {
+        Ok(self.tables().list_outstanding_batches())
+    }
+
+    async fn put_batch_tombstone(&self, record: BatchTombstoneRecord) -> Result<(), DatabaseError> {
+        let permit = self.acquire().await;
+        self.append(permit, WalEntry::PutBatchTombstone(record)).await
+    }
+
+    async fn read_batch_tombstone(&self, batch_id: &str) -> Result<Option<BatchTombstoneRecord>, DatabaseError> {
+        Ok(self.tables().read_batch_tombstone(batch_id))
+    }
+
+    async fn delete_batch_tombstones(&self, deleted_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        let permit = self.acquire().await;
+        let batch_ids = self.tables().batch_tombstones_deleted_before(deleted_before_epoch_millis);
+        let deleted = batch_ids.len();
+        if deleted > 0 {
+            self.append(permit, WalEntry::DeleteBatchTombstones(batch_ids)).await?;
+        }
+        Ok(deleted)
+    }
+
+    async fn create_idempotency_record(
+            &self,
+            record: IdempotencyRecord) -> Result<IdempotencyRecord, DatabaseError> {
+        let permit = self.acquire().await;
+        if let Some(existing) = self.tables().read_idempotency_record(&record.key) {
+            return Ok(existing);
+        }
+        self.append(permit, WalEntry::PutIdempotencyRecord(record.clone())).await?;
+        Ok(record)
+    }
+
+    async fn delete_idempotency_records(
+            &self,
+            created_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        let permit = self.acquire().await;
+        let keys = self.tables().idempotency_keys_created_before(created_before_epoch_millis);
+        let deleted = keys.len();
+        if deleted > 0 {
+            self.append(permit, WalEntry::DeleteIdempotencyRecords(keys)).await?;
+        }
+        Ok(deleted)
+    }
+
+    async fn create_connection(&self, record: ConnectionRecord) -> Result<ConnectionRecord, DatabaseError> {
+        let permit = self.acquire().await;
+        if let Some(existing) = self.tables().read_connection(&record.target_name, &record.account_id) {
+            return Ok(existing);
+        }
+        self.append(permit, WalEntry::PutConnection(record.clone())).await?;
+        Ok(record)
+    }
+
+    async fn read_connection(
+            &self,
+            target_name: &str,
+            account_id: &str) -> Result<Option<ConnectionRecord>, DatabaseError> {
+        Ok(self.tables().read_connection(target_name, account_id))
+    }
+
+    async fn put_poll_presence(&self, record: PollPresenceRecord) -> Result<(), DatabaseError> {
+        self.tables().put_poll_presence(record);
+        Ok(())
+    }
+
+    async fn list_poll_presence(&self, target_name: &str) -> Result<Vec<PollPresenceRecord>, DatabaseError> {
+        Ok(self.tables().list_poll_presence(target_name))
+    }
+
+    async fn delete_poll_presence(&self, expired_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        Ok(self.tables().delete_poll_presence(expired_before_epoch_millis))
+    }
+
+    async fn create_constraint_group(&self, group: ConstraintGroupRecord) -> Result<bool, DatabaseError> {
+        let permit = self.acquire().await;
+        if self.tables().read_constraint_group(&group.name).is_some() {
+            return Ok(false);
+        }
+        self.append(permit, WalEntry::PutConstraintGroup(group)).await?;
+        Ok(true)
+    }
+
+    async fn read_constraint_group(&self, name: &str) -> Result<Option<ConstraintGroupRecord>, DatabaseError> {
+        Ok(self.tables().read_constraint_group(name))
+    }
+
+    async fn update_constraint_group(
+            &self,
+            name: &str,
+            op: &mut GroupOp<'_>) -> Result<bool, DatabaseError> {
+        let permit = self.acquire().await;
+        let mut group = match self.tables().read_constraint_group(name) {
+            Some(group) => group,
+            None => return Ok(false),
+        };
+        op(&mut group);
+        self.append(permit, WalEntry::PutConstraintGroup(group)).await?;
+        Ok(true)
+    }
+
+    async fn delete_constraint_group(&self, name: &str) -> Result<bool, DatabaseError> {
+        let permit = self.acquire().await;
+        if self.tables().read_constraint_group(name).is_none() {
+            return Ok(false);
+        }
+        self.append(permit, WalEntry::DeleteConstraintGroup(name.to_owned())).await?;
+        Ok(true)
+    }
+
+    async fn create_schedule(&self, schedule: ScheduleRecord) -> Result<bool, DatabaseError> {
+        let permit = self.acquire().await;
+        if self.tables().read_schedule(&schedule.name).is_some() {
+            return Ok(false);
+        }
+        self.append(permit, WalEntry::PutSchedule(schedule)).await?;
+        Ok(true)
+    }
+
+    async fn read_schedule(&self, name: &str) -> Result<Option<ScheduleRecord>, DatabaseError> {
+        Ok(self.tables().read_schedule(name))
+    }
+
+    async fn update_schedule(
+            &self,
+            name: &str,
+            op: &mut ScheduleOp<'_>) -> Result<bool, DatabaseError> {
+        let permit = self.acquire().await;
+        let mut schedule = match self.tables().read_schedule(name) {
+            Some(schedule) => schedule,
+            None => return Ok(false),
+        };
+        op(&mut schedule);
+        self.append(permit, WalEntry::PutSchedule(schedule)).await?;
+        Ok(true)
+    }
+
+    async fn delete_schedule(&self, name: &str) -> Result<bool, DatabaseError> {
+        let permit = self.acquire().await;
+        if self.tables().read_schedule(name).is_none() {
+            return Ok(false);
+        }
+        self.append(permit, WalEntry::DeleteSchedule(name.to_owned())).await?;
+        Ok(true)
+    }
+
+    async fn list_schedules(&self) -> Result<Vec<ScheduleRecord>, DatabaseError> {
+        Ok(self.tables().list_schedules())
+    }
+}
+
+impl WalState {
+    // Durably logs the entry and then applies it to the tables. Blocks, and only locks the
+    // tables for in-memory work.
+    fn append(&mut self, tables: &Mutex<LocalTables>, entry: WalEntry) -> Result<(), DatabaseError> {
+        let record = WalRecord { sequence: self.next_sequence, entry };
+        let mut line = serde_json::to_vec(&record)
+            .map_err(|err| DatabaseError::Corrupt(format!("Failed to encode log entry: {}", err)))?;
+        line.push(b'\n');
+        let write_result = self.wal.write_all(&line)
+            .and_then(|_| self.wal.sync_data());
+        if let Err(err) = write_result {
+            // Best effort removal of a partial entry so it cannot be mistaken for a torn
+            // tail once later entries are appended after it.
+            let _ = self.wal.set_len(self.wal_len);
+            return Err(err.into());
+        }
+        self.wal_len += line.len() as u64;
+        self.next_sequence += 1;
+        self.entries_since_snapshot += 1;
+        apply(&mut blocking::lock(tables), record.entry);
+
+        if self.entries_since_snapshot >= self.snapshot_interval {
+            // The entry is already durable, so a failed snapshot only means a longer replay
+            // on the next startup.
+            // TODO: warn log
+            let _ = self.snapshot(tables);
+        }
+        Ok(())
+    }
+
+    fn snapshot(&mut self, tables: &Mutex<LocalTables>) -> Result<(), DatabaseError> {
+        let encoded = serde_json::to_vec(&Snapshot {
+            sequence: self.next_sequence - 1,
+            tables: &*blocking::lock(tables),
+        }).map_err(|err| DatabaseError::Corrupt(format!("Failed to encode snapshot: {}", err)))?;
+
+        let tmp_path = self.data_dir.join(SNAPSHOT_TMP_FILE_NAME);
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&encoded)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.data_dir.join(SNAPSHOT_FILE_NAME))?;
+        File::open(&self.data_dir)?.sync_all()?;
+
+        // Entries up to the snapshot's sequence are skipped during recovery, so a crash
+        // before this truncation is harmless.
+        self.wal.set_len(0)?;
+        self.wal.sync_data()?;
+        self.wal_len = 0;
+        self.entries_since_snapshot = 0;
+        Ok(())
+    }
+}
+
+fn apply(tables: &mut LocalTables, entry: WalEntry) {
+    match entry {
+        WalEntry::PutBatch(batch) => tables.put_batch(*batch),
+        WalEntry::DeleteBatch(batch_id) => {
+            tables.delete_batch(&batch_id);
+        }
+        WalEntry::PutIdempotencyRecord(record) => {
+            tables.put_idempotency_record(record);
+        }
+        WalEntry::DeleteIdempotencyRecords(keys) => {
+            for key in keys {
+                tables.delete_idempotency_record(&key);
+            }
+        }
+        WalEntry::PutConstraintGroup(group) => tables.put_constraint_group(group),
+        WalEntry::DeleteConstraintGroup(name) => {
+            tables.delete_constraint_group(&name);
+        }
+        WalEntry::PutBatchTombstone(record) => tables.put_batch_tombstone(record),
+        WalEntry::DeleteBatchTombstones(batch_ids) => {
+            for batch_id in batch_ids {
+                tables.delete_batch_tombstone(&batch_id);
+            }
+        }
+        WalEntry::PutSchedule(schedule) => tables.put_schedule(schedule),
+        WalEntry::DeleteSchedule(name) => {
+            tables.delete_schedule(&name);
+        }
+        WalEntry::PutConnection(record) => {
+            tables.put_connection(record);
+        }
+    }
+}
+
+fn read_snapshot(data_dir: &Path) -> Result<Option<Snapshot>, DatabaseError> {
+    let path = data_dir.join(SNAPSHOT_FILE_NAME);
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    serde_json::from_slice(&contents)
+        .map(Some)
+        .map_err(|err| DatabaseError::Corrupt(format!(
+            "Unreadable snapshot {}: {}", path.display(), err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{CommandDefinition, RetryPolicy};
+
+    use uuid::Uuid;
+
+    // A fresh data dir, removed again when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("dispatch-test-{}", Uuid::new_v4().to_simple())))
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn test_batch(target_name: &str) -> BatchRecord {
+        let definition = CommandDefinition {
+            name: "deploy".to_owned(),
+            data: "v2".to_owned(),
+            max_retries: 0,
+            success_required: true,
+            retry_policy: RetryPolicy::Immediate,
+            execution_timeout_millis: None,
+            parallel_with_previous: false,
+        };
+        BatchRecord::new(format!("{}", Uuid::new_v4().to_hyphenated()), target_name.to_owned(),
+            vec![definition], 1000)
+    }
+
+    // The TestDir is a temporary of the expression, so outlives the case using the database.
+    storage_conformance_tests!(Some(FileDatabase::open(TestDir::new().path()).expect("Failed to open FileDatabase")));
+
+    #[tokio::test]
+    async fn recovers_after_reopen() {
+        let test_dir = TestDir::new();
+        let data_dir = test_dir.path();
+        let (kept, deleted) = (test_batch("target"), test_batch("target"));
+        {
+            let database = FileDatabase::open_with_snapshot_interval(data_dir, 3).unwrap();
+            for batch in [kept.clone(), deleted.clone(), test_batch("other")] {
+                assert!(database.create_batch(batch).await.unwrap());
+            }
+            // The snapshot was taken, so these are only in the log.
+            assert!(database.update_batch(&kept.id, &mut |batch| {
+                batch.start(0, "nonce", &[], |_| "token".to_owned(), 2000);
+            }).await.unwrap());
+            assert!(database.delete_batch(&deleted.id).await.unwrap());
+            database.create_connection(ConnectionRecord {
+                target_name: "target".to_owned(),
+                account_id: "account".to_owned(),
+                created_epoch_millis: 3000,
+            }).await.unwrap();
+        }
+        assert!(data_dir.join(SNAPSHOT_FILE_NAME).exists());
+
+        let database = FileDatabase::open_with_snapshot_interval(data_dir, 3).unwrap();
+        let listed = database.list_active_batches("target").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, kept.id);
+        assert!(listed[0].commands[0].attempts[0].token().is_some());
+        assert_eq!(database.list_active_batches("other").await.unwrap().len(), 1);
+        assert!(database.read_connection("target", "account").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn discards_torn_tail() {
+        let test_dir = TestDir::new();
+        let data_dir = test_dir.path();
+        let batch = test_batch("target");
+        {
+            let database = FileDatabase::open(data_dir).unwrap();
+            assert!(database.create_batch(batch.clone()).await.unwrap());
+        }
+        let mut wal = OpenOptions::new().append(true).open(data_dir.join(WAL_FILE_NAME)).unwrap();
+        wal.write_all(b"{\"sequence\":1,\"entry\":{\"PutBa").unwrap();
+
+        let database = FileDatabase::open(data_dir).unwrap();
+        assert!(database.read_batch(&batch.id).await.unwrap().is_some());
+        let other = test_batch("target");
+        assert!(database.create_batch(other.clone()).await.unwrap());
+        drop(database);
+
+        let database = FileDatabase::open(data_dir).unwrap();
+        assert_eq!(database.list_active_batches("target").await.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_corrupt_entries() {
+        let test_dir = TestDir::new();
+        let data_dir = test_dir.path();
+        fs::create_dir_all(data_dir).unwrap();
+        fs::write(data_dir.join(WAL_FILE_NAME), "garbage\n{}\n").unwrap();
+        assert!(matches!(FileDatabase::open(data_dir), Err(DatabaseError::Corrupt(_))));
+    }
+
+    #[tokio::test]
+    async fn rejects_corrupt_final_entry() {
+        let test_dir = TestDir::new();
+        let data_dir = test_dir.path();
+        {
+            let database = FileDatabase::open(data_dir).unwrap();
+            assert!(database.create_batch(test_batch("target")).await.unwrap());
+        }
+        let mut wal = OpenOptions::new().append(true).open(data_dir.join(WAL_FILE_NAME)).unwrap();
+        wal.write_all(b"{\"sequence\":1,\"entry\":{\"PutBa\n").unwrap();
+        assert!(matches!(FileDatabase::open(data_dir), Err(DatabaseError::Corrupt(_))));
+    }
+}
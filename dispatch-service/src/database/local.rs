@@ -0,0 +1,375 @@
+use super::{BatchOp, DatabaseError, GroupOp, ScheduleOp, Storage};
+use crate::records::{
+    BatchRecord,
+    BatchState,
+    BatchTombstoneRecord,
+    ConnectionRecord,
+    ConstraintGroupRecord,
+    IdempotencyRecord,
+    PollPresenceRecord,
+    ScheduleRecord,
+};
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+pub struct LocalDatabase {
+    tables: Mutex<LocalTables>
+}
+
+// In-memory tables. Also used by FileDatabase, which keeps a full copy of its data in
+// memory and only goes to disk for writes.
+#[derive(Default, Serialize, Deserialize)]
+pub(super) struct LocalTables {
+    batches: HashMap<String, BatchRecord>,
+    // Batch ids per target name, in dispatch order.
+    target_batches: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    idempotency: HashMap<String, IdempotencyRecord>,
+    // Presence records by target name, then node address. Never persisted, since polls do
+    // not survive a restart either.
+    #[serde(skip)]
+    poll_presence: HashMap<String, HashMap<String, PollPresenceRecord>>,
+    // Constraint groups by name.
+    #[serde(default)]
+    constraint_groups: HashMap<String, ConstraintGroupRecord>,
+    // Tombstones of deleted batches by batch id.
+    #[serde(default)]
+    batch_tombstones: HashMap<String, BatchTombstoneRecord>,
+    // Schedules by name.
+    #[serde(default)]
+    schedules: HashMap<String, ScheduleRecord>,
+    // Connections by target name, then account id.
+    #[serde(default)]
+    connections: HashMap<String, HashMap<String, ConnectionRecord>>,
+}
+
+impl LocalDatabase {
+    pub fn new() -> Self {
+        LocalDatabase {
+            tables: Mutex::new(LocalTables::default())
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LocalTables> {
+        self.tables.lock().expect("LocalDatabase lock poisoned")
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for LocalDatabase {
+    async fn create_batch(&self, batch: BatchRecord) -> Result<bool, DatabaseError> {
+        Ok(self.lock().create_batch(batch))
+    }
+
+    async fn read_batch(&self, batch_id: &str) -> Result<Option<BatchRecord>, DatabaseError> {
+        Ok(self.lock().read_batch(batch_id))
+    }
+
+    async fn update_batch(
+            &self,
+            batch_id: &str,
+            op: &mut BatchOp<'_>) -> Result<bool, DatabaseError> {
+        Ok(self.lock().batches.get_mut(batch_id).map(op).is_some())
+    }
+
+    async fn delete_batch(&self, batch_id: &str) -> Result<bool, DatabaseError> {
+        Ok(self.lock().delete_batch(batch_id))
+    }
+
+    async fn list_active_batches(&self, target_name: &str) -> Result<Vec<BatchRecord>, DatabaseError> {
+        Ok(self.lock().list_active_batches(target_name))
+    }
+
+    async fn list_outstanding_batches(&self) -> Result<Vec<BatchRecord>, DatabaseError> {
+        Ok(self.lock().list_outstanding_batches())
+    }
+
+    async fn put_batch_tombstone(&self, record: BatchTombstoneRecord) -> Result<(), DatabaseError> {
+        self.lock().put_batch_tombstone(record);
+        Ok(())
+    }
+
+    async fn read_batch_tombstone(&self, batch_id: &str) -> Result<Option<BatchTombstoneRecord>, DatabaseError> {
+        Ok(self.lock().read_batch_tombstone(batch_id))
+    }
+
+    async fn delete_batch_tombstones(&self, deleted_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        let mut tables = self.lock();
+        let batch_ids = tables.batch_tombstones_deleted_before(deleted_before_epoch_millis);
+        for batch_id in &batch_ids {
+            tables.delete_batch_tombstone(batch_id);
+        }
+        Ok(batch_ids.len())
+    }
+
+    async fn create_idempotency_record(
+            &self,
+            record: IdempotencyRecord) -> Result<IdempotencyRecord, DatabaseError> {
+        let mut tables = self.lock();
+        Ok(tables.read_idempotency_record(&record.key)
+            .unwrap_or_else(|| tables.put_idempotency_record(record)))
+    }
+
+    async fn delete_idempotency_records(
+            &self,
+            created_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        let mut tables = self.lock();
+        let keys = tables.idempotency_keys_created_before(created_before_epoch_millis);
+        for key in &keys {
+            tables.delete_idempotency_record(key);
+        }
+        Ok(keys.len())
+    }
+
+    async fn create_connection(&self, record: ConnectionRecord) -> Result<ConnectionRecord, DatabaseError> {
+        let mut tables = self.lock();
+        Ok(tables.read_connection(&record.target_name, &record.account_id)
+            .unwrap_or_else(|| tables.put_connection(record)))
+    }
+
+    async fn read_connection(
+            &self,
+            target_name: &str,
+            account_id: &str) -> Result<Option<ConnectionRecord>, DatabaseError> {
+        Ok(self.lock().read_connection(target_name, account_id))
+    }
+
+    async fn put_poll_presence(&self, record: PollPresenceRecord) -> Result<(), DatabaseError> {
+        self.lock().put_poll_presence(record);
+        Ok(())
+    }
+
+    async fn list_poll_presence(&self, target_name: &str) -> Result<Vec<PollPresenceRecord>, DatabaseError> {
+        Ok(self.lock().list_poll_presence(target_name))
+    }
+
+    async fn delete_poll_presence(&self, expired_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        Ok(self.lock().delete_poll_presence(expired_before_epoch_millis))
+    }
+
+    async fn create_constraint_group(&self, group: ConstraintGroupRecord) -> Result<bool, DatabaseError> {
+        let mut tables = self.lock();
+        if tables.read_constraint_group(&group.name).is_some() {
+            return Ok(false);
+        }
+        tables.put_constraint_group(group);
+        Ok(true)
+    }
+
+    async fn read_constraint_group(&self, name: &str) -> Result<Option<ConstraintGroupRecord>, DatabaseError> {
+        Ok(self.lock().read_constraint_group(name))
+    }
+
+    async fn update_constraint_group(
+            &self,
+            name: &str,
+            op: &mut GroupOp<'_>) -> Result<bool, DatabaseError> {
+        Ok(self.lock().constraint_groups.get_mut(name).map(op).is_some())
+    }
+
+    async fn delete_constraint_group(&self, name: &str) -> Result<bool, DatabaseError> {
+        Ok(self.lock().delete_constraint_group(name))
+    }
+
+    async fn create_schedule(&self, schedule: ScheduleRecord) -> Result<bool, DatabaseError> {
+        let mut tables = self.lock();
+        if tables.read_schedule(&schedule.name).is_some() {
+            return Ok(false);
+        }
+        tables.put_schedule(schedule);
+        Ok(true)
+    }
+
+    async fn read_schedule(&self, name: &str) -> Result<Option<ScheduleRecord>, DatabaseError> {
+        Ok(self.lock().read_schedule(name))
+    }
+
+    async fn update_schedule(
+            &self,
+            name: &str,
+            op: &mut ScheduleOp<'_>) -> Result<bool, DatabaseError> {
+        Ok(self.lock().schedules.get_mut(name).map(op).is_some())
+    }
+
+    async fn delete_schedule(&self, name: &str) -> Result<bool, DatabaseError> {
+        Ok(self.lock().delete_schedule(name))
+    }
+
+    async fn list_schedules(&self) -> Result<Vec<ScheduleRecord>, DatabaseError> {
+        Ok(self.lock().list_schedules())
+    }
+}
+
+impl LocalTables {
+    pub(super) fn create_batch(&mut self, batch: BatchRecord) -> bool {
+        if self.batches.contains_key(&batch.id) {
+            return false;
+        }
+        self.target_batches.entry(batch.target_name.clone())
+            .or_default()
+            .push(batch.id.clone());
+        self.batches.insert(batch.id.clone(), batch);
+        true
+    }
+
+    pub(super) fn read_batch(&self, batch_id: &str) -> Option<BatchRecord> {
+        self.batches.get(batch_id).cloned()
+    }
+
+    // Inserts the batch if it does not exist, otherwise overwrites it.
+    pub(super) fn put_batch(&mut self, batch: BatchRecord) {
+        match self.batches.get_mut(&batch.id) {
+            Some(existing) => *existing = batch,
+            None => {
+                self.create_batch(batch);
+            }
+        }
+    }
+
+    pub(super) fn delete_batch(&mut self, batch_id: &str) -> bool {
+        let batch = match self.batches.remove(batch_id) {
+            Some(batch) => batch,
+            None => return false,
+        };
+        if let Some(ids) = self.target_batches.get_mut(&batch.target_name) {
+            ids.retain(|id| id != batch_id);
+            if ids.is_empty() {
+                self.target_batches.remove(&batch.target_name);
+            }
+        }
+        true
+    }
+
+    pub(super) fn list_active_batches(&self, target_name: &str) -> Vec<BatchRecord> {
+        self.target_batches.get(target_name)
+            .map(|ids| ids.iter()
+                .filter_map(|id| self.batches.get(id))
+                .filter(|batch| batch.state == BatchState::Active)
+                .cloned()
+                .collect())
+            .unwrap_or_default()
+    }
+
+    pub(super) fn list_outstanding_batches(&self) -> Vec<BatchRecord> {
+        self.batches.values()
+            .filter(|batch| batch.is_outstanding())
+            .cloned()
+            .collect()
+    }
+
+    pub(super) fn put_batch_tombstone(&mut self, record: BatchTombstoneRecord) {
+        self.batch_tombstones.insert(record.batch_id.clone(), record);
+    }
+
+    pub(super) fn read_batch_tombstone(&self, batch_id: &str) -> Option<BatchTombstoneRecord> {
+        self.batch_tombstones.get(batch_id).cloned()
+    }
+
+    pub(super) fn delete_batch_tombstone(&mut self, batch_id: &str) {
+        self.batch_tombstones.remove(batch_id);
+    }
+
+    pub(super) fn batch_tombstones_deleted_before(&self, deleted_before_epoch_millis: usize) -> Vec<String> {
+        self.batch_tombstones.values()
+            .filter(|record| record.deleted_epoch_millis < deleted_before_epoch_millis)
+            .map(|record| record.batch_id.clone())
+            .collect()
+    }
+
+    pub(super) fn read_idempotency_record(&self, key: &str) -> Option<IdempotencyRecord> {
+        self.idempotency.get(key).cloned()
+    }
+
+    pub(super) fn put_idempotency_record(&mut self, record: IdempotencyRecord) -> IdempotencyRecord {
+        self.idempotency.insert(record.key.clone(), record.clone());
+        record
+    }
+
+    pub(super) fn delete_idempotency_record(&mut self, key: &str) {
+        self.idempotency.remove(key);
+    }
+
+    pub(super) fn idempotency_keys_created_before(&self, created_before_epoch_millis: usize) -> Vec<String> {
+        self.idempotency.values()
+            .filter(|record| record.created_epoch_millis < created_before_epoch_millis)
+            .map(|record| record.key.clone())
+            .collect()
+    }
+
+    pub(super) fn read_connection(&self, target_name: &str, account_id: &str) -> Option<ConnectionRecord> {
+        self.connections.get(target_name)
+            .and_then(|accounts| accounts.get(account_id))
+            .cloned()
+    }
+
+    pub(super) fn put_connection(&mut self, record: ConnectionRecord) -> ConnectionRecord {
+        self.connections.entry(record.target_name.clone())
+            .or_default()
+            .insert(record.account_id.clone(), record.clone());
+        record
+    }
+
+    pub(super) fn put_poll_presence(&mut self, record: PollPresenceRecord) {
+        self.poll_presence.entry(record.target_name.clone())
+            .or_default()
+            .insert(record.node_address.clone(), record);
+    }
+
+    pub(super) fn list_poll_presence(&self, target_name: &str) -> Vec<PollPresenceRecord> {
+        self.poll_presence.get(target_name)
+            .map(|nodes| nodes.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub(super) fn delete_poll_presence(&mut self, expired_before_epoch_millis: usize) -> usize {
+        let mut deleted = 0;
+        self.poll_presence.retain(|_target_name, nodes| {
+            let before = nodes.len();
+            nodes.retain(|_node_address, record| record.expires_epoch_millis >= expired_before_epoch_millis);
+            deleted += before - nodes.len();
+            !nodes.is_empty()
+        });
+        deleted
+    }
+
+    pub(super) fn read_constraint_group(&self, name: &str) -> Option<ConstraintGroupRecord> {
+        self.constraint_groups.get(name).cloned()
+    }
+
+    // Inserts the group if it does not exist, otherwise overwrites it.
+    pub(super) fn put_constraint_group(&mut self, group: ConstraintGroupRecord) {
+        self.constraint_groups.insert(group.name.clone(), group);
+    }
+
+    pub(super) fn delete_constraint_group(&mut self, name: &str) -> bool {
+        self.constraint_groups.remove(name).is_some()
+    }
+
+    pub(super) fn read_schedule(&self, name: &str) -> Option<ScheduleRecord> {
+        self.schedules.get(name).cloned()
+    }
+
+    // Inserts the schedule if it does not exist, otherwise overwrites it.
+    pub(super) fn put_schedule(&mut self, schedule: ScheduleRecord) {
+        self.schedules.insert(schedule.name.clone(), schedule);
+    }
+
+    pub(super) fn delete_schedule(&mut self, name: &str) -> bool {
+        self.schedules.remove(name).is_some()
+    }
+
+    pub(super) fn list_schedules(&self) -> Vec<ScheduleRecord> {
+        self.schedules.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    storage_conformance_tests!(Some(LocalDatabase::new()));
+}
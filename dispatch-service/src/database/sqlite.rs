@@ -0,0 +1,808 @@
+// Storage in a single SQLite file, which can be inspected with the standard sqlite3 tools.
+//
+// Every read-modify-write happens inside an IMMEDIATE transaction, which takes the write
+// lock up front. That keeps the create-if-absent and update semantics intact even when
+// several processes share the same file.
+//
+// Command definitions live in their own table, since they never change after dispatch.
+// The rest of a batch's state (command and attempt progress) is kept as a JSON document
+// on the batch row.
+
+use super::{BatchOp, DatabaseError, GroupOp, ScheduleOp, Storage};
+use super::blocking::{self, Permit};
+use crate::records::{
+    BatchRecord,
+    BatchState,
+    BatchTombstoneRecord,
+    CommandDefinition,
+    ConnectionRecord,
+    ConstraintGroupRecord,
+    IdempotencyRecord,
+    PollPresenceRecord,
+    ScheduleRecord,
+};
+
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde_json::Value;
+use tokio::sync::Semaphore;
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+
+    CREATE TABLE IF NOT EXISTS batch_idempotency (
+        idempotency_key TEXT PRIMARY KEY,
+        request_hash TEXT NOT NULL,
+        batch_id TEXT NOT NULL,
+        created_epoch_millis INTEGER NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS batch_idempotency_by_created
+        ON batch_idempotency (created_epoch_millis);
+
+    CREATE TABLE IF NOT EXISTS command_batches (
+        batch_id TEXT PRIMARY KEY,
+        target_name TEXT NOT NULL,
+        -- Increases with every dispatch, used to list a target's batches in dispatch order.
+        dispatch_sequence INTEGER NOT NULL,
+        -- One of active, succeeded, failed or cancelled. Derived from state, kept for inspection.
+        status TEXT NOT NULL,
+        -- 1 once the batch is done or cancelled, until its completion notification is sent
+        -- or given up on.
+        notification_pending INTEGER NOT NULL DEFAULT 0,
+        state TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS command_definitions (
+        batch_id TEXT NOT NULL,
+        command_index INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        data TEXT NOT NULL,
+        max_retries INTEGER NOT NULL,
+        success_required INTEGER NOT NULL,
+        -- JSON encoded RetryPolicy.
+        retry_policy TEXT NOT NULL DEFAULT '{\"type\":\"immediate\"}',
+        -- NULL for no limit.
+        execution_timeout_millis INTEGER,
+        parallel_with_previous INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (batch_id, command_index)
+    );
+
+    CREATE TABLE IF NOT EXISTS batch_tombstones (
+        batch_id TEXT PRIMARY KEY,
+        deleted_epoch_millis INTEGER NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS batch_tombstones_by_deleted
+        ON batch_tombstones (deleted_epoch_millis);
+
+    CREATE TABLE IF NOT EXISTS connections (
+        target_name TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        created_epoch_millis INTEGER NOT NULL,
+        PRIMARY KEY (target_name, account_id)
+    );
+
+    CREATE TABLE IF NOT EXISTS poll_presence (
+        target_name TEXT NOT NULL,
+        node_address TEXT NOT NULL,
+        expires_epoch_millis INTEGER NOT NULL,
+        PRIMARY KEY (target_name, node_address)
+    );
+
+    CREATE TABLE IF NOT EXISTS constraint_groups (
+        name TEXT PRIMARY KEY,
+        -- The group's limit, members and holders as a JSON document.
+        record TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS schedules (
+        name TEXT PRIMARY KEY,
+        -- The schedule's target, cron expression, commands and last run as a JSON document.
+        record TEXT NOT NULL
+    );
+";
+
+// Created after migrate, since they cover columns it may have to add. Partial, so listings
+// skip finished batches without reading them.
+const INDEXES: &str = "
+    DROP INDEX IF EXISTS command_batches_by_target;
+
+    CREATE INDEX IF NOT EXISTS active_batches_by_target
+        ON command_batches (target_name, dispatch_sequence)
+        WHERE status = 'active';
+
+    CREATE INDEX IF NOT EXISTS outstanding_batches
+        ON command_batches (batch_id)
+        WHERE status = 'active' OR notification_pending = 1;
+";
+
+// Every call runs on the blocking thread pool, while holding the permit. Updates keep their
+// IMMEDIATE transaction open while the caller's modification runs back on the worker thread.
+pub struct SqliteDatabase {
+    conn: Arc<Mutex<Connection>>,
+    permit: Arc<Semaphore>,
+}
+
+impl SqliteDatabase {
+    pub(super) fn open(path: &Path) -> Result<Self, DatabaseError> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute_batch(SCHEMA)?;
+        migrate(&conn)?;
+        conn.execute_batch(INDEXES)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            permit: Arc::new(Semaphore::new(1)),
+        })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, DatabaseError>
+            where T: Send + 'static, F: FnOnce(&mut Connection) -> Result<T, DatabaseError> + Send + 'static {
+        let permit = Permit::acquire(&self.permit).await;
+        let conn = self.conn.clone();
+        blocking::run(move || {
+            let _permit = permit;
+            f(&mut *connection(&conn)?)
+        }).await
+    }
+
+    // Reads a record inside an IMMEDIATE transaction, applies op to it and writes it back.
+    // Returns false without calling op if read finds no record.
+    async fn update<R, ReadFn, WriteFn>(
+            &self,
+            read: ReadFn,
+            op: &mut dyn FnMut(&mut R),
+            write: WriteFn) -> Result<bool, DatabaseError>
+            where
+                R: Send + 'static,
+                ReadFn: FnOnce(&Connection) -> Result<Option<R>, DatabaseError> + Send + 'static,
+                WriteFn: FnOnce(&Connection, &R) -> Result<(), DatabaseError> + Send + 'static {
+        let permit = Permit::acquire(&self.permit).await;
+        let conn = self.conn.clone();
+        let (permit, record) = blocking::run(move || {
+            let conn = connection(&conn)?;
+            conn.execute_batch("BEGIN IMMEDIATE")?;
+            let record = read(&conn);
+            if !matches!(record, Ok(Some(_))) {
+                conn.execute_batch("ROLLBACK")?;
+            }
+            Ok((permit, record?))
+        }).await?;
+        let mut record = match record {
+            Some(record) => record,
+            None => return Ok(false),
+        };
+        op(&mut record);
+        let conn = self.conn.clone();
+        blocking::run(move || {
+            let _permit = permit;
+            let conn = blocking::lock(&conn);
+            let result = write(&conn, &record)
+                .and_then(|_| Ok(conn.execute_batch("COMMIT")?));
+            if result.is_err() && !conn.is_autocommit() {
+                // Otherwise rolled back by the next caller.
+                // TODO: warn log
+                let _ = conn.execute_batch("ROLLBACK");
+            }
+            result
+        }).await?;
+        Ok(true)
+    }
+}
+
+// Locks the connection, first rolling back the transaction of an update whose request was
+// dropped before it finished.
+fn connection(conn: &Mutex<Connection>) -> Result<MutexGuard<'_, Connection>, DatabaseError> {
+    let conn = blocking::lock(conn);
+    if !conn.is_autocommit() {
+        conn.execute_batch("ROLLBACK")?;
+    }
+    Ok(conn)
+}
+
+#[async_trait(?Send)]
+impl Storage for SqliteDatabase {
+    async fn create_batch(&self, batch: BatchRecord) -> Result<bool, DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let inserted = tx.execute(
+                "INSERT OR IGNORE INTO command_batches
+                    (batch_id, target_name, dispatch_sequence, status, notification_pending, state)
+                VALUES (?1, ?2,
+                    (SELECT COALESCE(MAX(dispatch_sequence), 0) + 1 FROM command_batches), ?3, ?4, ?5)",
+                params![batch.id, batch.target_name, status_column(batch.state), batch.is_notification_pending(),
+                    encode_state(&batch)?])?;
+            if inserted == 0 {
+                return Ok(false);
+            }
+            for (index, command) in batch.commands.iter().enumerate() {
+                let definition = &command.definition;
+                tx.execute(
+                    "INSERT OR IGNORE INTO command_definitions
+                        (batch_id, command_index, name, data, max_retries, success_required, retry_policy,
+                        execution_timeout_millis, parallel_with_previous)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        batch.id,
+                        index as i64,
+                        definition.name,
+                        definition.data,
+                        definition.max_retries as i64,
+                        definition.success_required,
+                        serde_json::to_string(&definition.retry_policy)?,
+                        definition.execution_timeout_millis.map(|millis| millis as i64),
+                        definition.parallel_with_previous,
+                    ])?;
+            }
+            tx.commit()?;
+            Ok(true)
+        }).await
+    }
+
+    async fn read_batch(&self, batch_id: &str) -> Result<Option<BatchRecord>, DatabaseError> {
+        let batch_id = batch_id.to_owned();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            read_batch(&tx, &batch_id)
+        }).await
+    }
+
+    async fn update_batch(
+            &self,
+            batch_id: &str,
+            op: &mut BatchOp<'_>) -> Result<bool, DatabaseError> {
+        let batch_id = batch_id.to_owned();
+        self.update(move |conn| read_batch(conn, &batch_id), op, |conn, batch: &BatchRecord| {
+            conn.execute(
+                "UPDATE command_batches SET status = ?2, notification_pending = ?3, state = ?4 WHERE batch_id = ?1",
+                params![batch.id, status_column(batch.state), batch.is_notification_pending(), encode_state(batch)?])?;
+            Ok(())
+        }).await
+    }
+
+    async fn delete_batch(&self, batch_id: &str) -> Result<bool, DatabaseError> {
+        let batch_id = batch_id.to_owned();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            tx.execute("DELETE FROM command_definitions WHERE batch_id = ?1", params![batch_id])?;
+            let deleted = tx.execute("DELETE FROM command_batches WHERE batch_id = ?1", params![batch_id])?;
+            tx.commit()?;
+            Ok(deleted > 0)
+        }).await
+    }
+
+    async fn list_active_batches(&self, target_name: &str) -> Result<Vec<BatchRecord>, DatabaseError> {
+        let target_name = target_name.to_owned();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let batch_ids = {
+                let mut statement = tx.prepare(
+                    "SELECT batch_id FROM command_batches
+                    WHERE target_name = ?1 AND status = 'active'
+                    ORDER BY dispatch_sequence")?;
+                let rows = statement.query_map(params![target_name], |row| row.get::<_, String>(0))?;
+                rows.collect::<Result<Vec<_>, _>>()?
+            };
+            let mut batches = Vec::with_capacity(batch_ids.len());
+            for batch_id in batch_ids {
+                batches.extend(read_batch(&tx, &batch_id)?);
+            }
+            Ok(batches)
+        }).await
+    }
+
+    async fn list_outstanding_batches(&self) -> Result<Vec<BatchRecord>, DatabaseError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            let batch_ids = {
+                let mut statement = tx.prepare(
+                    "SELECT batch_id FROM command_batches WHERE status = 'active' OR notification_pending = 1")?;
+                let rows = statement.query_map(params![], |row| row.get::<_, String>(0))?;
+                rows.collect::<Result<Vec<_>, _>>()?
+            };
+            let mut batches = Vec::with_capacity(batch_ids.len());
+            for batch_id in batch_ids {
+                batches.extend(read_batch(&tx, &batch_id)?);
+            }
+            Ok(batches)
+        }).await
+    }
+
+    async fn put_batch_tombstone(&self, record: BatchTombstoneRecord) -> Result<(), DatabaseError> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO batch_tombstones (batch_id, deleted_epoch_millis) VALUES (?1, ?2)",
+                params![record.batch_id, record.deleted_epoch_millis as i64])?;
+            Ok(())
+        }).await
+    }
+
+    async fn read_batch_tombstone(&self, batch_id: &str) -> Result<Option<BatchTombstoneRecord>, DatabaseError> {
+        let batch_id = batch_id.to_owned();
+        self.with_conn(move |conn| {
+            Ok(conn.query_row(
+                "SELECT deleted_epoch_millis FROM batch_tombstones WHERE batch_id = ?1",
+                params![batch_id],
+                |row| Ok(BatchTombstoneRecord {
+                    batch_id: batch_id.clone(),
+                    deleted_epoch_millis: row.get::<_, i64>(0)? as usize,
+                }))
+                .optional()?)
+        }).await
+    }
+
+    async fn delete_batch_tombstones(&self, deleted_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        self.with_conn(move |conn| {
+            Ok(conn.execute(
+                "DELETE FROM batch_tombstones WHERE deleted_epoch_millis < ?1",
+                params![deleted_before_epoch_millis as i64])?)
+        }).await
+    }
+
+    async fn create_idempotency_record(
+            &self,
+            record: IdempotencyRecord) -> Result<IdempotencyRecord, DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            tx.execute(
+                "INSERT OR IGNORE INTO batch_idempotency
+                    (idempotency_key, request_hash, batch_id, created_epoch_millis)
+                VALUES (?1, ?2, ?3, ?4)",
+                params![record.key, record.request_hash, record.batch_id, record.created_epoch_millis as i64])?;
+            let stored = tx.query_row(
+                "SELECT request_hash, batch_id, created_epoch_millis FROM batch_idempotency
+                WHERE idempotency_key = ?1",
+                params![record.key],
+                |row| Ok(IdempotencyRecord {
+                    key: record.key.clone(),
+                    request_hash: row.get(0)?,
+                    batch_id: row.get(1)?,
+                    created_epoch_millis: row.get::<_, i64>(2)? as usize,
+                }))?;
+            tx.commit()?;
+            Ok(stored)
+        }).await
+    }
+
+    async fn delete_idempotency_records(
+            &self,
+            created_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        self.with_conn(move |conn| {
+            Ok(conn.execute(
+                "DELETE FROM batch_idempotency WHERE created_epoch_millis < ?1",
+                params![created_before_epoch_millis as i64])?)
+        }).await
+    }
+
+    async fn create_connection(&self, record: ConnectionRecord) -> Result<ConnectionRecord, DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            tx.execute(
+                "INSERT OR IGNORE INTO connections (target_name, account_id, created_epoch_millis)
+                VALUES (?1, ?2, ?3)",
+                params![record.target_name, record.account_id, record.created_epoch_millis as i64])?;
+            let stored = read_connection(&tx, &record.target_name, &record.account_id)?
+                .ok_or_else(|| DatabaseError::Corrupt(format!(
+                    "Connection {} {} vanished inside its transaction", record.target_name, record.account_id)))?;
+            tx.commit()?;
+            Ok(stored)
+        }).await
+    }
+
+    async fn read_connection(
+            &self,
+            target_name: &str,
+            account_id: &str) -> Result<Option<ConnectionRecord>, DatabaseError> {
+        let (target_name, account_id) = (target_name.to_owned(), account_id.to_owned());
+        self.with_conn(move |conn| read_connection(conn, &target_name, &account_id)).await
+    }
+
+    async fn put_poll_presence(&self, record: PollPresenceRecord) -> Result<(), DatabaseError> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO poll_presence (target_name, node_address, expires_epoch_millis)
+                VALUES (?1, ?2, ?3)",
+                params![record.target_name, record.node_address, record.expires_epoch_millis as i64])?;
+            Ok(())
+        }).await
+    }
+
+    async fn list_poll_presence(&self, target_name: &str) -> Result<Vec<PollPresenceRecord>, DatabaseError> {
+        let target_name = target_name.to_owned();
+        self.with_conn(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT node_address, expires_epoch_millis FROM poll_presence WHERE target_name = ?1")?;
+            let records = statement.query_map(params![target_name], |row| Ok(PollPresenceRecord {
+                    target_name: target_name.clone(),
+                    node_address: row.get(0)?,
+                    expires_epoch_millis: row.get::<_, i64>(1)? as usize,
+                }))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(records)
+        }).await
+    }
+
+    async fn delete_poll_presence(&self, expired_before_epoch_millis: usize) -> Result<usize, DatabaseError> {
+        self.with_conn(move |conn| {
+            Ok(conn.execute(
+                "DELETE FROM poll_presence WHERE expires_epoch_millis < ?1",
+                params![expired_before_epoch_millis as i64])?)
+        }).await
+    }
+
+    async fn create_constraint_group(&self, group: ConstraintGroupRecord) -> Result<bool, DatabaseError> {
+        self.with_conn(move |conn| {
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO constraint_groups (name, record) VALUES (?1, ?2)",
+                params![group.name, serde_json::to_string(&group)?])?;
+            Ok(inserted > 0)
+        }).await
+    }
+
+    async fn read_constraint_group(&self, name: &str) -> Result<Option<ConstraintGroupRecord>, DatabaseError> {
+        let name = name.to_owned();
+        self.with_conn(move |conn| read_constraint_group(conn, &name)).await
+    }
+
+    async fn update_constraint_group(
+            &self,
+            name: &str,
+            op: &mut GroupOp<'_>) -> Result<bool, DatabaseError> {
+        let (name, read_name) = (name.to_owned(), name.to_owned());
+        self.update(move |conn| read_constraint_group(conn, &read_name), op, move |conn, group: &ConstraintGroupRecord| {
+            conn.execute(
+                "UPDATE constraint_groups SET record = ?2 WHERE name = ?1",
+                params![name, serde_json::to_string(group)?])?;
+            Ok(())
+        }).await
+    }
+
+    async fn delete_constraint_group(&self, name: &str) -> Result<bool, DatabaseError> {
+        let name = name.to_owned();
+        self.with_conn(move |conn| {
+            let deleted = conn.execute("DELETE FROM constraint_groups WHERE name = ?1", params![name])?;
+            Ok(deleted > 0)
+        }).await
+    }
+
+    async fn create_schedule(&self, schedule: ScheduleRecord) -> Result<bool, DatabaseError> {
+        self.with_conn(move |conn| {
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO schedules (name, record) VALUES (?1, ?2)",
+                params![schedule.name, serde_json::to_string(&schedule)?])?;
+            Ok(inserted > 0)
+        }).await
+    }
+
+    async fn read_schedule(&self, name: &str) -> Result<Option<ScheduleRecord>, DatabaseError> {
+        let name = name.to_owned();
+        self.with_conn(move |conn| read_schedule(conn, &name)).await
+    }
+
+    async fn update_schedule(
+            &self,
+            name: &str,
+            op: &mut ScheduleOp<'_>) -> Result<bool, DatabaseError> {
+        let (name, read_name) = (name.to_owned(), name.to_owned());
+        self.update(move |conn| read_schedule(conn, &read_name), op, move |conn, schedule: &ScheduleRecord| {
+            conn.execute(
+                "UPDATE schedules SET record = ?2 WHERE name = ?1",
+                params![name, serde_json::to_string(schedule)?])?;
+            Ok(())
+        }).await
+    }
+
+    async fn delete_schedule(&self, name: &str) -> Result<bool, DatabaseError> {
+        let name = name.to_owned();
+        self.with_conn(move |conn| {
+            let deleted = conn.execute("DELETE FROM schedules WHERE name = ?1", params![name])?;
+            Ok(deleted > 0)
+        }).await
+    }
+
+    async fn list_schedules(&self) -> Result<Vec<ScheduleRecord>, DatabaseError> {
+        self.with_conn(|conn| {
+            let mut statement = conn.prepare("SELECT record FROM schedules")?;
+            let records = statement.query_map(params![], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            records.iter()
+                .map(|record| Ok(serde_json::from_str(record)?))
+                .collect()
+        }).await
+    }
+}
+
+fn read_constraint_group(conn: &Connection, name: &str) -> Result<Option<ConstraintGroupRecord>, DatabaseError> {
+    let record = conn.query_row(
+        "SELECT record FROM constraint_groups WHERE name = ?1",
+        params![name],
+        |row| row.get::<_, String>(0))
+        .optional()?;
+    match record {
+        Some(record) => Ok(Some(serde_json::from_str(&record)?)),
+        None => Ok(None),
+    }
+}
+
+fn read_schedule(conn: &Connection, name: &str) -> Result<Option<ScheduleRecord>, DatabaseError> {
+    let record = conn.query_row(
+        "SELECT record FROM schedules WHERE name = ?1",
+        params![name],
+        |row| row.get::<_, String>(0))
+        .optional()?;
+    match record {
+        Some(record) => Ok(Some(serde_json::from_str(&record)?)),
+        None => Ok(None),
+    }
+}
+
+// Brings tables created by older versions up to date. CREATE TABLE IF NOT EXISTS leaves
+// existing tables alone, so columns added since have to be added here too.
+fn migrate(conn: &Connection) -> Result<(), DatabaseError> {
+    let idempotency_columns = {
+        let mut statement = conn.prepare("PRAGMA table_info(batch_idempotency)")?;
+        let rows = statement.query_map(params![], |row| row.get::<_, String>(1))?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+    if !idempotency_columns.iter().any(|column| column == "request_hash") {
+        // Records from before request hashes were kept match any request.
+        conn.execute_batch(
+            "ALTER TABLE batch_idempotency
+            ADD COLUMN request_hash TEXT NOT NULL DEFAULT ''")?;
+    }
+    let batch_columns = {
+        let mut statement = conn.prepare("PRAGMA table_info(command_batches)")?;
+        let rows = statement.query_map(params![], |row| row.get::<_, String>(1))?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+    if !batch_columns.iter().any(|column| column == "notification_pending") {
+        // Batches from before notifications were sent never asked for one.
+        conn.execute_batch(
+            "ALTER TABLE command_batches
+            ADD COLUMN notification_pending INTEGER NOT NULL DEFAULT 0")?;
+    }
+    let columns = {
+        let mut statement = conn.prepare("PRAGMA table_info(command_definitions)")?;
+        let rows = statement.query_map(params![], |row| row.get::<_, String>(1))?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+    if !columns.iter().any(|column| column == "retry_policy") {
+        conn.execute_batch(
+            "ALTER TABLE command_definitions
+            ADD COLUMN retry_policy TEXT NOT NULL DEFAULT '{\"type\":\"immediate\"}'")?;
+    }
+    if !columns.iter().any(|column| column == "execution_timeout_millis") {
+        conn.execute_batch(
+            "ALTER TABLE command_definitions
+            ADD COLUMN execution_timeout_millis INTEGER")?;
+    }
+    if !columns.iter().any(|column| column == "parallel_with_previous") {
+        conn.execute_batch(
+            "ALTER TABLE command_definitions
+            ADD COLUMN parallel_with_previous INTEGER NOT NULL DEFAULT 0")?;
+    }
+    Ok(())
+}
+
+fn read_batch(conn: &Connection, batch_id: &str) -> Result<Option<BatchRecord>, DatabaseError> {
+    let row = conn.query_row(
+        "SELECT target_name, state FROM command_batches WHERE batch_id = ?1",
+        params![batch_id],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+        .optional()?;
+    let (target_name, state) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let mut statement = conn.prepare(
+        "SELECT name, data, max_retries, success_required, retry_policy, execution_timeout_millis,
+            parallel_with_previous
+        FROM command_definitions
+        WHERE batch_id = ?1
+        ORDER BY command_index")?;
+    let rows = statement.query_map(params![batch_id], |row| Ok((
+            CommandDefinition {
+                name: row.get(0)?,
+                data: row.get(1)?,
+                max_retries: row.get::<_, i64>(2)? as usize,
+                success_required: row.get(3)?,
+                retry_policy: Default::default(),
+                execution_timeout_millis: row.get::<_, Option<i64>>(5)?.map(|millis| millis as usize),
+                parallel_with_previous: row.get(6)?,
+            },
+            row.get::<_, String>(4)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut definitions = Vec::with_capacity(rows.len());
+    for (mut definition, retry_policy) in rows {
+        definition.retry_policy = serde_json::from_str(&retry_policy)?;
+        definitions.push(definition);
+    }
+    decode_batch(batch_id, target_name, &state, definitions).map(Some)
+}
+
+fn read_connection(
+        conn: &Connection,
+        target_name: &str,
+        account_id: &str) -> Result<Option<ConnectionRecord>, DatabaseError> {
+    Ok(conn.query_row(
+        "SELECT created_epoch_millis FROM connections WHERE target_name = ?1 AND account_id = ?2",
+        params![target_name, account_id],
+        |row| Ok(ConnectionRecord {
+            target_name: target_name.to_owned(),
+            account_id: account_id.to_owned(),
+            created_epoch_millis: row.get::<_, i64>(0)? as usize,
+        }))
+        .optional()?)
+}
+
+fn status_column(state: BatchState) -> &'static str {
+    match state {
+        BatchState::Active => "active",
+        BatchState::Done { succeeded: true } => "succeeded",
+        BatchState::Done { succeeded: false } => "failed",
+        BatchState::Cancelled => "cancelled",
+    }
+}
+
+// Encodes everything about the batch except what is stored in other columns and tables.
+fn encode_state(batch: &BatchRecord) -> Result<String, DatabaseError> {
+    let mut value = serde_json::to_value(batch)?;
+    if let Some(object) = value.as_object_mut() {
+        object.remove("id");
+        object.remove("target_name");
+    }
+    if let Some(commands) = value.get_mut("commands").and_then(Value::as_array_mut) {
+        for command in commands.iter_mut().filter_map(Value::as_object_mut) {
+            command.remove("definition");
+        }
+    }
+    Ok(value.to_string())
+}
+
+fn decode_batch(
+        batch_id: &str,
+        target_name: String,
+        state: &str,
+        definitions: Vec<CommandDefinition>) -> Result<BatchRecord, DatabaseError> {
+    let mut value: Value = serde_json::from_str(state)?;
+    let object = value.as_object_mut()
+        .ok_or_else(|| DatabaseError::Corrupt(format!("State of batch {} is not an object", batch_id)))?;
+    object.insert("id".to_owned(), Value::from(batch_id));
+    object.insert("target_name".to_owned(), Value::from(target_name));
+    let commands = object.get_mut("commands")
+        .and_then(Value::as_array_mut)
+        .filter(|commands| commands.len() == definitions.len())
+        .ok_or_else(|| DatabaseError::Corrupt(format!(
+            "State of batch {} does not match its command definitions", batch_id)))?;
+    for (command, definition) in commands.iter_mut().zip(definitions) {
+        if let Some(command) = command.as_object_mut() {
+            command.insert("definition".to_owned(), serde_json::to_value(definition)?);
+        }
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{CompleteNotification, NotificationChannel, NotificationState};
+
+    use std::path::PathBuf;
+
+    use uuid::Uuid;
+
+    // A fresh database file, removed along with its WAL files when dropped.
+    struct TestPath(PathBuf);
+
+    impl TestPath {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("dispatch-test-{}.db", Uuid::new_v4().to_simple())))
+        }
+
+        fn open(&self) -> SqliteDatabase {
+            SqliteDatabase::open(&self.0).expect("Failed to open SqliteDatabase")
+        }
+    }
+
+    impl Drop for TestPath {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = self.0.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    // The TestPath is a temporary of the expression, so outlives the case using the database.
+    storage_conformance_tests!(Some(TestPath::new().open()));
+
+    #[test]
+    fn lists_batches_through_partial_indexes() {
+        let path = TestPath::new();
+        drop(path.open());
+        let conn = Connection::open(&path.0).unwrap();
+        let plan = |query: &str| conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", query)).unwrap()
+            .query_map(params![], |row| row.get::<_, String>(3)).unwrap()
+            .collect::<Result<Vec<_>, _>>().unwrap()
+            .join("\n");
+        let active = plan("SELECT batch_id FROM command_batches
+            WHERE target_name = 'target' AND status = 'active'
+            ORDER BY dispatch_sequence");
+        assert!(active.contains("active_batches_by_target"), "{}", active);
+        let outstanding = plan(
+            "SELECT batch_id FROM command_batches WHERE status = 'active' OR notification_pending = 1");
+        assert!(outstanding.contains("outstanding_batches"), "{}", outstanding);
+    }
+
+    #[tokio::test]
+    async fn waits_for_locks_off_the_worker_thread() {
+        let path = TestPath::new();
+        let database = path.open();
+        // Stands in for another process writing to the same file.
+        let other = Connection::open(&path.0).unwrap();
+        other.execute_batch("BEGIN IMMEDIATE").unwrap();
+
+        let record = BatchTombstoneRecord { batch_id: "batch".to_owned(), deleted_epoch_millis: 1000 };
+        // Were the write waiting out the busy timeout on this thread, the lock would only be
+        // released once it had given up.
+        let (written, ()) = futures::join!(database.put_batch_tombstone(record), async {
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            other.execute_batch("COMMIT").unwrap();
+        });
+        written.unwrap();
+        assert!(database.read_batch_tombstone("batch").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn migrates_idempotency_records_without_request_hashes() {
+        let path = TestPath::new();
+        Connection::open(&path.0).unwrap().execute_batch(
+            "CREATE TABLE batch_idempotency (
+                idempotency_key TEXT PRIMARY KEY,
+                batch_id TEXT NOT NULL,
+                created_epoch_millis INTEGER NOT NULL
+            );
+            INSERT INTO batch_idempotency VALUES ('key', 'batch', 1000);").unwrap();
+
+        let database = path.open();
+        let record = database.create_idempotency_record(IdempotencyRecord {
+            key: "key".to_owned(),
+            request_hash: "hash".to_owned(),
+            batch_id: "other".to_owned(),
+            created_epoch_millis: 2000,
+        }).await.unwrap();
+        assert_eq!((record.request_hash.as_str(), record.batch_id.as_str()), ("", "batch"));
+    }
+
+    #[tokio::test]
+    async fn migrates_batches_without_notification_columns() {
+        let path = TestPath::new();
+        Connection::open(&path.0).unwrap().execute_batch(
+            "CREATE TABLE command_batches (
+                batch_id TEXT PRIMARY KEY,
+                target_name TEXT NOT NULL,
+                dispatch_sequence INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                state TEXT NOT NULL
+            );").unwrap();
+
+        let database = path.open();
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), Vec::new(), 1000);
+        batch.complete_notification = Some(CompleteNotification {
+            channel: NotificationChannel::HTTP { endpoint: "http://localhost/done".to_owned(), additional_headers: None },
+            state: NotificationState::Pending { failures: 0 },
+        });
+        batch.cancel("operator", "bad rollout", 2000);
+        assert!(database.create_batch(batch).await.unwrap());
+        assert_eq!(database.list_outstanding_batches().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,119 @@
+// A batch may be dispatched with dependencies on other batches, eg so that a database
+// migration finishes before the app servers update. The batch waits, with its first command
+// handed out by neither ReceiveCommands nor StartCommand, until every dependency has
+// succeeded. If one fails, is cancelled or is deleted first, the waiting batch fails too.
+//
+// Dependencies are usually on other targets, whose batches finish on whichever host their
+// executor talks to, so nothing is told when a dependency finishes. Instead, the waiting
+// batch's dependencies are checked by whatever looks at it next: a poll for its target, a
+// StartCommand, or the reaper. Polls only read them, so that they never write. StartCommand
+// and the reaper record the outcome, including failing the batch.
+
+use crate::database::{Database, DatabaseError};
+use crate::records::{BatchRecord, BatchState};
+
+// Whether every dependency the batch is still waiting on has succeeded. Records nothing, so
+// a batch whose dependency failed stays active until settled.
+pub async fn satisfied(database: &Database, batch: &BatchRecord) -> Result<bool, DatabaseError> {
+    for dependency in &batch.dependencies {
+        match database.read_batch(dependency).await?.map(|dependency| dependency.state) {
+            Some(BatchState::Done { succeeded: true }) => {},
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+// Checks the dependencies of a waiting batch, recording any which have succeeded since, or
+// failing the batch if one of them failed. Returns the batch as it is afterwards, or None
+// if it was deleted in the meantime.
+pub async fn settle(database: &Database, batch: BatchRecord) -> Result<Option<BatchRecord>, DatabaseError> {
+    if batch.state != BatchState::Active || !batch.is_waiting() {
+        return Ok(Some(batch));
+    }
+    let mut succeeded = Vec::new();
+    let mut failed = None;
+    for dependency in &batch.dependencies {
+        match database.read_batch(dependency).await?.map(|dependency| dependency.state) {
+            Some(BatchState::Done { succeeded: true }) => succeeded.push(dependency.clone()),
+            Some(BatchState::Active) => {},
+            _ => {
+                failed = Some(dependency.clone());
+                break;
+            },
+        }
+    }
+    if succeeded.is_empty() && failed.is_none() {
+        return Ok(Some(batch));
+    }
+    database.update_batch(&batch.id, |batch| {
+        batch.settle_dependencies(&succeeded, failed.as_deref());
+        batch.clone()
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{BatchFailure, CommandDefinition, CommandState, RetryPolicy, StartOutcome};
+
+    fn batch(batch_id: &str, dependencies: &[&str]) -> BatchRecord {
+        let definition = CommandDefinition {
+            name: "deploy".to_owned(),
+            data: "v2".to_owned(),
+            max_retries: 0,
+            success_required: true,
+            retry_policy: RetryPolicy::Immediate,
+            execution_timeout_millis: None,
+            parallel_with_previous: false,
+        };
+        let mut batch = BatchRecord::new(batch_id.to_owned(), "target".to_owned(), vec![definition], 1000);
+        batch.dependencies = dependencies.iter().map(|dependency| dependency.to_string()).collect();
+        batch
+    }
+
+    async fn set_state(database: &Database, batch_id: &str, state: BatchState) {
+        database.update_batch(batch_id, |batch| batch.state = state).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn waits_until_every_dependency_succeeds() {
+        let database = Database::local();
+        for batch in [batch("migration", &[]), batch("cache", &[]), batch("app", &["migration", "cache"])] {
+            assert!(database.create_batch(batch).await.unwrap());
+        }
+        let app = database.read_batch("app").await.unwrap().unwrap();
+        assert!(settle(&database, app.clone()).await.unwrap().unwrap().is_waiting());
+
+        set_state(&database, "migration", BatchState::Done { succeeded: true }).await;
+        let app = settle(&database, app).await.unwrap().unwrap();
+        assert_eq!(app.dependencies, vec!["cache".to_owned()]);
+        // Deleting a dependency which already succeeded no longer matters.
+        database.delete_batch("migration").await.unwrap();
+        let mut app = database.update_batch("app", |batch| {
+            assert!(matches!(batch.start(0, "nonce", &[], |_| "token".to_owned(), 2000), StartOutcome::Waiting));
+            batch.clone()
+        }).await.unwrap().unwrap();
+
+        set_state(&database, "cache", BatchState::Done { succeeded: true }).await;
+        app = settle(&database, app).await.unwrap().unwrap();
+        assert!(!app.is_waiting());
+        assert_eq!(app.state, BatchState::Active);
+    }
+
+    #[tokio::test]
+    async fn fails_when_a_dependency_fails() {
+        let database = Database::local();
+        for batch in [batch("migration", &[]), batch("cache", &[]), batch("app", &["migration", "cache"])] {
+            assert!(database.create_batch(batch).await.unwrap());
+        }
+        set_state(&database, "migration", BatchState::Done { succeeded: true }).await;
+        set_state(&database, "cache", BatchState::Cancelled).await;
+        let app = database.read_batch("app").await.unwrap().unwrap();
+        let app = settle(&database, app).await.unwrap().unwrap();
+        assert_eq!(app.state, BatchState::Done { succeeded: false });
+        assert_eq!(app.failure, Some(BatchFailure::DependencyFailed { batch_id: "cache".to_owned() }));
+        assert_eq!(app.commands[0].state, CommandState::Skipped);
+        assert!(app.commands[0].attempts.is_empty());
+    }
+}
@@ -1,55 +1,120 @@
+use crate::database::DatabaseError;
+
 use hyper::{Body, Response};
+use serde_json::json;
 
-pub fn no_route() -> Response<Body> {
-    return Response::builder()
-        .status(404)
+fn error_response(status: u16, error: &str, message: &str) -> Response<Body> {
+    let body = json!({ "error": error, "message": message }).to_string();
+    Response::builder()
+        .status(status)
         .header("Content-Type", "application/json")
-        .body(Body::from(r#"{"error":"no_route","message":\
-            "No route found matching request path and method"}"#))
-        .expect("Failed to build no_route error response")
+        .body(Body::from(body))
+        .unwrap_or_else(|_| panic!("Failed to build {} error response", error))
+}
+
+pub fn no_route() -> Response<Body> {
+    error_response(404, "no_route",
+        "No route found matching request path and method")
 }
 
 pub fn body_too_large() -> Response<Body> {
-    return Response::builder()
-        .status(400)
-        .header("Content-Type", "application/json")
-        .body(Body::from(r#"{"error":"body_too_large","message":\
-            "Request body was larger than the maximum allowed"}"#))
-        .expect("Failed to build body_too_large error response")
+    error_response(400, "body_too_large",
+        "Request body was larger than the maximum allowed")
 }
 
 pub fn body_read_failed() -> Response<Body> {
-    return Response::builder()
-        .status(400)
-        .header("Content-Type", "application/json")
-        .body(Body::from(r#"{"error":"body_read_failed","message":\
-            "IO failure while reading request body"}"#))
-        .expect("Failed to build body_read_failed error response")
+    error_response(400, "body_read_failed",
+        "IO failure while reading request body")
 }
 
 pub fn req_json_parse() -> Response<Body> {
-    return Response::builder()
-        .status(400)
-        .header("Content-Type", "application/json")
-        .body(Body::from(r#"{"error":"req_json_parse","message":\
-            "Request body did not parse as valid JSON"}"#))
-        .expect("Failed to build req_json_parse error response")
+    error_response(400, "req_json_parse",
+        "Request body did not parse as valid JSON")
 }
 
 pub fn internal() -> Response<Body> {
-    return Response::builder()
-        .status(500)
-        .header("Content-Type", "application/json")
-        .body(Body::from(r#"{"error":"internal","message":\
-            "An unexpected internal error occurred within the service"}"#))
-        .expect("Failed to build internal error response")
+    error_response(500, "internal",
+        "An unexpected internal error occurred within the service")
 }
 
 pub fn no_content_length() -> Response<Body> {
-    return Response::builder()
-        .status(411)
-        .header("Content-Type", "application/json")
-        .body(Body::from(r#"{"error":"no_content_length","message":\
-            "The mandatory Content-Length header was not present"}"#))
-        .expect("Failed to build no_content_length error response")
-}
\ No newline at end of file
+    error_response(411, "no_content_length",
+        "The mandatory Content-Length header was not present")
+}
+
+pub fn batch_not_found() -> Response<Body> {
+    error_response(404, "batch_not_found",
+        "No command batch exists with the given batch id")
+}
+
+pub fn dependency_not_found() -> Response<Body> {
+    error_response(404, "dependency_not_found",
+        "No command batch exists with one of the given dependency batch ids")
+}
+
+pub fn command_not_found() -> Response<Body> {
+    error_response(404, "command_not_found",
+        "The command batch has no command at the given command index")
+}
+
+pub fn command_not_active() -> Response<Body> {
+    error_response(409, "command_not_active",
+        "The command at the given index is not one the batch is waiting on")
+}
+
+pub fn command_already_started() -> Response<Body> {
+    error_response(409, "command_already_started",
+        "The current attempt of the command was already started with a different nonce")
+}
+
+pub fn batch_already_done() -> Response<Body> {
+    error_response(409, "batch_already_done",
+        "The command batch finished before it could be cancelled")
+}
+
+pub fn batch_not_active() -> Response<Body> {
+    error_response(409, "batch_not_active",
+        "The command batch is already done or cancelled")
+}
+
+pub fn batch_paused() -> Response<Body> {
+    error_response(409, "batch_paused",
+        "The command batch is paused, so no command can be started until it is resumed")
+}
+
+pub fn invalid_attempt_token() -> Response<Body> {
+    error_response(403, "invalid_attempt_token",
+        "The attempt token was not issued by the dispatch service for the given batch")
+}
+
+pub fn idempotency_conflict() -> Response<Body> {
+    error_response(409, "idempotency_conflict",
+        "The nonce was already used by a request with different parameters")
+}
+
+pub fn group_not_found() -> Response<Body> {
+    error_response(404, "group_not_found",
+        "No constraint group exists with the given name")
+}
+
+pub fn invalid_group_limit() -> Response<Body> {
+    error_response(400, "invalid_group_limit",
+        "Constraint group count limits must be at least 1, and percentage limits between 0 and 100")
+}
+
+pub fn schedule_not_found() -> Response<Body> {
+    error_response(404, "schedule_not_found",
+        "No schedule exists with the given name")
+}
+
+pub fn invalid_cron() -> Response<Body> {
+    error_response(400, "invalid_cron",
+        "The cron expression must have five valid fields and match at least one time")
+}
+
+impl From<DatabaseError> for Response<Body> {
+    fn from(_err: DatabaseError) -> Self {
+        // TODO: error log
+        internal()
+    }
+}
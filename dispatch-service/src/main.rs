@@ -1,20 +1,37 @@
+mod aws;
+mod constraints;
+mod cron;
 mod database;
+mod dependencies;
 mod errors;
+mod metrics;
+mod notifications;
 mod operations;
+mod peers;
+mod polls;
+mod reaper;
 mod records;
+mod scheduler;
+mod sweeper;
+mod tokens;
 
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
+use crate::constraints::ConstraintGroups;
 use crate::database::Database;
-use crate::operations::Router;
+use crate::notifications::Notifier;
+use crate::peers::Peers;
+use crate::polls::PollRegistry;
+use crate::tokens::Keyring;
+use crate::operations::{Context, HEARTBEAT_INTERVAL_MILLIS, Router};
 
 use core_affinity::CoreId;
-use crossbeam::channel::{self, Sender, Receiver, TryRecvError, TrySendError};
-use hyper::{Body, Request, Response, Version};
+use crossbeam::channel::{self, Sender, Receiver, TryRecvError};
+use hyper::{Body, Request, Response};
 use hyper::server::conn::Http;
 use hyper::service::service_fn;
 use net2::TcpBuilder;
@@ -30,9 +47,14 @@ const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
 const ACCEPT_SOME_SPIN_FOR: Duration = Duration::from_millis(15);
 const ACCEPT_EMPTY_SPIN_FOR: Duration = Duration::from_secs(1);
 const ACCEPT_EMPTY_SPIN_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_PORT: u16 = 43316;
+const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_MAX_MISSED_HEARTBEATS: u32 = 3;
+const DEFAULT_PRIORITY_AGING: Duration = Duration::from_secs(10 * 60);
 
 struct AcceptedConn {
     stream: TcpStream,
+    #[allow(dead_code)] // TODO: logging
     remote_addr: SocketAddr
 }
 
@@ -43,47 +65,119 @@ fn main() {
     let max_conns = core_ids.len() * MAX_CONNS_PER_CORE;
     let max_conns_semaphore = Arc::new(Semaphore::new(max_conns));
 
+    let port = std::env::var("DISPATCH_PORT")
+        .map(|port| port.parse().expect("DISPATCH_PORT is not a valid port"))
+        .unwrap_or(DEFAULT_PORT);
+    // The IP address to listen on, eg 0.0.0.0 to accept connections from other hosts.
+    let bind_address = std::env::var("DISPATCH_BIND_ADDRESS")
+        .map(|address| address.parse().expect("DISPATCH_BIND_ADDRESS is not a valid IP address"))
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    // How other dispatch-service hosts sharing the database reach this one.
+    let node_address = std::env::var("DISPATCH_NODE_ADDRESS")
+        .unwrap_or_else(|_| {
+            let host = if bind_address.is_unspecified() { IpAddr::V4(Ipv4Addr::LOCALHOST) } else { bind_address };
+            SocketAddr::new(host, port).to_string()
+        });
+
     let accept_queue_max = core_ids.len() * MAX_ACCEPT_QUEUE_PER_CORE;
     let accept_queue_semaphore = Arc::new(Semaphore::new(accept_queue_max));
     let (accept_queue_tx, accept_queue_rx) = channel::bounded(accept_queue_max);
 
-    let database = Arc::new(Database::local());
+    // One of "local" for a purely in-memory database, "file:<data dir>" for durable
+    // single-node storage, "sqlite:<db file>", or "dynamo:<table name>".
+    let database_spec = std::env::var("DISPATCH_DATABASE")
+        .unwrap_or_else(|_| "local".to_owned());
+    let database = Arc::new(Database::open(&database_spec)
+        .expect("Failed to open database"));
+
+    // How long DispatchCommands nonces are remembered for, in seconds.
+    let idempotency_window = std::env::var("DISPATCH_IDEMPOTENCY_WINDOW_SECS")
+        .map(|secs| Duration::from_secs(secs.parse()
+            .expect("DISPATCH_IDEMPOTENCY_WINDOW_SECS is not a whole number of seconds")))
+        .unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW);
+    let sweeper_handle = sweeper::start_sweeper_thread(database.clone(), idempotency_window);
+
+    // How many heartbeats in a row an executor may miss before its attempt is failed.
+    let max_missed_heartbeats = std::env::var("DISPATCH_MAX_MISSED_HEARTBEATS")
+        .map(|missed| missed.parse::<u32>().ok()
+            .filter(|missed| *missed > 0)
+            .expect("DISPATCH_MAX_MISSED_HEARTBEATS is not a positive whole number"))
+        .unwrap_or(DEFAULT_MAX_MISSED_HEARTBEATS);
+    let heartbeat_timeout = Duration::from_millis(HEARTBEAT_INTERVAL_MILLIS as u64) * max_missed_heartbeats;
+    let reaper_handle = reaper::start_reaper_thread(database.clone(), Notifier::from_env(), heartbeat_timeout);
+
+    // Comma separated <key id>=<secret> pairs for signing attempt tokens, the first of which
+    // signs new tokens. Hosts sharing a database must share keys.
+    let keyring = match std::env::var("DISPATCH_ATTEMPT_TOKEN_KEYS") {
+        Ok(keys) => Keyring::parse(&keys).expect("DISPATCH_ATTEMPT_TOKEN_KEYS is invalid"),
+        // TODO: warn log that tokens will not survive a restart
+        Err(_) => Keyring::random(),
+    };
+
+    // How long batches dispatched without a deadline have before their unstarted commands
+    // expire, in seconds. Batches never expire by default.
+    let default_batch_deadline = std::env::var("DISPATCH_DEFAULT_BATCH_DEADLINE_SECS").ok()
+        .map(|secs| Duration::from_secs(secs.parse()
+            .expect("DISPATCH_DEFAULT_BATCH_DEADLINE_SECS is not a whole number of seconds")));
 
+    // How many seconds a batch waits to gain one level of priority, so that low priority
+    // batches are eventually handed out on busy targets.
+    let priority_aging = std::env::var("DISPATCH_PRIORITY_AGING_SECS")
+        .map(|secs| secs.parse::<u64>().ok()
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .expect("DISPATCH_PRIORITY_AGING_SECS is not a positive whole number of seconds"))
+        .unwrap_or(DEFAULT_PRIORITY_AGING);
+
+    let context = Arc::new(Context {
+        database,
+        polls: PollRegistry::default(),
+        peers: Peers::new(node_address),
+        constraints: ConstraintGroups::default(),
+        keyring,
+        default_batch_deadline,
+        priority_aging,
+    });
+
+    let scheduler_handle = scheduler::start_scheduler_thread(context.clone());
     let worker_handles = start_worker_threads(
         &core_ids,
         accept_queue_rx,
         accept_queue_semaphore.clone(),
-        database.clone());
+        context);
     let acceptor_handles = start_acceptor_threads(
         &core_ids,
+        SocketAddr::new(bind_address, port),
         accept_queue_tx,
         max_conns_semaphore,
-        accept_queue_semaphore,
-        database);
+        accept_queue_semaphore);
     for handle in worker_handles {
         handle.join().expect("Worker thread panicked");
     }
     for handle in acceptor_handles {
         handle.join().expect("Acceptor thread panicked");
     }
+    sweeper_handle.join().expect("Sweeper thread panicked");
+    reaper_handle.join().expect("Reaper thread panicked");
+    scheduler_handle.join().expect("Scheduler thread panicked");
 }
 
 fn start_worker_threads(
         core_ids: &[CoreId],
         accept_queue: Receiver<AcceptedConn>,
         accept_queue_semaphore: Arc<Semaphore>,
-        database: Arc<Database>) -> Vec<JoinHandle<()>> {
+        context: Arc<Context>) -> Vec<JoinHandle<()>> {
     core_ids.iter().cloned().enumerate().map(|(thread_index, core_id)| {
         let accept_queue = accept_queue.clone();
         let accept_queue_semaphore = accept_queue_semaphore.clone();
-        let database = database.clone();
+        let context = context.clone();
         let thread_name = format!("dispatch-worker-{}", thread_index);
         std::thread::Builder::new()
             .name(thread_name)
             .stack_size(10 * 1014 * 1024)
             .spawn(move || {
                 core_affinity::set_for_current(core_id);
-                worker_main(accept_queue, accept_queue_semaphore, database);
+                worker_main(accept_queue, accept_queue_semaphore, context);
             })
             .expect("Failed to spawn worker thread")
     }).collect()
@@ -91,10 +185,10 @@ fn start_worker_threads(
 
 fn start_acceptor_threads(
         core_ids: &[CoreId],
+        bind_address: SocketAddr,
         accept_queue: Sender<AcceptedConn>,
         max_conns_semaphore: Arc<Semaphore>,
-        accept_queue_semaphore: Arc<Semaphore>,
-        database: Arc<Database>) -> Vec<JoinHandle<()>> {
+        accept_queue_semaphore: Arc<Semaphore>) -> Vec<JoinHandle<()>> {
     core_ids.iter().cloned().enumerate().map(|(thread_index, core_id)| {
         let accept_queue = accept_queue.clone();
         let max_conns_semaphore = max_conns_semaphore.clone();
@@ -105,7 +199,7 @@ fn start_acceptor_threads(
             .stack_size(10 * 1024)
             .spawn(move || {
                 core_affinity::set_for_current(core_id);
-                acceptor_main(accept_queue, max_conns_semaphore, accept_queue_semaphore);
+                acceptor_main(bind_address, accept_queue, max_conns_semaphore, accept_queue_semaphore);
             })
             .expect("Failed to spawn acceptor thread")
     }).collect()
@@ -114,7 +208,7 @@ fn start_acceptor_threads(
 fn worker_main(
         accept_queue: Receiver<AcceptedConn>,
         accept_queue_semaphore: Arc<Semaphore>,
-        database: Arc<Database>) {
+        context: Arc<Context>) {
     println!("Hello world! I'm a worker thread.");
     let mut rt = tokio::runtime::Builder::new()
         .basic_scheduler()
@@ -133,12 +227,12 @@ fn worker_main(
                     // Allow more connections to be accept()ed.
                     accept_queue_semaphore.add_permits(1);
                     // Handle the request in a separate task.
-                    spawn_local(handle_conn(conn, router.clone(), database.clone()));
+                    spawn_local(handle_conn(conn, router.clone(), context.clone()));
                     prev_accept_some = Instant::now();
                     // If we get a bunch of new connections all at once, make sure to yield
                     // occasionally to allow response-generating futures to execute.
                     if prev_accept_some.saturating_duration_since(prev_accept_empty) > ACCEPT_SOME_SPIN_FOR {
-                        yield_now().await;
+                        let _ = yield_now().await;
                     }
                 },
                 Err(TryRecvError::Empty) => {
@@ -154,7 +248,7 @@ fn worker_main(
                         // and CPU usage.
                         delay_for(ACCEPT_EMPTY_SPIN_BACKOFF).await;
                     } else {
-                        yield_now().await;
+                        let _ = yield_now().await;
                     }
                 },
                 Err(TryRecvError::Disconnected) => {
@@ -170,22 +264,26 @@ fn worker_main(
 }
 
 fn acceptor_main(
+        bind_address: SocketAddr,
         accept_queue: Sender<AcceptedConn>,
-        max_conns_semaphore: Arc<Semaphore>,
+        _max_conns_semaphore: Arc<Semaphore>,
         accept_queue_semaphore: Arc<Semaphore>) {
     let mut rt = tokio::runtime::Builder::new()
         .basic_scheduler()
         .enable_all()
         .build()
         .expect("Failed to build tokio runtime on acceptor thread");
-    let local = tokio::task::LocalSet::new();
-    let listener = TcpBuilder::new_v4()
+    let builder = match bind_address {
+        SocketAddr::V4(_) => TcpBuilder::new_v4(),
+        SocketAddr::V6(_) => TcpBuilder::new_v6(),
+    };
+    let listener = builder
         .expect("Failed to create TcpBuilder")
         .reuse_address(true)
         .expect("Failed to set reuse_address(true)")
         .reuse_port(true)
         .expect("Failed to set reuse_port(true)")
-        .bind((Ipv4Addr::new(127, 0, 0, 1), 43316))
+        .bind(bind_address)
         .expect("Failed to bind socket")
         .listen(128)
         .expect("Failed to begin listening on socket");
@@ -223,22 +321,22 @@ fn acceptor_main(
     });
 }
 
-async fn handle_conn(conn: AcceptedConn, router: Rc<Router>, database: Arc<Database>) {
+async fn handle_conn(conn: AcceptedConn, router: Rc<Router>, context: Arc<Context>) {
     let service = service_fn(|req: Request<Body>| {
         // This function may be invoked multiple times for pipelined requests on the same
         // connection, so we need to clone things for each invocation.
         let router = router.clone();
-        let database = database.clone();
+        let context = context.clone();
         async move {
             // This error type never occurs. I wish that ! worked.
             Result::<_, Box<dyn std::error::Error + Send + Sync + 'static>>::Ok(
-                handle_request(req, router, database).await
+                handle_request(req, router, context).await
             )
         }
     });
     let mut http = Http::new().with_executor(LocalExec);
     http.http1_only(true);
-    if let Err(err) = http.serve_connection(conn.stream, service).await {
+    if let Err(_err) = http.serve_connection(conn.stream, service).await {
         // TODO: warn log
     }
 }
@@ -246,8 +344,8 @@ async fn handle_conn(conn: AcceptedConn, router: Rc<Router>, database: Arc<Datab
 async fn handle_request(
         req: Request<Body>,
         router: Rc<Router>,
-        database: Arc<Database>) -> Response<Body> {
-    router.route(req, database).await.unwrap_or_else(|| crate::errors::no_route())
+        context: Arc<Context>) -> Response<Body> {
+    router.route(req, context).await.unwrap_or_else(crate::errors::no_route)
 }
 
 // Copied from https://github.com/hyperium/hyper/blob/master/examples/single_threaded.rs
@@ -0,0 +1,76 @@
+// Service-wide counters, exported in the Prometheus text format by GET /metrics.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub static METRICS: Metrics = Metrics {
+    idempotency_records_collected: AtomicUsize::new(0),
+    attempts_timed_out: AtomicUsize::new(0),
+    batches_expired: AtomicUsize::new(0),
+    scheduled_runs_dispatched: AtomicUsize::new(0),
+    notifications_delivered: AtomicUsize::new(0),
+    notifications_failed: AtomicUsize::new(0),
+    notifications_abandoned: AtomicUsize::new(0),
+    poll_interrupts_failed: AtomicUsize::new(0),
+};
+
+pub struct Metrics {
+    // Expired idempotency records deleted by the sweeper.
+    pub idempotency_records_collected: AtomicUsize,
+    // Started attempts failed because their executor stopped heartbeating, or because they
+    // ran past their execution timeout. Counted by the reaper, or by the heartbeat which
+    // found the attempt overdue.
+    pub attempts_timed_out: AtomicUsize,
+    // Batches failed by the reaper because they passed their deadline.
+    pub batches_expired: AtomicUsize,
+    // Schedule runs dispatched by the scheduler. Every host racing to dispatch a run counts
+    // it, though only one batch is created.
+    pub scheduled_runs_dispatched: AtomicUsize,
+    // Batch completion notifications sent by the reaper.
+    pub notifications_delivered: AtomicUsize,
+    // Failed attempts at sending a completion notification, including the last attempt at
+    // an abandoned one.
+    pub notifications_failed: AtomicUsize,
+    // Completion notifications given up on after failing too many times.
+    pub notifications_abandoned: AtomicUsize,
+    // Peers whose polls could not be interrupted after a dispatch or resume, counting a
+    // failure to find the peers at all as one. Their polls still find the batch when they
+    // next check.
+    pub poll_interrupts_failed: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(&mut out, "dispatch_idempotency_records_collected_total",
+            "Expired idempotency records deleted by the sweeper.",
+            &self.idempotency_records_collected);
+        counter(&mut out, "dispatch_attempts_timed_out_total",
+            "Started attempts failed because their executor stopped heartbeating or they ran past \
+                their execution timeout.",
+            &self.attempts_timed_out);
+        counter(&mut out, "dispatch_batches_expired_total",
+            "Batches failed because they passed their deadline with commands left unstarted.",
+            &self.batches_expired);
+        counter(&mut out, "dispatch_scheduled_runs_dispatched_total",
+            "Schedule runs dispatched by this host's scheduler.",
+            &self.scheduled_runs_dispatched);
+        counter(&mut out, "dispatch_notifications_delivered_total",
+            "Batch completion notifications sent by this host's reaper.",
+            &self.notifications_delivered);
+        counter(&mut out, "dispatch_notifications_failed_total",
+            "Failed attempts at sending a batch completion notification.",
+            &self.notifications_failed);
+        counter(&mut out, "dispatch_notifications_abandoned_total",
+            "Batch completion notifications given up on after failing too many times.",
+            &self.notifications_abandoned);
+        counter(&mut out, "dispatch_poll_interrupts_failed_total",
+            "Peers whose polls could not be interrupted after a dispatch or resume.",
+            &self.poll_interrupts_failed);
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicUsize) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+        name, help, name, name, value.load(Ordering::Relaxed)));
+}
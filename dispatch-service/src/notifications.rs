@@ -0,0 +1,277 @@
+// Sends the notifications dispatchers ask for on the channels they name. Every notification
+// is a JSON document: POSTed as is to HTTP endpoints, and used as the message body for SQS
+// queues and SNS topics.
+
+use crate::aws::{self, AwsCredentials};
+use crate::operations::{now_epoch_millis, BatchStatus};
+use crate::records::{BatchRecord, NotificationChannel};
+
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use serde_json::json;
+use tokio::time::timeout;
+
+const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+#[allow(dead_code)] // TODO: logging
+pub enum NotificationError {
+    // The channel cannot be sent to, eg because its endpoint is invalid or no AWS
+    // credentials are configured.
+    Invalid(String),
+    Http(hyper::Error),
+    TimedOut,
+    // The receiving end answered with a status other than 2xx.
+    Rejected(u16),
+}
+
+impl From<hyper::Error> for NotificationError {
+    fn from(err: hyper::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+impl From<hyper::http::Error> for NotificationError {
+    fn from(err: hyper::http::Error) -> Self {
+        Self::Invalid(err.to_string())
+    }
+}
+
+pub struct Notifier {
+    client: Client<HttpsConnector<HttpConnector>>,
+    // None if AWS credentials are not configured, in which case only HTTP channels work.
+    credentials: Option<AwsCredentials>,
+    // Region of SQS queues whose URL does not name one.
+    region: String,
+    // Overrides the SNS endpoint for every region, eg to test against a stand-in.
+    sns_endpoint: Option<String>,
+}
+
+impl Notifier {
+    pub fn new(credentials: Option<AwsCredentials>, region: String, sns_endpoint: Option<String>) -> Self {
+        Self {
+            client: Client::builder().build(HttpsConnector::new()),
+            credentials,
+            region,
+            sns_endpoint,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            AwsCredentials::from_env().ok(),
+            aws::region_from_env(),
+            std::env::var("DISPATCH_SNS_ENDPOINT").ok())
+    }
+
+    // Sends the batch's completion notification, with its final status.
+    pub async fn notify_complete(&self, batch: &BatchRecord, channel: &NotificationChannel) -> Result<(), NotificationError> {
+        let message = json!({
+            "batch_id": batch.id,
+            "target_name": batch.target_name,
+            "batch": BatchStatus::from(batch),
+        });
+        self.send(channel, message.to_string()).await
+    }
+
+    async fn send(&self, channel: &NotificationChannel, message: String) -> Result<(), NotificationError> {
+        let request = match channel {
+            NotificationChannel::HTTP { endpoint, additional_headers } => {
+                let mut request = Request::post(endpoint.as_str())
+                    .header("content-type", "application/json");
+                for (name, value) in additional_headers.iter().flatten() {
+                    request = request.header(name.as_str(), value.as_str());
+                }
+                request.body(Body::from(message))?
+            },
+            NotificationChannel::SQS { queue_url } => {
+                let uri: Uri = queue_url.parse()
+                    .map_err(|_| NotificationError::Invalid(format!("Invalid queue URL {}", queue_url)))?;
+                let host = uri.host().unwrap_or_default();
+                // Queue URLs look like https://sqs.<region>.amazonaws.com/<account>/<name>.
+                let region = match host.split('.').collect::<Vec<_>>()[..] {
+                    ["sqs", region, ..] => region.to_owned(),
+                    _ => self.region.clone(),
+                };
+                let endpoint = format!("{}://{}/", uri.scheme_str().unwrap_or("https"), authority(&uri)?);
+                let body = json!({ "QueueUrl": queue_url, "MessageBody": message }).to_string();
+                self.aws_request(&endpoint, &region, "sqs", vec![
+                    ("content-type", "application/x-amz-json-1.0".to_owned()),
+                    ("x-amz-target", "AmazonSQS.SendMessage".to_owned()),
+                ], body)?
+            },
+            NotificationChannel::SNS { target_arn } => {
+                // arn:aws:sns:<region>:<account>:<topic>
+                let region = target_arn.split(':').nth(3)
+                    .filter(|region| !region.is_empty())
+                    .ok_or_else(|| NotificationError::Invalid(format!("Invalid SNS ARN {}", target_arn)))?;
+                let endpoint = self.sns_endpoint.clone()
+                    .unwrap_or_else(|| format!("https://sns.{}.amazonaws.com/", region));
+                let body = format!("Action=Publish&Version=2010-03-31&TargetArn={}&Message={}",
+                    form_encode(target_arn), form_encode(&message));
+                self.aws_request(&endpoint, region, "sns", vec![
+                    ("content-type", "application/x-www-form-urlencoded; charset=utf-8".to_owned()),
+                ], body)?
+            },
+        };
+        let response = timeout(NOTIFICATION_TIMEOUT, self.client.request(request)).await
+            .map_err(|_| NotificationError::TimedOut)??;
+        if !response.status().is_success() {
+            return Err(NotificationError::Rejected(response.status().as_u16()));
+        }
+        Ok(())
+    }
+
+    fn aws_request(
+            &self,
+            endpoint: &str,
+            region: &str,
+            service: &str,
+            mut headers: Vec<(&'static str, String)>,
+            body: String) -> Result<Request<Body>, NotificationError> {
+        let credentials = self.credentials.as_ref()
+            .ok_or_else(|| NotificationError::Invalid("AWS credentials are not configured".to_owned()))?;
+        let uri: Uri = endpoint.parse()
+            .map_err(|_| NotificationError::Invalid(format!("Invalid endpoint {}", endpoint)))?;
+        headers.push(("host", authority(&uri)?.to_owned()));
+        aws::sign(credentials, region, service, &mut headers, &body, now_epoch_millis() / 1000);
+        let mut request = Request::post(uri);
+        for (name, value) in &headers {
+            request = request.header(*name, value.as_str());
+        }
+        Ok(request.body(Body::from(body))?)
+    }
+}
+
+fn authority(uri: &Uri) -> Result<&str, NotificationError> {
+    uri.authority()
+        .map(|authority| authority.as_str())
+        .ok_or_else(|| NotificationError::Invalid(format!("{} has no host", uri)))
+}
+
+// Percent-encodes everything but unreserved characters, as SigV4 expects of form bodies.
+fn form_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// The SQS and SNS tests need stand-ins for both, such as moto in server mode, and are
+// skipped unless AWS_LOCAL_ENDPOINT is set, eg to http://localhost:5000.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::BatchState;
+
+    use serde_json::Value;
+    use uuid::Uuid;
+
+    fn local_notifier() -> Option<(String, Notifier)> {
+        let endpoint = std::env::var("AWS_LOCAL_ENDPOINT").ok()?;
+        let credentials = AwsCredentials {
+            access_key_id: "test".to_owned(),
+            secret_access_key: "test".to_owned(),
+            session_token: None,
+        };
+        let notifier = Notifier::new(Some(credentials), "us-west-2".to_owned(), Some(endpoint.clone()));
+        Some((endpoint, notifier))
+    }
+
+    async fn call_sqs(notifier: &Notifier, endpoint: &str, operation: &str, body: Value) -> Value {
+        let request = notifier.aws_request(endpoint, "us-west-2", "sqs", vec![
+            ("content-type", "application/x-amz-json-1.0".to_owned()),
+            ("x-amz-target", format!("AmazonSQS.{}", operation)),
+        ], body.to_string()).unwrap();
+        let response = notifier.client.request(request).await.unwrap();
+        assert!(response.status().is_success());
+        serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap()
+    }
+
+    async fn call_sns(notifier: &Notifier, endpoint: &str, params: &str) -> String {
+        let request = notifier.aws_request(endpoint, "us-west-2", "sns", vec![
+            ("content-type", "application/x-www-form-urlencoded; charset=utf-8".to_owned()),
+        ], format!("Version=2010-03-31&{}", params)).unwrap();
+        let response = notifier.client.request(request).await.unwrap();
+        assert!(response.status().is_success());
+        String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap()
+    }
+
+    async fn create_queue(notifier: &Notifier, endpoint: &str) -> (String, String) {
+        let name = format!("notifications-{}", Uuid::new_v4());
+        let created = call_sqs(notifier, endpoint, "CreateQueue", json!({ "QueueName": name })).await;
+        let queue_url = created["QueueUrl"].as_str().unwrap().to_owned();
+        let attributes = call_sqs(notifier, endpoint, "GetQueueAttributes", json!({
+            "QueueUrl": queue_url,
+            "AttributeNames": ["QueueArn"],
+        })).await;
+        (queue_url, attributes["Attributes"]["QueueArn"].as_str().unwrap().to_owned())
+    }
+
+    async fn receive_message(notifier: &Notifier, endpoint: &str, queue_url: &str) -> Value {
+        let received = call_sqs(notifier, endpoint, "ReceiveMessage", json!({
+            "QueueUrl": queue_url,
+            "WaitTimeSeconds": 1,
+        })).await;
+        let messages = received["Messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        serde_json::from_str(messages[0]["Body"].as_str().unwrap()).unwrap()
+    }
+
+    fn finished_batch() -> BatchRecord {
+        let mut batch = BatchRecord::new(Uuid::new_v4().to_string(), "target".to_owned(), Vec::new(), 1000);
+        batch.state = BatchState::Done { succeeded: true };
+        batch
+    }
+
+    #[tokio::test]
+    async fn notifies_sqs_queues() {
+        let (endpoint, notifier) = match local_notifier() {
+            Some(local) => local,
+            None => return,
+        };
+        let (queue_url, _queue_arn) = create_queue(&notifier, &endpoint).await;
+        let batch = finished_batch();
+        notifier.notify_complete(&batch, &NotificationChannel::SQS { queue_url: queue_url.clone() }).await.unwrap();
+
+        let message = receive_message(&notifier, &endpoint, &queue_url).await;
+        assert_eq!(message["batch_id"], batch.id.as_str());
+        assert_eq!(message["batch"]["status"], "done");
+        assert_eq!(message["batch"]["succeeded"], true);
+    }
+
+    #[tokio::test]
+    async fn notifies_sns_topics() {
+        let (endpoint, notifier) = match local_notifier() {
+            Some(local) => local,
+            None => return,
+        };
+        let created = call_sns(&notifier, &endpoint, &format!("Action=CreateTopic&Name=notifications-{}", Uuid::new_v4())).await;
+        let topic_arn = created.split("<TopicArn>").nth(1).and_then(|rest| rest.split("</TopicArn>").next()).unwrap().to_owned();
+        let (queue_url, queue_arn) = create_queue(&notifier, &endpoint).await;
+        call_sns(&notifier, &endpoint, &format!("Action=Subscribe&Protocol=sqs&TopicArn={}&Endpoint={}",
+            form_encode(&topic_arn), form_encode(&queue_arn))).await;
+        let batch = finished_batch();
+        notifier.notify_complete(&batch, &NotificationChannel::SNS { target_arn: topic_arn }).await.unwrap();
+
+        // SNS wraps the notification in an envelope of its own.
+        let envelope = receive_message(&notifier, &endpoint, &queue_url).await;
+        let message: Value = serde_json::from_str(envelope["Message"].as_str().unwrap()).unwrap();
+        assert_eq!(message["batch_id"], batch.id.as_str());
+        assert_eq!(message["batch"]["status"], "done");
+    }
+
+    #[test]
+    fn form_encodes_values() {
+        assert_eq!(form_encode("arn:aws:sns:us-west-2:1:done"), "arn%3Aaws%3Asns%3Aus-west-2%3A1%3Adone");
+        assert_eq!(form_encode("{\"a\": \"b c~\"}"), "%7B%22a%22%3A%20%22b%20c~%22%7D");
+        assert_eq!(form_encode("é"), "%C3%A9");
+    }
+}
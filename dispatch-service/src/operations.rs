@@ -1,12 +1,26 @@
+mod cancel_batch;
 mod complete_command;
 mod delete_commands;
+mod delete_constraint_group;
+mod delete_schedule;
 mod describe_command;
 mod describe_commands;
+mod describe_constraint_group;
+mod describe_schedule;
 mod dispatch_commands;
 mod heartbeat_command;
+mod interrupt_polls;
+mod metrics;
+mod pause_batch;
+mod put_constraint_group;
+mod put_schedule;
 mod receive_commands;
+mod resume_batch;
 mod start_command;
 
+// Also the status sent in batch completion notifications.
+pub use describe_commands::BatchStatus;
+
 use crate::errors::{
     body_read_failed,
     body_too_large,
@@ -14,14 +28,47 @@ use crate::errors::{
     internal,
     no_content_length,
 };
-use crate::database::Database;
+use crate::constraints::ConstraintGroups;
+use crate::database::{Database, DatabaseError};
+use crate::metrics::METRICS;
+use crate::peers::Peers;
+use crate::polls::PollRegistry;
+use crate::records::{BatchRecord, IdempotencyRecord};
+use crate::tokens::{AttemptClaims, Keyring};
 
 use std::future::Future;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use hyper::{Body, Method, Request, Response};
-use regex::{Captures, Regex, RegexSet};
+use regex::RegexSet;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// How often executors are asked to heartbeat a started command.
+pub const HEARTBEAT_INTERVAL_MILLIS: usize = 30_000;
+
+// Everything the operation handlers share, across every worker thread.
+pub struct Context {
+    pub database: Arc<Database>,
+    pub polls: PollRegistry,
+    pub peers: Peers,
+    pub constraints: ConstraintGroups,
+    // Signs and verifies attempt tokens.
+    pub keyring: Keyring,
+    // Deadline for batches dispatched without one, relative to when they are dispatched.
+    pub default_batch_deadline: Option<Duration>,
+    // How long a batch waits in ReceiveCommands to gain one level of priority.
+    pub priority_aging: Duration,
+}
+
+impl Context {
+    // Deadline for a batch dispatched now without one, if batches have a default deadline.
+    pub fn default_deadline_epoch_millis(&self, now_epoch_millis: usize) -> Option<usize> {
+        self.default_batch_deadline.map(|deadline| now_epoch_millis + deadline.as_millis() as usize)
+    }
+}
 
 #[derive(Clone)]
 pub struct Router {
@@ -34,19 +81,19 @@ impl Router {
         let path_set = RegexSet::new(Operation::all().iter()
             .map(|op| op.path_regex()))
             .expect("One of the operation regexes was invalid");
-        let all_operations = Operation::all().iter().cloned().collect();
+        let all_operations = Operation::all().to_vec();
         Self {
             path_set,
             all_operations
         }
     }
 
-    pub async fn route(&self, req: Request<Body>, database: Arc<Database>) -> Option<Response<Body>> {
+    pub async fn route(&self, req: Request<Body>, context: Arc<Context>) -> Option<Response<Body>> {
         // So few operations that doing anything more complicated is pointless.
         for op_index in self.path_set.matches(req.uri().path()) {
             if let Some(op) = self.all_operations.get(op_index) {
                 if req.method() == op.method() {
-                    return Some(op.invoke(req, database).await);
+                    return Some(op.invoke(req, context).await);
                 }
             }
         }
@@ -64,6 +111,17 @@ enum Operation {
     DescribeCommands,
     DescribeCommand,
     DeleteCommands,
+    CancelBatch,
+    PauseBatch,
+    ResumeBatch,
+    PutConstraintGroup,
+    DescribeConstraintGroup,
+    DeleteConstraintGroup,
+    PutSchedule,
+    DescribeSchedule,
+    DeleteSchedule,
+    Metrics,
+    InterruptPolls,
 }
 
 impl Operation {
@@ -76,51 +134,87 @@ impl Operation {
             Self::CompleteCommand,
             Self::DescribeCommands,
             Self::DescribeCommand,
-            Self::DeleteCommands
+            Self::DeleteCommands,
+            Self::CancelBatch,
+            Self::PauseBatch,
+            Self::ResumeBatch,
+            Self::PutConstraintGroup,
+            Self::DescribeConstraintGroup,
+            Self::DeleteConstraintGroup,
+            Self::PutSchedule,
+            Self::DescribeSchedule,
+            Self::DeleteSchedule,
+            Self::Metrics,
+            Self::InterruptPolls,
         ]
     }
 
     fn path_regex(&self) -> &'static str {
         match self {
-            Self::ReceiveCommands => "/api/dispatch/receive_commands",
-            Self::DispatchCommands => "/api/dispatch/dispatch_commands",
-            Self::StartCommand => "/api/dispatch/start_command",
-            Self::HeartbeatCommand => "/api/dispatch/heartbeat_command",
-            Self::CompleteCommand => "/api/dispatch/complete_command",
-            Self::DescribeCommands => "/api/dispatch/describe_commands",
-            Self::DescribeCommand => "/api/disptch/describe_command",
-            Self::DeleteCommands => "/api/dispatch/delete_commands",
+            Self::ReceiveCommands => "^/api/dispatch/receive_commands$",
+            Self::DispatchCommands => "^/api/dispatch/dispatch_commands$",
+            Self::StartCommand => "^/api/dispatch/start_command$",
+            Self::HeartbeatCommand => "^/api/dispatch/heartbeat_command$",
+            Self::CompleteCommand => "^/api/dispatch/complete_command$",
+            Self::DescribeCommands => "^/api/dispatch/describe_commands$",
+            Self::DescribeCommand => "^/api/dispatch/describe_command$",
+            Self::DeleteCommands => "^/api/dispatch/delete_commands$",
+            Self::CancelBatch => "^/api/dispatch/cancel_batch$",
+            Self::PauseBatch => "^/api/dispatch/pause_batch$",
+            Self::ResumeBatch => "^/api/dispatch/resume_batch$",
+            Self::PutConstraintGroup => "^/api/dispatch/put_constraint_group$",
+            Self::DescribeConstraintGroup => "^/api/dispatch/describe_constraint_group$",
+            Self::DeleteConstraintGroup => "^/api/dispatch/delete_constraint_group$",
+            Self::PutSchedule => "^/api/dispatch/put_schedule$",
+            Self::DescribeSchedule => "^/api/dispatch/describe_schedule$",
+            Self::DeleteSchedule => "^/api/dispatch/delete_schedule$",
+            Self::Metrics => "^/metrics$",
+            Self::InterruptPolls => "^/internal/dispatch/interrupt_polls$",
         }
     }
 
     fn method(&self) -> &'static Method {
-        &Method::POST
+        match self {
+            Self::Metrics => &Method::GET,
+            _ => &Method::POST,
+        }
     }
 
-    async fn invoke(&self, req: Request<Body>, database: Arc<Database>) -> Response<Body> {
+    async fn invoke(&self, req: Request<Body>, context: Arc<Context>) -> Response<Body> {
         match self {
-            Self::ReceiveCommands => receive_commands::handle(req).await,
-            Self::DispatchCommands => dispatch_commands::handle(req, database).await,
-            Self::StartCommand => start_command::handle(req).await,
-            Self::HeartbeatCommand => heartbeat_command::handle(req).await,
-            Self::CompleteCommand => complete_command::handle(req).await,
-            Self::DescribeCommands => describe_commands::handle(req).await,
-            Self::DescribeCommand => describe_command::handle(req).await,
-            Self::DeleteCommands => delete_commands::handle(req).await,
+            Self::ReceiveCommands => receive_commands::handle(req, context).await,
+            Self::DispatchCommands => dispatch_commands::handle(req, context).await,
+            Self::StartCommand => start_command::handle(req, context).await,
+            Self::HeartbeatCommand => heartbeat_command::handle(req, context).await,
+            Self::CompleteCommand => complete_command::handle(req, context).await,
+            Self::DescribeCommands => describe_commands::handle(req, context).await,
+            Self::DescribeCommand => describe_command::handle(req, context).await,
+            Self::DeleteCommands => delete_commands::handle(req, context).await,
+            Self::CancelBatch => cancel_batch::handle(req, context).await,
+            Self::PauseBatch => pause_batch::handle(req, context).await,
+            Self::ResumeBatch => resume_batch::handle(req, context).await,
+            Self::PutConstraintGroup => put_constraint_group::handle(req, context).await,
+            Self::DescribeConstraintGroup => describe_constraint_group::handle(req, context).await,
+            Self::DeleteConstraintGroup => delete_constraint_group::handle(req, context).await,
+            Self::PutSchedule => put_schedule::handle(req, context).await,
+            Self::DescribeSchedule => describe_schedule::handle(req, context).await,
+            Self::DeleteSchedule => delete_schedule::handle(req, context).await,
+            Self::Metrics => metrics::handle(req, context).await,
+            Self::InterruptPolls => interrupt_polls::handle(req, context).await,
         }
     }
 }
 
 pub async fn run_operation<Op, In, Out, Fut>(
         req: Request<Body>,
-        database: Arc<Database>,
+        context: Arc<Context>,
         max_body_size: usize,
         op: Op) -> Response<Body>
 where
-    Op: FnOnce(Request<In>, Arc<Database>) -> Fut,
+    Op: FnOnce(Request<In>, Arc<Context>) -> Fut,
     In: for<'a> Deserialize<'a>,
     Out: Serialize,
-    Fut: Future<Output = Response<Out>>
+    Fut: Future<Output = Result<Response<Out>, Response<Body>>>
 {
     let content_length = match req.headers().get("Content-Length")
             .and_then(|header| header.to_str().ok())
@@ -139,7 +233,7 @@ where
     // TODO: custom version of to_bytes which stops as soon as the max_body_size is exceeded.
     let bytes = match hyper::body::to_bytes(in_body).await {
         Ok(bytes) => bytes,
-        Err(err) => {
+        Err(_err) => {
             // TODO: debug log
             return body_read_failed();
         }
@@ -150,20 +244,142 @@ where
     }
     let input: In = match serde_json::from_slice(&bytes) {
         Ok(input) => input,
-        Err(err) => {
+        Err(_err) => {
             // TODO: debug log
             return req_json_parse();
         }
     };
     let req = Request::from_parts(parts, input);
-    let (parts, output) = op(req, database).await.into_parts();
+    let (parts, output) = match op(req, context).await {
+        Ok(response) => response.into_parts(),
+        Err(error_response) => return error_response,
+    };
     let out_bytes = match serde_json::to_vec(&output) {
         Ok(out_bytes) => out_bytes,
-        Err(err) => {
+        Err(_err) => {
             // TODO: warn log
             return internal();
         }
     };
     let out_body = Body::from(out_bytes);
-    return Response::from_parts(parts, out_body);
-}
\ No newline at end of file
+    Response::from_parts(parts, out_body)
+}
+
+// The claims of the attempt token, or None if it was not issued by this service for the
+// given batch.
+pub fn verify_attempt_token(context: &Context, batch_id: &str, attempt_token: &str) -> Option<AttemptClaims> {
+    context.keyring.verify(attempt_token)
+        .filter(|claims| claims.batch_id == batch_id)
+}
+
+// True if the batch was deleted, rather than never existing. Executors calling in about a
+// deleted batch are told to discard it.
+pub async fn was_deleted(context: &Context, batch_id: &str) -> Result<bool, DatabaseError> {
+    Ok(context.database.read_batch_tombstone(batch_id).await?.is_some())
+}
+
+pub enum Dispatch {
+    // The idempotency key's batch, whether or not this call created it.
+    Batch(String),
+    // The key was already used for a request with a different hash.
+    Conflict,
+    // make_batch declined to create the batch.
+    Declined,
+}
+
+// Creates the batch made by make_batch (given the new batch's id) unless the idempotency key
+// already has one, and lets polls for its target know. make_batch is only called if the
+// key's batch has not been created yet, and may decline by returning None.
+pub async fn dispatch_once<Fut>(
+        context: &Context,
+        key: String,
+        request_hash: String,
+        now_epoch_millis: usize,
+        make_batch: impl FnOnce(String) -> Fut) -> Result<Dispatch, DatabaseError>
+where
+    Fut: Future<Output = Result<Option<BatchRecord>, DatabaseError>>
+{
+    // The idempotency record is created first, so that a retry after a failure part way
+    // through finishes creating the same batch.
+    let record = context.database.create_idempotency_record(IdempotencyRecord {
+        key,
+        request_hash: request_hash.clone(),
+        batch_id: format!("{}", Uuid::new_v4().to_hyphenated()),
+        created_epoch_millis: now_epoch_millis,
+    }).await?;
+    // Records written before request hashes were kept have none to compare.
+    if !record.request_hash.is_empty() && record.request_hash != request_hash {
+        return Ok(Dispatch::Conflict);
+    }
+    // A retry of a dispatch whose batch was deleted since must not bring it back.
+    if was_deleted(context, &record.batch_id).await? {
+        return Ok(Dispatch::Batch(record.batch_id));
+    }
+    let target_name = match context.database.read_batch(&record.batch_id).await? {
+        Some(batch) => batch.target_name,
+        None => {
+            let batch = match make_batch(record.batch_id.clone()).await? {
+                Some(batch) => batch,
+                None => return Ok(Dispatch::Declined),
+            };
+            let target_name = batch.target_name.clone();
+            // If a concurrent retry created the batch in the meantime, it is left as is.
+            context.database.create_batch(batch).await?;
+            target_name
+        },
+    };
+    wake_polls(context, &target_name, &record.batch_id).await;
+    Ok(Dispatch::Batch(record.batch_id))
+}
+
+// Lets polls for the target know about the batch, on this host and its peers. Best effort,
+// since the batch is already stored: polls which are not told still find it when they next
+// check, at the latest once they time out.
+pub async fn wake_polls(context: &Context, target_name: &str, batch_id: &str) {
+    context.polls.wake(target_name, Some(batch_id));
+    if let Err(_err) = context.peers.interrupt_polls(&context.database, target_name, batch_id).await {
+        // TODO: warn log
+        METRICS.poll_interrupts_failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn now_epoch_millis() -> usize {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .expect("System clock is set before the unix epoch")
+        .as_millis() as usize
+}
+
+// Helpers for exercising operations the way clients call them.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::constraints::ConstraintGroups;
+
+    use serde_json::Value;
+
+    // A single host's context around fresh in-memory storage.
+    pub fn context() -> Context {
+        Context {
+            database: Arc::new(Database::local()),
+            polls: PollRegistry::default(),
+            peers: Peers::new("127.0.0.1:0".to_owned()),
+            constraints: ConstraintGroups::default(),
+            keyring: Keyring::random(),
+            default_batch_deadline: None,
+            priority_aging: Duration::from_secs(600),
+        }
+    }
+
+    // Posts the JSON body to the operation at path, returning the response status and body.
+    pub async fn call(context: &Arc<Context>, path: &str, body: Value) -> (u16, Value) {
+        let body = body.to_string();
+        let req = Request::post(path)
+            .header("Content-Length", body.len())
+            .body(Body::from(body))
+            .expect("Failed to build request");
+        let response = Router::new().route(req, context.clone()).await.expect("No route for path");
+        let status = response.status().as_u16();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.expect("Failed to read response");
+        (status, serde_json::from_slice(&bytes).expect("Response is not JSON"))
+    }
+}
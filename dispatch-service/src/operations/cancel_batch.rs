@@ -0,0 +1,48 @@
+use crate::constraints;
+use crate::errors::{batch_already_done, batch_not_found};
+use crate::operations::{Context, now_epoch_millis, run_operation};
+use crate::records::CancelOutcome;
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+// TODO: canceller permissions
+#[derive(Deserialize)]
+pub struct Input {
+    // Command batch id.
+    pub batch_id: String,
+    // Who is cancelling the batch, eg a user or the name of an automated system.
+    pub cancelled_by: String,
+    // Why the batch is being cancelled.
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 16 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let now = now_epoch_millis();
+        let outcome = context.database.update_batch(&input.batch_id, |batch| {
+            (batch.cancel(&input.cancelled_by, &input.reason, now), batch.target_name.clone())
+        }).await?;
+        match outcome {
+            None => Err(batch_not_found()),
+            Some((CancelOutcome::Cancelled { interrupted }, target_name)) => {
+                // The executors find out at their next heartbeat, but the slots are not theirs
+                // to give back any more.
+                for (command_index, groups) in interrupted {
+                    constraints::release(&context.database, &target_name, &groups, &input.batch_id, command_index).await?;
+                }
+                Ok(Response::new(Output {}))
+            },
+            // Cancelling is idempotent, but only the first cancellation is recorded.
+            Some((CancelOutcome::AlreadyCancelled, _)) => Ok(Response::new(Output {})),
+            Some((CancelOutcome::AlreadyDone, _)) => Err(batch_already_done()),
+        }
+    }).await
+}
@@ -1,4 +1,11 @@
-use hyper::{Body, Method, Request, Response};
+use crate::constraints;
+use crate::errors::{batch_not_found, invalid_attempt_token};
+use crate::operations::{Context, now_epoch_millis, run_operation, verify_attempt_token, was_deleted};
+use crate::records::CompleteOutcome;
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
@@ -16,15 +23,97 @@ pub struct Input {
 #[derive(Serialize)]
 #[serde(tag = "instruction")]
 pub enum Output {
-    // The client should discard the entire command batch.
+    // The client should discard the entire command batch. Given when the command failed
+    // for the last time and success_required was set, which fails the batch, or when the
+    // attempt timed out or a later attempt has been started since. Also given while the
+    // batch is paused, though the completion is still recorded, and once the batch is
+    // resumed ReceiveCommands hands out whatever comes next.
+    #[serde(rename = "discard")]
     Discard,
     // The client should proceed to the next command. If no more commands, then discard the
-    // command batch.
+    // command batch. Given when the command succeeded, or failed for the last time without
+    // success_required. If other commands in the same step are still running, the next step
+    // only starts once they are done.
+    #[serde(rename = "next_command")]
     NextCommand,
-    // The client should retry the same command again.
-    SameCommand,
+    // The client should retry the same command again, starting it after retry_after_millis.
+    #[serde(rename = "same_command")]
+    SameCommand {
+        retry_after_millis: usize
+    },
 }
 
-pub async fn handle(_req: Request<Body>) -> Response<Body> {
-    Response::new(Body::from("complete_command called"))
-}
\ No newline at end of file
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 64 * 1024, |req: Request<Input>, context| async move {
+        let Input { batch_id, attempt_token, success, data } = req.into_body();
+        verify_attempt_token(&context, &batch_id, &attempt_token)
+            .ok_or_else(invalid_attempt_token)?;
+        let now = now_epoch_millis();
+        let outcome = context.database.update_batch(&batch_id, |batch| {
+            let held = batch.held_constraint_groups(&attempt_token)
+                .map(|(command_index, groups)| (batch.target_name.clone(), command_index, groups));
+            (batch.complete(&attempt_token, success, data.clone(), now), held)
+        }).await?;
+        let outcome = match outcome {
+            Some((outcome, Some((target_name, command_index, groups)))) => {
+                constraints::release(&context.database, &target_name, &groups, &batch_id, command_index).await?;
+                Some(outcome)
+            }
+            outcome => outcome.map(|(outcome, _held)| outcome),
+        };
+        if outcome.is_none() && was_deleted(&context, &batch_id).await? {
+            return Ok(Response::new(Output::Discard));
+        }
+        match outcome {
+            None => Err(batch_not_found()),
+            Some(CompleteOutcome::Discard) => Ok(Response::new(Output::Discard)),
+            Some(CompleteOutcome::NextCommand) => Ok(Response::new(Output::NextCommand)),
+            Some(CompleteOutcome::SameCommand { available_epoch_millis }) => Ok(Response::new(Output::SameCommand {
+                retry_after_millis: available_epoch_millis.saturating_sub(now),
+            })),
+        }
+    }).await
+}
+#[cfg(test)]
+mod tests {
+    use crate::operations::testing::{call, context};
+
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn completing_while_paused_discards_the_batch_until_resumed() {
+        let context = Arc::new(context());
+        let (_, output) = call(&context, "/api/dispatch/dispatch_commands", json!({
+            "target_name": "target",
+            "nonce": "nonce",
+            "commands": [
+                { "name": "drain", "data": "", "max_retries": 0, "success_required": true },
+                { "name": "deploy", "data": "v2", "max_retries": 0, "success_required": true },
+            ],
+        })).await;
+        let batch_id = output["batch_id"].clone();
+        let (_, output) = call(&context, "/api/dispatch/start_command", json!({
+            "batch_id": batch_id, "command_index": 0, "nonce": "start", "group_membership": [],
+        })).await;
+        let attempt_token = output["attempt_token"].clone();
+        call(&context, "/api/dispatch/pause_batch", json!({
+            "batch_id": batch_id, "paused_by": "operator", "reason": "incident",
+        })).await;
+
+        let (status, output) = call(&context, "/api/dispatch/complete_command", json!({
+            "batch_id": batch_id, "attempt_token": attempt_token, "success": true,
+        })).await;
+        assert_eq!((status, output["instruction"].as_str()), (200, Some("discard")));
+        let receive = json!({
+            "target_name": "target", "exclude_batches": [], "group_membership": [], "timeout_millis": 0,
+        });
+        let (_, output) = call(&context, "/api/dispatch/receive_commands", receive.clone()).await;
+        assert_eq!(output["command_batches"], json!([]));
+
+        call(&context, "/api/dispatch/resume_batch", json!({ "batch_id": batch_id })).await;
+        let (_, output) = call(&context, "/api/dispatch/receive_commands", receive).await;
+        assert_eq!(output["command_batches"][0]["commands"][0]["name"], "deploy");
+    }
+}
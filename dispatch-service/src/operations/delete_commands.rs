@@ -1,11 +1,16 @@
-use std::collections::HashMap;
+use crate::constraints;
+use crate::operations::{Context, now_epoch_millis, run_operation};
+use crate::records::BatchTombstoneRecord;
 
-use hyper::{Body, Method, Request, Response};
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 pub struct Input {
-    // Command batch id.
+    // Command batch id. Deleting a batch which does not exist succeeds, so that deletes can
+    // be retried.
     pub batch_id: String
 }
 
@@ -13,6 +18,25 @@ pub struct Input {
 pub struct Output {
 }
 
-pub async fn handle(_req: Request<Body>) -> Response<Body> {
-    Response::new(Body::from("delete_commands called"))
-}
\ No newline at end of file
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let batch = match context.database.read_batch(&input.batch_id).await? {
+            Some(batch) => batch,
+            None => return Ok(Response::new(Output {})),
+        };
+        // The tombstone goes first, so that an executor still working on the batch is told to
+        // discard it however its next call lines up with the delete.
+        context.database.put_batch_tombstone(BatchTombstoneRecord {
+            batch_id: input.batch_id.clone(),
+            deleted_epoch_millis: now_epoch_millis(),
+        }).await?;
+        context.database.delete_batch(&input.batch_id).await?;
+        // Slots taken by a start racing with the delete are reclaimed later on, since their
+        // batch no longer exists.
+        for (command_index, groups) in batch.started_attempt_groups() {
+            constraints::release(&context.database, &batch.target_name, &groups, &input.batch_id, command_index).await?;
+        }
+        Ok(Response::new(Output {}))
+    }).await
+}
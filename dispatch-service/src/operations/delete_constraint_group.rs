@@ -0,0 +1,28 @@
+use crate::errors::group_not_found;
+use crate::operations::{Context, run_operation};
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct Input {
+    // Name of the constraint group. Clients which still list it in group_membership are no
+    // longer constrained by it.
+    pub group_name: String
+}
+
+#[derive(Serialize)]
+pub struct Output {
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        if !context.database.delete_constraint_group(&input.group_name).await? {
+            return Err(group_not_found());
+        }
+        Ok(Response::new(Output {}))
+    }).await
+}
@@ -0,0 +1,27 @@
+use crate::errors::schedule_not_found;
+use crate::operations::{Context, run_operation};
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct Input {
+    // Name of the schedule. Batches it already dispatched are left alone.
+    pub schedule_name: String
+}
+
+#[derive(Serialize)]
+pub struct Output {
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        if !context.database.delete_schedule(&input.schedule_name).await? {
+            return Err(schedule_not_found());
+        }
+        Ok(Response::new(Output {}))
+    }).await
+}
@@ -1,6 +1,10 @@
-use std::collections::HashMap;
+use crate::errors::{batch_not_found, command_not_found};
+use crate::operations::{Context, run_operation};
+use crate::records::{AttemptRecord, AttemptState};
 
-use hyper::{Body, Method, Request, Response};
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
@@ -19,18 +23,8 @@ pub struct Output {
     pub attempts: Vec<AttemptStatus>,
 }
 
-#[derive(Serialize)]
-#[serde(tag = "status")]
-pub enum CommandStatus {
-    #[serde(rename = "inactive")]
-    Inactive,
-    #[serde(rename = "active")]
-    Active,
-    #[serde(rename = "done")]
-    Done {
-        succeeded: bool
-    }
-}
+// Command status is reported identically by DescribeCommands.
+pub use super::describe_commands::CommandStatus;
 
 #[derive(Serialize)]
 #[serde(tag = "status")]
@@ -45,9 +39,18 @@ pub enum AttemptStatus {
         available_epoch_millis: usize,
         start_epoch_millis: usize,
     },
+    // The service failed the attempt, either because the executor stopped heartbeating or
+    // because the attempt ran past the command's execution_timeout_millis.
+    #[serde(rename = "timed_out")]
+    TimedOut {
+        heartbeats: usize,
+        available_epoch_millis: usize,
+        start_epoch_millis: usize,
+        timeout_epoch_millis: usize,
+    },
     #[serde(rename = "done")]
     Done {
-        data: String,
+        data: Option<String>,
         succeeded: bool,
         heartbeats: usize,
         available_epoch_millis: usize,
@@ -56,6 +59,53 @@ pub enum AttemptStatus {
     }
 }
 
-pub async fn handle(_req: Request<Body>) -> Response<Body> {
-    Response::new(Body::from("describe_command called"))
+impl From<&AttemptRecord> for AttemptStatus {
+    fn from(attempt: &AttemptRecord) -> Self {
+        let available_epoch_millis = attempt.available_epoch_millis;
+        match &attempt.state {
+            AttemptState::Available => Self::Available { available_epoch_millis },
+            AttemptState::Started { heartbeats, start_epoch_millis, .. } => Self::Started {
+                heartbeats: *heartbeats,
+                available_epoch_millis,
+                start_epoch_millis: *start_epoch_millis,
+            },
+            AttemptState::Done {
+                    heartbeats,
+                    start_epoch_millis,
+                    complete_epoch_millis,
+                    timed_out: true,
+                    .. } => Self::TimedOut {
+                heartbeats: *heartbeats,
+                available_epoch_millis,
+                start_epoch_millis: *start_epoch_millis,
+                timeout_epoch_millis: *complete_epoch_millis,
+            },
+            AttemptState::Done {
+                    succeeded,
+                    data,
+                    heartbeats,
+                    start_epoch_millis,
+                    complete_epoch_millis,
+                    .. } => Self::Done {
+                data: data.clone(),
+                succeeded: *succeeded,
+                heartbeats: *heartbeats,
+                available_epoch_millis,
+                start_epoch_millis: *start_epoch_millis,
+                complete_epoch_millis: *complete_epoch_millis,
+            },
+        }
+    }
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let batch = context.database.read_batch(&input.batch_id).await?.ok_or_else(batch_not_found)?;
+        let command = batch.commands.get(input.command_index).ok_or_else(command_not_found)?;
+        Ok(Response::new(Output {
+            command: command.state.into(),
+            attempts: command.attempts.iter().map(AttemptStatus::from).collect(),
+        }))
+    }).await
 }
\ No newline at end of file
@@ -1,6 +1,10 @@
-use std::collections::HashMap;
+use crate::errors::batch_not_found;
+use crate::operations::{Context, run_operation};
+use crate::records::{BatchFailure, BatchRecord, BatchState, CommandState, NotificationState, Pause};
 
-use hyper::{Body, Method, Request, Response};
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
@@ -15,6 +19,17 @@ pub struct Output {
     pub batch: BatchStatus,
     // A status for every command in the batch.
     pub commands: Vec<CommandStatus>,
+    // Every time the batch was paused, oldest first.
+    pub pauses: Vec<PauseStatus>,
+    // When the batch's first step became (or becomes) available, if it was dispatched with
+    // a not-before time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_before_epoch_millis: Option<usize>,
+    // Priority given at dispatch, not counting what the batch gained by waiting.
+    pub priority: i32,
+    // Whether the batch's completion notification has been sent, if one was asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub complete_notification: Option<NotificationStatus>,
 }
 
 #[derive(Serialize)]
@@ -22,10 +37,31 @@ pub struct Output {
 pub enum BatchStatus {
     #[serde(rename = "active")]
     Active,
+    // Active, but not starting its first command until these dependencies have succeeded.
+    #[serde(rename = "waiting")]
+    Waiting {
+        waiting_on: Vec<String>,
+    },
+    // Active, but not moving on to another attempt until resumed.
+    #[serde(rename = "paused")]
+    Paused {
+        paused_by: String,
+        reason: String,
+        paused_epoch_millis: usize,
+    },
     #[serde(rename = "done")]
     Done {
-        succeeded: bool
-    }
+        succeeded: bool,
+        // Why the batch failed, when it was not down to a command failing.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        failure: Option<FailureStatus>,
+    },
+    #[serde(rename = "cancelled")]
+    Cancelled {
+        cancelled_by: String,
+        reason: String,
+        cancelled_epoch_millis: usize,
+    },
 }
 
 #[derive(Serialize)]
@@ -38,9 +74,138 @@ pub enum CommandStatus {
     #[serde(rename = "done")]
     Done {
         succeeded: bool
+    },
+    // Never started (or never retried) because the batch passed its deadline first.
+    #[serde(rename = "expired")]
+    Expired,
+    // Never started because a dependency of the batch failed.
+    #[serde(rename = "skipped")]
+    Skipped,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "reason")]
+pub enum FailureStatus {
+    #[serde(rename = "expired")]
+    Expired,
+    // The dependency failed, was cancelled or was deleted before it succeeded.
+    #[serde(rename = "dependency_failed")]
+    DependencyFailed {
+        batch_id: String
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum NotificationStatus {
+    // Waiting for the batch to finish, or to be retried after failing to send.
+    #[serde(rename = "pending")]
+    Pending {
+        failures: usize
+    },
+    #[serde(rename = "delivered")]
+    Delivered {
+        delivered_epoch_millis: usize
+    },
+    // Given up on after failing too many times.
+    #[serde(rename = "abandoned")]
+    Abandoned,
+}
+
+#[derive(Serialize)]
+pub struct PauseStatus {
+    pub paused_by: String,
+    pub reason: String,
+    pub paused_epoch_millis: usize,
+    // Absent while the batch is still paused.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resumed_epoch_millis: Option<usize>,
+}
+
+impl From<&BatchRecord> for BatchStatus {
+    fn from(batch: &BatchRecord) -> Self {
+        match batch.state {
+            BatchState::Active => match batch.pauses.last() {
+                Some(pause) if batch.is_paused() => Self::Paused {
+                    paused_by: pause.paused_by.clone(),
+                    reason: pause.reason.clone(),
+                    paused_epoch_millis: pause.paused_epoch_millis,
+                },
+                _ if batch.is_waiting() => Self::Waiting { waiting_on: batch.dependencies.clone() },
+                _ => Self::Active,
+            },
+            BatchState::Done { succeeded } => Self::Done {
+                succeeded,
+                failure: batch.failure.as_ref().map(FailureStatus::from),
+            },
+            BatchState::Cancelled => {
+                let cancellation = batch.cancellation.clone().unwrap_or_default();
+                Self::Cancelled {
+                    cancelled_by: cancellation.cancelled_by,
+                    reason: cancellation.reason,
+                    cancelled_epoch_millis: cancellation.cancelled_epoch_millis,
+                }
+            },
+        }
+    }
+}
+
+impl From<&Pause> for PauseStatus {
+    fn from(pause: &Pause) -> Self {
+        Self {
+            paused_by: pause.paused_by.clone(),
+            reason: pause.reason.clone(),
+            paused_epoch_millis: pause.paused_epoch_millis,
+            resumed_epoch_millis: pause.resumed_epoch_millis,
+        }
+    }
+}
+
+impl From<&BatchFailure> for FailureStatus {
+    fn from(failure: &BatchFailure) -> Self {
+        match failure {
+            BatchFailure::Expired => Self::Expired,
+            BatchFailure::DependencyFailed { batch_id } => Self::DependencyFailed { batch_id: batch_id.clone() },
+        }
+    }
+}
+
+impl From<NotificationState> for NotificationStatus {
+    fn from(state: NotificationState) -> Self {
+        match state {
+            NotificationState::Pending { failures } => Self::Pending { failures },
+            NotificationState::Delivered { delivered_epoch_millis } => Self::Delivered { delivered_epoch_millis },
+            NotificationState::Abandoned => Self::Abandoned,
+        }
+    }
+}
+
+impl From<CommandState> for CommandStatus {
+    fn from(state: CommandState) -> Self {
+        match state {
+            CommandState::Inactive => Self::Inactive,
+            CommandState::Active => Self::Active,
+            CommandState::Done { succeeded } => Self::Done { succeeded },
+            CommandState::Expired => Self::Expired,
+            CommandState::Skipped => Self::Skipped,
+        }
     }
 }
 
-pub async fn handle(_req: Request<Body>) -> Response<Body> {
-    Response::new(Body::from("describe_commands called"))
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let batch = context.database.read_batch(&input.batch_id).await?.ok_or_else(batch_not_found)?;
+        Ok(Response::new(Output {
+            batch: (&batch).into(),
+            commands: batch.commands.iter()
+                .map(|command| command.state.into())
+                .collect(),
+            pauses: batch.pauses.iter().map(PauseStatus::from).collect(),
+            not_before_epoch_millis: batch.not_before_epoch_millis,
+            priority: batch.priority,
+            complete_notification: batch.complete_notification.as_ref()
+                .map(|notification| notification.state.into()),
+        }))
+    }).await
 }
\ No newline at end of file
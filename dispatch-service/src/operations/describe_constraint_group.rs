@@ -0,0 +1,57 @@
+use crate::errors::group_not_found;
+use crate::operations::{Context, run_operation};
+use crate::records::GroupLimit;
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct Input {
+    // Name of the constraint group.
+    pub group_name: String
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    pub limit: GroupLimit,
+    // How many members may be executing commands at once, given the current membership.
+    pub max_executing: usize,
+    // Targets which recently polled as members of the group, sorted by name.
+    pub members: Vec<String>,
+    // Commands holding an execution slot in the group.
+    pub executing: Vec<Executing>,
+}
+
+#[derive(Serialize)]
+pub struct Executing {
+    pub target_name: String,
+    pub batch_id: String,
+    pub command_index: usize,
+    pub acquired_epoch_millis: usize,
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let group = context.database.read_constraint_group(&input.group_name).await?.ok_or_else(group_not_found)?;
+        let mut members = group.members.keys().cloned().collect::<Vec<_>>();
+        members.sort();
+        let mut executing = group.holders.iter()
+            .flat_map(|(target_name, holders)| holders.iter().map(move |holder| Executing {
+                target_name: target_name.clone(),
+                batch_id: holder.batch_id.clone(),
+                command_index: holder.command_index,
+                acquired_epoch_millis: holder.acquired_epoch_millis,
+            }))
+            .collect::<Vec<_>>();
+        executing.sort_by_key(|executing| executing.acquired_epoch_millis);
+        Ok(Response::new(Output {
+            limit: group.limit,
+            max_executing: group.max_executing(),
+            members,
+            executing,
+        }))
+    }).await
+}
@@ -0,0 +1,52 @@
+use crate::cron::Cron;
+use crate::errors::schedule_not_found;
+use crate::operations::{Context, run_operation};
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct Input {
+    pub schedule_name: String
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    pub target_name: String,
+    pub cron: String,
+    // The commands of every run's batch, in order.
+    pub commands: Vec<Command>,
+    pub priority: i32,
+    // Runs due up to this time have been dispatched, or skipped because they were missed.
+    pub last_run_epoch_millis: usize,
+    // When the next run is due.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_run_epoch_millis: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct Command {
+    pub name: String,
+    pub data: String,
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let schedule = context.database.read_schedule(&input.schedule_name).await?.ok_or_else(schedule_not_found)?;
+        let next_run_epoch_millis = Cron::parse(&schedule.cron).ok()
+            .and_then(|cron| cron.next_after(schedule.last_run_epoch_millis));
+        Ok(Response::new(Output {
+            target_name: schedule.target_name,
+            cron: schedule.cron,
+            commands: schedule.commands.into_iter()
+                .map(|command| Command { name: command.name, data: command.data })
+                .collect(),
+            priority: schedule.priority,
+            last_run_epoch_millis: schedule.last_run_epoch_millis,
+            next_run_epoch_millis,
+        }))
+    }).await
+}
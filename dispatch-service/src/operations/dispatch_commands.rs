@@ -1,15 +1,22 @@
-use crate::database::Database;
-use crate::operations::run_operation;
+use crate::errors::{dependency_not_found, idempotency_conflict, internal};
+use crate::operations::{Context, Dispatch, dispatch_once, now_epoch_millis, run_operation, was_deleted};
+use crate::records::{
+    BatchRecord,
+    CommandDefinition,
+    CompleteNotification,
+    NotificationChannel,
+    NotificationState,
+    RetryPolicy,
+};
 
-use std::collections::HashMap;
 use std::sync::Arc;
 
-use hyper::{Body, Method, Request, Response};
+use hyper::{Body, Request, Response};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use sha2::{Digest, Sha256};
 
 // TODO: dispatcher permissions (or just infer from caller's auth mechanisms?).
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Input {
     // The target the commands are being dispatched against.
     pub target_name: String,
@@ -18,12 +25,31 @@ pub struct Input {
     // Randomly generated retry nonce. If the client retries, then each retry should have
     // the same nonce, to allow for idempotency.
     pub nonce: String,
-    // Channel on which notifications will be sent when the batch is complete.
-    pub batch_complete_notification: Option<Channel>,
+    // Once this passes, any command which has not started yet expires, and the batch fails.
+    // Defaults to the service's default batch deadline, if one is configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline_epoch_millis: Option<usize>,
+    // Ids of batches which must all succeed before the first command becomes available. If
+    // any of them fails, is cancelled or is deleted first, this batch fails too.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+    // The batch's first step stays hidden from ReceiveCommands until this passes, eg the
+    // start of a change window. StartCommand answers deferred until then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before_epoch_millis: Option<usize>,
+    // ReceiveCommands hands out higher priority batches first, eg 100 for a hotfix or -10 for
+    // background maintenance, and batches of equal priority in dispatch order. Batches gain
+    // priority as they wait, so low priority ones still run on a busy target. Defaults to 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    // Channel on which a notification is sent once the batch is done or cancelled, eg
+    // {"type": "aws_sqs", "queue_url": "..."}. The notification is sent at least once, by the
+    // reaper, with the batch's final status.
+    pub batch_complete_notification: Option<NotificationChannel>,
 }
 
 // TODO: executor permissions
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Command {
     // Command name.
     pub name: String,
@@ -31,56 +57,204 @@ pub struct Command {
     pub data: String,
     // Maximum retries for this command. The actual max attempts is max_retries + 1.
     pub max_retries: usize,
+    // How long to wait before each retry, eg {"type": "exponential", "initial_delay_millis":
+    // 1000, "max_delay_millis": 60000, "jitter": true}. Retries are immediate by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_policy: Option<RetryPolicy>,
+    // How long each attempt may run for. Once it passes, the attempt is timed out even if
+    // the executor is still heartbeating, and the command is retried or failed as usual.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub execution_timeout_millis: Option<usize>,
+    // If true, the command joins the step of the command before it, eg to warm several caches
+    // at once. The commands of a step may be executed concurrently, and the batch moves on
+    // to the next step once all of them are done.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub parallel_with_previous: bool,
     // Channel on which notifications will be sent when the command becomes available.
-    pub command_available_notification: Option<Channel>,
+    #[allow(dead_code)] // TODO: notifications
+    pub command_available_notification: Option<NotificationChannel>,
     // Channel on which notifications will be sent when the executor makes progress on a
     // command (starts an attempt, completes an attempt, etc).
-    pub command_progress_notification: Option<Channel>,
+    #[allow(dead_code)] // TODO: notifications
+    pub command_progress_notification: Option<NotificationChannel>,
     // If true, then if all retries are exhausted due to failure the batch will fail. If
     // false, then retries will still be used but if the retries are exhausted then the
     // batch will proceed to the next command.
     pub success_required: bool,
 }
 
-#[derive(Deserialize)]
-#[serde(tag = "type")]
-pub enum Channel {
-    #[serde(rename = "http")]
-    HTTP {
-        endpoint: String,
-        additional_headers: Option<HashMap<String, String>>,
-    },
-    #[serde(rename = "aws_sqs")]
-    SQS {
-        queue_url: String,
-    },
-    #[serde(rename = "aws_sns")]
-    SNS {
-        target_arn: String,
-    }
-}
-
 #[derive(Serialize)]
 pub struct Output {
     // The new command batch id.
     pub batch_id: String
 }
 
-pub async fn handle(req: Request<Body>, database: Arc<Database>) -> Response<Body> {
-    run_operation(req, database, 32 * 1024, |req: Request<Input>, database| async move {
-        let batch_id = format!("{}", Uuid::new_v4().to_hyphenated());
-
-        // TODO:
-        // - Generate batch UUID.
-        // - Create idempotency record.
-        //      - Record's key should be computed based on a hash of the api name and all parameters.
-        //      - If already exists, replace the generated batch UUID with the one from the existing idempotency record.
-        // - Create command definition records
-        //      - If already exists, do nothing
-        // - Create command batch record
-        //      - If already exists, do nothing (do not overwrite!)
-        // - Check for remote poll against input target_name. If found, interrupt.
-        // - Return batch id.
-        Response::new(Output { batch_id })
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 32 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let now = now_epoch_millis();
+        let request_hash = request_hash(&input).map_err(|_err| internal())?;
+        let key = format!("DispatchCommands#{}", input.nonce);
+        let context = &context;
+        let dispatch = dispatch_once(context, key, request_hash, now, |batch_id| async move {
+            // Dependencies deleted since they were dispatched still count, and fail the batch
+            // once it settles them.
+            for dependency in &input.depends_on {
+                if context.database.read_batch(dependency).await?.is_none()
+                        && !was_deleted(context, dependency).await? {
+                    return Ok(None);
+                }
+            }
+            let definitions = input.commands.into_iter().map(CommandDefinition::from).collect();
+            let mut batch = BatchRecord::new(batch_id, input.target_name, definitions, now);
+            batch.deadline_epoch_millis = input.deadline_epoch_millis
+                .or_else(|| context.default_deadline_epoch_millis(now));
+            batch.dependencies = input.depends_on;
+            batch.priority = input.priority.unwrap_or_default();
+            batch.complete_notification = input.batch_complete_notification
+                .map(|channel| CompleteNotification { channel, state: NotificationState::Pending { failures: 0 } });
+            if let Some(not_before) = input.not_before_epoch_millis {
+                batch.hold_until(not_before);
+            }
+            Ok(Some(batch))
+        }).await?;
+        match dispatch {
+            Dispatch::Batch(batch_id) => Ok(Response::new(Output { batch_id })),
+            Dispatch::Conflict => Err(idempotency_conflict()),
+            Dispatch::Declined => Err(dependency_not_found()),
+        }
     }).await
-}
\ No newline at end of file
+}
+
+impl From<Command> for CommandDefinition {
+    fn from(command: Command) -> Self {
+        CommandDefinition {
+            name: command.name,
+            data: command.data,
+            max_retries: command.max_retries,
+            success_required: command.success_required,
+            retry_policy: command.retry_policy.unwrap_or_default(),
+            execution_timeout_millis: command.execution_timeout_millis,
+            parallel_with_previous: command.parallel_with_previous,
+        }
+    }
+}
+
+// Hash of every parameter except the nonce. serde_json objects keep their keys sorted, so
+// equal requests always encode the same way.
+fn request_hash(input: &Input) -> Result<String, serde_json::Error> {
+    let mut value = serde_json::to_value(input)?;
+    if let Some(object) = value.as_object_mut() {
+        object.remove("nonce");
+    }
+    Ok(hex::encode(Sha256::digest(value.to_string().as_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::Database;
+    use crate::dependencies;
+    use crate::metrics::METRICS;
+    use crate::operations::Context;
+    use crate::operations::testing::{call, context};
+
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn dispatch(nonce: &str) -> serde_json::Value {
+        json!({
+            "target_name": "target",
+            "nonce": nonce,
+            "commands": [{ "name": "deploy", "data": "v2", "max_retries": 0, "success_required": true }],
+        })
+    }
+
+    fn dispatch_after(nonce: &str, dependency: &serde_json::Value) -> serde_json::Value {
+        let mut input = dispatch(nonce);
+        input["depends_on"] = json!([dependency]);
+        input
+    }
+
+    #[tokio::test]
+    async fn retries_do_not_bring_back_deleted_batches() {
+        let context = Arc::new(context());
+        let (status, output) = call(&context, "/api/dispatch/dispatch_commands", dispatch("nonce")).await;
+        assert_eq!(status, 200);
+        let batch_id = output["batch_id"].clone();
+        let (status, _) = call(&context, "/api/dispatch/delete_commands", json!({ "batch_id": batch_id })).await;
+        assert_eq!(status, 200);
+
+        let (status, output) = call(&context, "/api/dispatch/dispatch_commands", dispatch("nonce")).await;
+        assert_eq!(status, 200);
+        assert_eq!(output["batch_id"], batch_id);
+        assert!(context.database.read_batch(batch_id.as_str().unwrap()).await.unwrap().is_none());
+        let (status, output) = call(&context, "/api/dispatch/describe_commands", json!({ "batch_id": batch_id })).await;
+        assert_eq!((status, output["error"].as_str()), (404, Some("batch_not_found")));
+    }
+
+    #[tokio::test]
+    async fn dependencies_are_only_checked_for_new_batches() {
+        let context = Arc::new(context());
+        let (_, output) = call(&context, "/api/dispatch/dispatch_commands", dispatch("migration")).await;
+        let migration_id = output["batch_id"].clone();
+        let (status, output) = call(&context, "/api/dispatch/dispatch_commands", dispatch_after("app", &migration_id)).await;
+        assert_eq!(status, 200);
+        let app_id = output["batch_id"].clone();
+        call(&context, "/api/dispatch/delete_commands", json!({ "batch_id": migration_id })).await;
+
+        // A retry returns the original batch, however its dependencies have changed.
+        let (status, output) = call(&context, "/api/dispatch/dispatch_commands", dispatch_after("app", &migration_id)).await;
+        assert_eq!((status, &output["batch_id"]), (200, &app_id));
+        // A new batch depending on the deleted one is accepted, but fails.
+        let (status, output) = call(&context, "/api/dispatch/dispatch_commands", dispatch_after("web", &migration_id)).await;
+        assert_eq!(status, 200);
+        let web_id = output["batch_id"].clone();
+        let web = context.database.read_batch(web_id.as_str().unwrap()).await.unwrap().unwrap();
+        dependencies::settle(&context.database, web).await.unwrap();
+        let (_, output) = call(&context, "/api/dispatch/describe_commands", json!({ "batch_id": web_id })).await;
+        assert_eq!(output["batch"]["failure"]["reason"], "dependency_failed");
+        // Dependencies which never existed are rejected.
+        let (status, output) = call(&context, "/api/dispatch/dispatch_commands", dispatch_after("db", &json!("unknown"))).await;
+        assert_eq!((status, output["error"].as_str()), (404, Some("dependency_not_found")));
+    }
+
+    #[tokio::test]
+    async fn completion_notifications_wait_for_the_batch() {
+        let context = Arc::new(context());
+        let mut input = dispatch("nonce");
+        input["batch_complete_notification"] = json!({ "type": "aws_sqs", "queue_url": "https://sqs.us-west-2.amazonaws.com/1/done" });
+        let (status, output) = call(&context, "/api/dispatch/dispatch_commands", input).await;
+        assert_eq!(status, 200);
+        let (_, output) = call(&context, "/api/dispatch/describe_commands", json!({ "batch_id": output["batch_id"] })).await;
+        assert_eq!(output["complete_notification"], json!({ "status": "pending", "failures": 0 }));
+
+        let (_, output) = call(&context, "/api/dispatch/dispatch_commands", dispatch("other")).await;
+        let (_, output) = call(&context, "/api/dispatch/describe_commands", json!({ "batch_id": output["batch_id"] })).await;
+        assert!(output.get("complete_notification").is_none());
+    }
+
+    #[tokio::test]
+    async fn dispatches_even_when_polls_cannot_be_interrupted() {
+        let path = std::env::temp_dir().join(format!("dispatch-test-{}.db", Uuid::new_v4().to_simple()));
+        let context = Arc::new(Context {
+            database: Arc::new(Database::sqlite(&path).unwrap()),
+            ..context()
+        });
+        // Finding the peers to interrupt now fails.
+        rusqlite::Connection::open(&path).unwrap().execute_batch("DROP TABLE poll_presence").unwrap();
+        let failed = METRICS.poll_interrupts_failed.load(Ordering::Relaxed);
+
+        let (status, output) = call(&context, "/api/dispatch/dispatch_commands", dispatch("nonce")).await;
+        assert_eq!(status, 200);
+        assert!(context.database.read_batch(output["batch_id"].as_str().unwrap()).await.unwrap().is_some());
+        assert!(METRICS.poll_interrupts_failed.load(Ordering::Relaxed) > failed);
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
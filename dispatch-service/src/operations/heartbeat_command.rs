@@ -1,4 +1,13 @@
-use hyper::{Body, Method, Request, Response};
+use crate::constraints;
+use crate::errors::{batch_not_found, invalid_attempt_token};
+use crate::operations::{Context, now_epoch_millis, run_operation, verify_attempt_token, was_deleted};
+use crate::metrics::METRICS;
+use crate::records::HeartbeatOutcome;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
@@ -13,7 +22,9 @@ pub struct Input {
 #[serde(tag = "instruction")]
 pub enum Output {
     // The client should cancel the running command (if possible) and should discard the
-    // entire command batch.
+    // entire command batch. Given when the attempt is no longer current, including when it
+    // ran past its execution timeout, in which case the command may be retried as a new
+    // attempt.
     #[serde(rename = "discard")]
     Discard,
     // The client should continue with executing the command.
@@ -21,6 +32,72 @@ pub enum Output {
     Continue
 }
 
-pub async fn handle(_req: Request<Body>) -> Response<Body> {
-    Response::new(Body::from("heartbeat_command called"))
-}
\ No newline at end of file
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        verify_attempt_token(&context, &input.batch_id, &input.attempt_token)
+            .ok_or_else(invalid_attempt_token)?;
+        let now = now_epoch_millis();
+        let outcome = context.database.update_batch(&input.batch_id, |batch| {
+            let held = batch.held_constraint_groups(&input.attempt_token)
+                .map(|(command_index, groups)| (batch.target_name.clone(), command_index, groups));
+            let outcome = batch.heartbeat(&input.attempt_token, now);
+            // Still held unless the heartbeat timed the attempt out. Otherwise the groups were
+            // already released by whatever ended the attempt.
+            let released = held.filter(|_held| outcome == HeartbeatOutcome::TimedOut);
+            (outcome, released)
+        }).await?;
+        let outcome = match outcome {
+            Some((outcome, Some((target_name, command_index, groups)))) => {
+                constraints::release(&context.database, &target_name, &groups, &input.batch_id, command_index).await?;
+                METRICS.attempts_timed_out.fetch_add(1, Ordering::Relaxed);
+                Some(outcome)
+            }
+            outcome => outcome.map(|(outcome, _released)| outcome),
+        };
+        if outcome.is_none() && was_deleted(&context, &input.batch_id).await? {
+            return Ok(Response::new(Output::Discard));
+        }
+        match outcome {
+            None => Err(batch_not_found()),
+            Some(HeartbeatOutcome::Continue) => Ok(Response::new(Output::Continue)),
+            Some(HeartbeatOutcome::Discard | HeartbeatOutcome::TimedOut) => Ok(Response::new(Output::Discard)),
+        }
+    }).await
+}
+#[cfg(test)]
+mod tests {
+    use crate::metrics::METRICS;
+    use crate::operations::testing::{call, context};
+
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn heartbeats_after_cancelling_are_not_timeouts() {
+        let context = Arc::new(context());
+        let (_, output) = call(&context, "/api/dispatch/dispatch_commands", json!({
+            "target_name": "target",
+            "nonce": "nonce",
+            "commands": [{ "name": "deploy", "data": "v2", "max_retries": 0, "success_required": true }],
+        })).await;
+        let batch_id = output["batch_id"].clone();
+        let (_, output) = call(&context, "/api/dispatch/start_command", json!({
+            "batch_id": batch_id, "command_index": 0, "nonce": "start", "group_membership": [],
+        })).await;
+        let attempt_token = output["attempt_token"].clone();
+        let (status, _) = call(&context, "/api/dispatch/cancel_batch", json!({
+            "batch_id": batch_id, "cancelled_by": "operator", "reason": "bad release",
+        })).await;
+        assert_eq!(status, 200);
+
+        let timed_out = METRICS.attempts_timed_out.load(Ordering::Relaxed);
+        let (status, output) = call(&context, "/api/dispatch/heartbeat_command", json!({
+            "batch_id": batch_id, "attempt_token": attempt_token,
+        })).await;
+        assert_eq!((status, output["instruction"].as_str()), (200, Some("discard")));
+        assert_eq!(METRICS.attempts_timed_out.load(Ordering::Relaxed), timed_out);
+    }
+}
@@ -0,0 +1,33 @@
+use crate::operations::{Context, run_operation};
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+// Sent by other dispatch-service hosts after dispatching commands against a target which
+// this host has polls parked for. See peers.rs.
+// Not authenticated, so it must only be reachable from the network the hosts share. At
+// worst a caller can wake polls early, which then find nothing and poll again.
+// TODO: restrict to other hosts.
+#[derive(Deserialize)]
+pub struct Input {
+    pub target_name: String,
+    // The batch which was dispatched or resumed. Absent from hosts which predate it.
+    #[serde(default)]
+    pub batch_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    // How many parked polls were woken.
+    pub woken: usize,
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let woken = context.polls.wake(&input.target_name, input.batch_id.as_deref());
+        Ok(Response::new(Output { woken }))
+    }).await
+}
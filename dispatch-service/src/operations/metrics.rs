@@ -0,0 +1,14 @@
+use crate::errors::internal;
+use crate::metrics::METRICS;
+use crate::operations::Context;
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+
+pub async fn handle(_req: Request<Body>, _context: Arc<Context>) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(METRICS.render()))
+        .unwrap_or_else(|_err| internal())
+}
@@ -0,0 +1,37 @@
+use crate::errors::{batch_not_active, batch_not_found};
+use crate::operations::{Context, now_epoch_millis, run_operation};
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+// TODO: pauser permissions
+#[derive(Deserialize)]
+pub struct Input {
+    // Command batch id.
+    pub batch_id: String,
+    // Who is pausing the batch, eg a user or the name of an automated system.
+    pub paused_by: String,
+    // Why the batch is being paused.
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 16 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let now = now_epoch_millis();
+        let paused = context.database.update_batch(&input.batch_id, |batch| {
+            batch.pause(&input.paused_by, &input.reason, now)
+        }).await?;
+        match paused {
+            None => Err(batch_not_found()),
+            Some(true) => Ok(Response::new(Output {})),
+            Some(false) => Err(batch_not_active()),
+        }
+    }).await
+}
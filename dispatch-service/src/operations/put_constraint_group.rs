@@ -0,0 +1,72 @@
+use crate::errors::invalid_group_limit;
+use crate::operations::{Context, run_operation};
+use crate::records::{ConstraintGroupRecord, GroupLimit};
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct Input {
+    // Name of the constraint group, as given by clients in group_membership.
+    pub group_name: String,
+    // Either {"type": "count", "count": N} with N at least 1, or {"type": "percentage",
+    // "percentage": N} with N up to 100. Replaces the limit of an existing group, without
+    // affecting commands which are already executing.
+    pub limit: GroupLimit,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    // True if the group did not exist before.
+    pub created: bool,
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let Input { group_name, limit } = req.into_body();
+        // A count of 0 would never let a member execute, whereas a percentage always lets one.
+        let valid = match limit {
+            GroupLimit::Count { count } => count > 0,
+            GroupLimit::Percentage { percentage } => percentage <= 100,
+        };
+        if !valid {
+            return Err(invalid_group_limit());
+        }
+        loop {
+            if context.database.update_constraint_group(&group_name, |group| group.limit = limit).await?.is_some() {
+                return Ok(Response::new(Output { created: false }));
+            }
+            let group = ConstraintGroupRecord::new(group_name.clone(), limit);
+            if context.database.create_constraint_group(group).await? {
+                return Ok(Response::new(Output { created: true }));
+            }
+            // Created concurrently, so update that one instead.
+        }
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::operations::testing::{call, context};
+
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn rejects_limits_which_never_allow_execution() {
+        let context = Arc::new(context());
+        for limit in [json!({ "type": "count", "count": 0 }), json!({ "type": "percentage", "percentage": 101 })] {
+            let (status, output) = call(&context, "/api/dispatch/put_constraint_group", json!({
+                "group_name": "web", "limit": limit,
+            })).await;
+            assert_eq!((status, output["error"].as_str()), (400, Some("invalid_group_limit")));
+        }
+        let (status, output) = call(&context, "/api/dispatch/put_constraint_group", json!({
+            "group_name": "web", "limit": { "type": "percentage", "percentage": 0 },
+        })).await;
+        assert_eq!((status, output["created"].as_bool()), (200, Some(true)));
+    }
+}
@@ -0,0 +1,68 @@
+use super::dispatch_commands::Command;
+use crate::cron::Cron;
+use crate::errors::invalid_cron;
+use crate::operations::{Context, now_epoch_millis, run_operation};
+use crate::records::{CommandDefinition, ScheduleRecord};
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct Input {
+    pub schedule_name: String,
+    // The target every run's batch is dispatched against.
+    pub target_name: String,
+    // When to dispatch, as five cron fields evaluated in UTC, eg "0 3 * * *" for 03:00
+    // every night.
+    pub cron: String,
+    // The commands of every run's batch, as in DispatchCommands.
+    pub commands: Vec<Command>,
+    // Priority of every run's batch, as in DispatchCommands. Defaults to 0.
+    #[serde(default)]
+    pub priority: i32,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    // True if the schedule did not exist before.
+    pub created: bool,
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 32 * 1024, |req: Request<Input>, context| async move {
+        let Input { schedule_name, target_name, cron, commands, priority } = req.into_body();
+        let now = now_epoch_millis();
+        if Cron::parse(&cron).ok().and_then(|parsed| parsed.next_after(now)).is_none() {
+            return Err(invalid_cron());
+        }
+        let commands = commands.into_iter().map(CommandDefinition::from).collect::<Vec<_>>();
+        loop {
+            // Replacing an existing schedule keeps its last run, so a run already dispatched
+            // is not dispatched again.
+            let updated = context.database.update_schedule(&schedule_name, |schedule| {
+                schedule.target_name = target_name.clone();
+                schedule.cron = cron.clone();
+                schedule.commands = commands.clone();
+                schedule.priority = priority;
+            }).await?;
+            if updated.is_some() {
+                return Ok(Response::new(Output { created: false }));
+            }
+            // Only runs due after the schedule was created are dispatched.
+            let schedule = ScheduleRecord {
+                name: schedule_name.clone(),
+                target_name: target_name.clone(),
+                cron: cron.clone(),
+                commands: commands.clone(),
+                priority,
+                last_run_epoch_millis: now,
+            };
+            if context.database.create_schedule(schedule).await? {
+                return Ok(Response::new(Output { created: true }));
+            }
+            // Created concurrently, so update that one instead.
+        }
+    }).await
+}
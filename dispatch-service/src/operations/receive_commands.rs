@@ -1,5 +1,25 @@
-use hyper::{Body, Method, Request, Response};
+use crate::constraints;
+use crate::database::DatabaseError;
+use crate::dependencies;
+use crate::operations::{Context, HEARTBEAT_INTERVAL_MILLIS, now_epoch_millis, run_operation};
+use crate::records::BatchState;
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Request, Response};
 use serde::{Deserialize, Serialize};
+use tokio::time::timeout_at;
+
+// Upper bound on timeout_millis, so that polls are not cut off by proxies and load
+// balancers between the executor and the service.
+const MAX_POLL_TIMEOUT_MILLIS: usize = 60_000;
+// How often a poll withheld by a full constraint group checks whether a slot freed up, or
+// a poll with batches waiting on dependencies checks whether they are done. Neither wakes
+// polls, since they may be parked on any host.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(2);
 
 // TODO: permissions policy for which dispatchers the executor is willing to receive commands
 // from.
@@ -9,10 +29,19 @@ pub struct Input {
     pub target_name: String,
     // Command batch ids to not return (because the client already knows about them).
     pub exclude_batches: Vec<String>,
+    // Max number of batches to return, at least 1. Defaults to no limit. Batches are
+    // returned highest priority first, counting the priority they gained by waiting, and in
+    // dispatch order among equals.
+    #[serde(default)]
+    pub max_batches: Option<usize>,
     // When polling for commands, clients also specify which constraint groups they
-    // are a part of.
+    // are a part of. While any of them is too full for the client to start executing
+    // another command, only batches whose current command is already executing are
+    // returned.
     pub group_membership: Vec<String>,
-    // Max time that the client is willing to wait for the long poll to return.
+    // Max time that the client is willing to wait for the long poll to return. The poll
+    // returns as soon as there are batches for the target which are not excluded, or with
+    // no batches once the timeout expires.
     pub timeout_millis: usize
 }
 
@@ -26,8 +55,8 @@ pub struct Output {
 pub struct Batch {
     // Unique id of the command batch.
     pub id: String,
-    // The commands in the batch, in the order in which they should be serially
-    // executed. Order is enforced by the dispatch service in the per-command
+    // The commands in the batch which have not finished yet, in the order in which they
+    // should be executed. Order is enforced by the dispatch service in the per-command
     // operations.
     pub commands: Vec<Command>,
 }
@@ -44,11 +73,176 @@ pub struct Command {
     pub name: String,
     // Freeform command data.
     pub data: String,
+    // If true, the command is in the same step as the command before it, and may be executed
+    // at the same time as it. The next step only starts once every command in the step is
+    // done.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub parallel_with_previous: bool,
     // How often clients should heartbeat the command when it is executing but not
     // completed.
     pub heartbeat_interval_millis: usize
 }
 
-pub async fn handle(_req: Request<Body>) -> Response<Body> {
-    Response::new(Body::from("receive_commands called"))
-}
\ No newline at end of file
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 16 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let timeout = Duration::from_millis(input.timeout_millis.min(MAX_POLL_TIMEOUT_MILLIS) as u64);
+        let deadline = Instant::now() + timeout;
+        // Batches the poll was woken for, which are read directly rather than trusted to
+        // show up in the target's listing straight away.
+        let mut woken_for = Vec::new();
+        loop {
+            // Register before checking for work, so that a dispatch in between is not missed.
+            let mut waiter = context.polls.register(&input.target_name);
+            context.peers.announce_poll(&context.database, &input.target_name).await?;
+            let now = now_epoch_millis();
+            context.constraints.refresh_membership(
+                &context.database, &input.target_name, &input.group_membership, now).await?;
+            let withheld = constraints::is_withheld(
+                &context.database, &input.target_name, &input.group_membership, now).await?;
+            let (command_batches, recheck_epoch_millis) =
+                active_batches(&context, &input, &woken_for, withheld, now).await?;
+            if !command_batches.is_empty() {
+                return Ok(Response::new(Output { command_batches }));
+            }
+            // Either woken by a dispatch (or due to check whether a constraint group slot
+            // freed up, a dependency finished or a batch's not-before time passed), in which
+            // case check for work again, or timed out.
+            let mut wake_at = deadline;
+            if withheld {
+                wake_at = wake_at.min(Instant::now() + RECHECK_INTERVAL);
+            }
+            if let Some(recheck_epoch_millis) = recheck_epoch_millis {
+                let recheck_in = Duration::from_millis(recheck_epoch_millis.saturating_sub(now) as u64);
+                wake_at = wake_at.min(Instant::now() + recheck_in);
+            }
+            match timeout_at(wake_at.into(), waiter.wait()).await {
+                Ok(Some(batch_id)) => woken_for.push(batch_id),
+                Ok(None) => {},
+                Err(_) if wake_at == deadline => return Ok(Response::new(Output { command_batches })),
+                Err(_) => {},
+            }
+        }
+    }).await
+}
+
+// Active batches for the target which have not passed their deadline, along with when to
+// check again for batches which were left out because they are waiting on dependencies or
+// their not-before time has not passed yet. Paused batches, and every batch when withheld
+// by a constraint group, are only returned while a command of their current step is
+// already executing, since the client could not start any others.
+//
+// Listing a target's batches may lag behind writes (see the dynamo module), so the batches
+// in woken_for are read again with a strongly consistent read.
+async fn active_batches(
+        context: &Context,
+        input: &Input,
+        woken_for: &[String],
+        withheld: bool,
+        now: usize) -> Result<(Vec<Batch>, Option<usize>), DatabaseError> {
+    let exclude_batches = input.exclude_batches.iter().collect::<HashSet<_>>();
+    let mut active = Vec::new();
+    let mut recheck_epoch_millis = None::<usize>;
+    let mut recheck_at = |epoch_millis: usize| {
+        recheck_epoch_millis = Some(recheck_epoch_millis.map_or(epoch_millis, |recheck| recheck.min(epoch_millis)));
+    };
+    let mut batches = context.database.list_active_batches(&input.target_name).await?;
+    for batch_id in woken_for {
+        let listed = batches.iter().position(|batch| &batch.id == batch_id);
+        match (listed, context.database.read_batch(batch_id).await?) {
+            (Some(index), Some(batch)) => batches[index] = batch,
+            (Some(index), None) => {
+                batches.remove(index);
+            },
+            (None, Some(batch)) if batch.target_name == input.target_name => batches.push(batch),
+            (None, _) => {},
+        }
+    }
+    for batch in batches {
+        if batch.state != BatchState::Active || exclude_batches.contains(&batch.id) {
+            continue;
+        }
+        if !batch.is_due(now) {
+            recheck_at(batch.not_before_epoch_millis.unwrap_or(now));
+            continue;
+        }
+        // Batches whose dependency failed are left for StartCommand or the reaper to fail.
+        if batch.is_waiting() && !dependencies::satisfied(&context.database, &batch).await? {
+            recheck_at(now + RECHECK_INTERVAL.as_millis() as usize);
+            continue;
+        }
+        active.push(batch);
+    }
+    // Stable, so batches of equal priority stay in dispatch order.
+    let aging_millis = context.priority_aging.as_millis() as usize;
+    active.sort_by_key(|batch| Reverse(batch.effective_priority(aging_millis, now)));
+    let batches = active.into_iter()
+        .filter(|batch| !batch.is_expired(now))
+        .filter(|batch| (!withheld && !batch.is_paused()) || batch.is_step_executing())
+        .take(input.max_batches.map_or(usize::MAX, |max_batches| max_batches.max(1)))
+        .map(|batch| Batch {
+            commands: batch.remaining_commands()
+                .map(|(index, command)| Command {
+                    index,
+                    name: command.definition.name.clone(),
+                    data: command.definition.data.clone(),
+                    parallel_with_previous: command.definition.parallel_with_previous,
+                    heartbeat_interval_millis: HEARTBEAT_INTERVAL_MILLIS,
+                })
+                .collect(),
+            id: batch.id,
+        })
+        .collect();
+    Ok((batches, recheck_epoch_millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::operations::Context;
+    use crate::operations::testing::{call, context};
+    use crate::records::BatchState;
+
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+
+    async fn dispatch(context: &Arc<Context>, target_name: &str, depends_on: &[&str]) -> String {
+        let (status, output) = call(context, "/api/dispatch/dispatch_commands", json!({
+            "target_name": target_name,
+            "nonce": target_name,
+            "commands": [{ "name": "deploy", "data": "v2", "max_retries": 0, "success_required": true }],
+            "depends_on": depends_on,
+        })).await;
+        assert_eq!(status, 200);
+        output["batch_id"].as_str().unwrap().to_owned()
+    }
+
+    async fn receive(context: &Arc<Context>, target_name: &str) -> Value {
+        let (status, output) = call(context, "/api/dispatch/receive_commands", json!({
+            "target_name": target_name, "exclude_batches": [], "group_membership": [], "timeout_millis": 0,
+        })).await;
+        assert_eq!(status, 200);
+        output["command_batches"].clone()
+    }
+
+    #[tokio::test]
+    async fn polls_only_read_dependencies() {
+        let context = Arc::new(context());
+        let migration = dispatch(&context, "db", &[]).await;
+        let cache = dispatch(&context, "cache", &[]).await;
+        let app = dispatch(&context, "app", &[&migration]).await;
+        let web = dispatch(&context, "web", &[&cache]).await;
+        assert_eq!(receive(&context, "app").await, json!([]));
+
+        for (batch_id, state) in [(&migration, BatchState::Done { succeeded: true }), (&cache, BatchState::Cancelled)] {
+            context.database.update_batch(batch_id, |batch| batch.state = state).await.unwrap();
+        }
+        // Handed out as soon as its dependency succeeded, but left for StartCommand to record.
+        assert_eq!(receive(&context, "app").await[0]["id"], app.as_str());
+        assert!(context.database.read_batch(&app).await.unwrap().unwrap().is_waiting());
+        // Left out once its dependency failed, but left for the reaper to fail.
+        assert_eq!(receive(&context, "web").await, json!([]));
+        let web = context.database.read_batch(&web).await.unwrap().unwrap();
+        assert_eq!((web.state, web.is_waiting()), (BatchState::Active, true));
+    }
+}
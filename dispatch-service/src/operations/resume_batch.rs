@@ -0,0 +1,37 @@
+use crate::errors::{batch_not_active, batch_not_found};
+use crate::operations::{Context, now_epoch_millis, run_operation, wake_polls};
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+// TODO: pauser permissions
+#[derive(Deserialize)]
+pub struct Input {
+    // Command batch id.
+    pub batch_id: String,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+}
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let now = now_epoch_millis();
+        let resumed = context.database.update_batch(&input.batch_id, |batch| {
+            (batch.resume(now), batch.target_name.clone())
+        }).await?;
+        match resumed {
+            None => Err(batch_not_found()),
+            Some((true, target_name)) => {
+                // The batch's next command can be handed out again.
+                wake_polls(&context, &target_name, &input.batch_id).await;
+                Ok(Response::new(Output {}))
+            },
+            Some((false, _)) => Err(batch_not_active()),
+        }
+    }).await
+}
@@ -1,4 +1,13 @@
-use hyper::{Body, Method, Request, Response};
+use crate::constraints;
+use crate::dependencies;
+use crate::errors::{batch_not_found, batch_paused, command_already_started, command_not_active, command_not_found};
+use crate::operations::{Context, now_epoch_millis, run_operation, was_deleted};
+use crate::records::{BatchState, StartOutcome};
+use crate::tokens::AttemptClaims;
+
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
@@ -9,7 +18,11 @@ pub struct Input {
     pub command_index: usize,
     // Randomly generated retry nonce. If the client retries, then each retry should have
     // the same nonce, to allow for idempotency.
-    pub nonce: String
+    pub nonce: String,
+    // Constraint groups the client is a member of, the same as in ReceiveCommands. The
+    // command only starts if it can take an execution slot in every one of them.
+    #[serde(default)]
+    pub group_membership: Vec<String>,
 }
 
 #[derive(Serialize)]
@@ -24,9 +37,89 @@ pub enum Output {
     Continue {
         // Initial attempt token.
         attempt_token: String
-    }
+    },
+    // The command is a retry waiting out its retry delay, one of the client's constraint
+    // groups is full, or the batch is waiting on its dependencies. The client should not
+    // execute the command yet, and should call StartCommand again after the given time.
+    #[serde(rename = "deferred")]
+    Deferred {
+        retry_after_millis: usize
+    },
 }
 
-pub async fn handle(_req: Request<Body>) -> Response<Body> {
-    Response::new(Body::from("start_command called"))
+// How long clients deferred by a full constraint group or a dependency wait before trying
+// again.
+const DEFERRED_RETRY_MILLIS: usize = 5_000;
+
+pub async fn handle(req: Request<Body>, context: Arc<Context>) -> Response<Body> {
+    run_operation(req, context, 4 * 1024, |req: Request<Input>, context| async move {
+        let input = req.into_body();
+        let now = now_epoch_millis();
+        let batch = match context.database.read_batch(&input.batch_id).await? {
+            Some(batch) => dependencies::settle(&context.database, batch).await?,
+            None => None,
+        };
+        let batch = match batch {
+            Some(batch) => batch,
+            None if was_deleted(&context, &input.batch_id).await? => return Ok(Response::new(Output::Discard)),
+            None => return Err(batch_not_found()),
+        };
+        // Only take slots for a start which might succeed. A retried start of an attempt
+        // which already started holds its slots already.
+        let startable = batch.state == BatchState::Active
+            && !batch.is_paused()
+            && !batch.is_expired(now)
+            && !batch.is_waiting()
+            && batch.is_active(input.command_index)
+            && !batch.is_executing(input.command_index);
+        let waiting_until = batch.waiting_until(input.command_index, now);
+        if let Some(available_epoch_millis) = waiting_until.filter(|_| startable) {
+            return Ok(Response::new(Output::Deferred {
+                retry_after_millis: available_epoch_millis - now,
+            }));
+        }
+        let mut groups = Vec::new();
+        if startable && !input.group_membership.is_empty() {
+            let acquired = constraints::acquire(&context.database, &batch.target_name, &input.group_membership,
+                &input.batch_id, input.command_index, now).await?;
+            match acquired {
+                Some(acquired) => groups = acquired,
+                None => return Ok(Response::new(Output::Deferred { retry_after_millis: DEFERRED_RETRY_MILLIS })),
+            }
+        }
+        let outcome = context.database.update_batch(&input.batch_id, |batch| {
+            let mint_token = |attempt| context.keyring.issue(&AttemptClaims {
+                batch_id: input.batch_id.clone(),
+                command_index: input.command_index,
+                attempt,
+                issued_epoch_millis: now,
+            });
+            let outcome = batch.start(input.command_index, &input.nonce, &groups, mint_token, now);
+            (outcome, batch.is_executing(input.command_index))
+        }).await?;
+        // Give the slots back unless the command ended up executing, which it may also have
+        // done by a concurrent start with a different nonce.
+        if !matches!(outcome, Some((_, true))) {
+            constraints::release(&context.database, &batch.target_name, &groups,
+                &input.batch_id, input.command_index).await?;
+        }
+        if outcome.is_none() && was_deleted(&context, &input.batch_id).await? {
+            return Ok(Response::new(Output::Discard));
+        }
+        match outcome.map(|(outcome, _executing)| outcome) {
+            None => Err(batch_not_found()),
+            Some(StartOutcome::Started { token }) =>
+                Ok(Response::new(Output::Continue { attempt_token: token })),
+            Some(StartOutcome::NotYetAvailable { available_epoch_millis }) => Ok(Response::new(Output::Deferred {
+                retry_after_millis: available_epoch_millis.saturating_sub(now),
+            })),
+            Some(StartOutcome::Discard) => Ok(Response::new(Output::Discard)),
+            Some(StartOutcome::Waiting) =>
+                Ok(Response::new(Output::Deferred { retry_after_millis: DEFERRED_RETRY_MILLIS })),
+            Some(StartOutcome::Paused) => Err(batch_paused()),
+            Some(StartOutcome::NoSuchCommand) => Err(command_not_found()),
+            Some(StartOutcome::NotActive) => Err(command_not_active()),
+            Some(StartOutcome::AlreadyStarted) => Err(command_already_started()),
+        }
+    }).await
 }
\ No newline at end of file
@@ -0,0 +1,176 @@
+// Coordination between dispatch-service hosts sharing a database. A host with polls
+// parked for a target records its presence in the database, and a dispatch handled by any
+// other host interrupts those polls with a request to the polling host's
+// /internal/dispatch/interrupt_polls endpoint.
+
+use crate::database::{Database, DatabaseError};
+use crate::metrics::METRICS;
+use crate::operations::now_epoch_millis;
+use crate::records::PollPresenceRecord;
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use serde_json::json;
+use tokio::task::spawn_local;
+use tokio::time::timeout;
+
+// How often a host with parked polls refreshes its presence for the target.
+const PRESENCE_REFRESH_MILLIS: usize = 30_000;
+// Must cover a refresh interval plus the longest possible poll, so that every parked poll
+// is covered by an unexpired presence record.
+const PRESENCE_TTL_MILLIS: usize = 120_000;
+const INTERRUPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Peers {
+    // Address other hosts reach this one on.
+    node_address: String,
+    client: Client<HttpConnector>,
+    // When this host last wrote its presence for each target.
+    presence_written: Mutex<HashMap<String, usize>>,
+}
+
+impl Peers {
+    pub fn new(node_address: String) -> Self {
+        Self {
+            node_address,
+            // Without pooling, every connection belongs to the runtime of the worker thread
+            // making the request. Interrupts are rare enough that this costs nothing.
+            client: Client::builder().pool_max_idle_per_host(0).build_http(),
+            presence_written: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Records that this host has a poll parked for the target, unless that was done
+    // recently enough to still cover the poll.
+    pub async fn announce_poll(&self, database: &Database, target_name: &str) -> Result<(), DatabaseError> {
+        let now = now_epoch_millis();
+        let fresh = self.presence_written()
+            .get(target_name)
+            .is_some_and(|written| now.saturating_sub(*written) < PRESENCE_REFRESH_MILLIS);
+        if fresh {
+            return Ok(());
+        }
+        database.put_poll_presence(PollPresenceRecord {
+            target_name: target_name.to_owned(),
+            node_address: self.node_address.clone(),
+            expires_epoch_millis: now + PRESENCE_TTL_MILLIS,
+        }).await?;
+        let mut presence_written = self.presence_written();
+        // Forget targets which are no longer being polled, so this does not grow forever.
+        presence_written.retain(|_target_name, written| now.saturating_sub(*written) < PRESENCE_TTL_MILLIS);
+        presence_written.insert(target_name.to_owned(), now);
+        Ok(())
+    }
+
+    // Asks every other host with polls parked for the target to wake them for the batch.
+    // Interrupts are sent in the background and are best effort, since a poll which misses
+    // one still finds the work on its next poll.
+    pub async fn interrupt_polls(
+            &self,
+            database: &Database,
+            target_name: &str,
+            batch_id: &str) -> Result<(), DatabaseError> {
+        let now = now_epoch_millis();
+        for record in database.list_poll_presence(target_name).await? {
+            if record.node_address == self.node_address || record.expires_epoch_millis < now {
+                continue;
+            }
+            let request = Request::post(format!(
+                    "http://{}/internal/dispatch/interrupt_polls", record.node_address))
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({ "target_name": target_name, "batch_id": batch_id }).to_string()));
+            let request = match request {
+                Ok(request) => request,
+                Err(_err) => {
+                    // TODO: warn log
+                    METRICS.poll_interrupts_failed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            let response = self.client.request(request);
+            spawn_local(async move {
+                let result = timeout(INTERRUPT_TIMEOUT, response).await;
+                if !matches!(result, Ok(Ok(ref response)) if response.status().is_success()) {
+                    // TODO: warn log
+                    METRICS.poll_interrupts_failed.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn presence_written(&self) -> MutexGuard<'_, HashMap<String, usize>> {
+        self.presence_written.lock().expect("Peers lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::operations::testing::{call, context};
+    use crate::operations::{Context, Router};
+    use crate::{AcceptedConn, handle_conn};
+
+    use super::*;
+
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use tokio::net::TcpListener;
+    use tokio::task::LocalSet;
+    use tokio::time::delay_for;
+
+    // Serves a host over HTTP on a port of its own, around the given storage.
+    async fn start_host(database: Arc<Database>) -> Arc<Context> {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind socket");
+        let address = listener.local_addr().expect("Failed to get local address");
+        let context = Arc::new(Context {
+            database,
+            peers: Peers::new(address.to_string()),
+            ..context()
+        });
+        let served = context.clone();
+        spawn_local(async move {
+            let router = Rc::new(Router::new());
+            while let Ok((stream, remote_addr)) = listener.accept().await {
+                spawn_local(handle_conn(AcceptedConn { stream, remote_addr }, router.clone(), served.clone()));
+            }
+        });
+        context
+    }
+
+    #[tokio::test]
+    async fn dispatches_interrupt_polls_on_other_hosts() {
+        LocalSet::new().run_until(async {
+            let database = Arc::new(Database::local());
+            let polling = start_host(database.clone()).await;
+            let dispatching = start_host(database.clone()).await;
+
+            let started = Instant::now();
+            let poll = spawn_local(async move {
+                call(&polling, "/api/dispatch/receive_commands", json!({
+                    "target_name": "target", "exclude_batches": [], "group_membership": [], "timeout_millis": 10_000,
+                })).await
+            });
+            while database.list_poll_presence("target").await.unwrap().is_empty() {
+                delay_for(Duration::from_millis(10)).await;
+            }
+            let (status, _) = call(&dispatching, "/api/dispatch/dispatch_commands", json!({
+                "target_name": "target",
+                "nonce": "nonce",
+                "commands": [{ "name": "deploy", "data": "v2", "max_retries": 0, "success_required": true }],
+            })).await;
+            assert_eq!(status, 200);
+
+            let (status, output) = poll.await.unwrap();
+            assert!(started.elapsed() < Duration::from_secs(5));
+            assert_eq!(status, 200);
+            assert_eq!(output["command_batches"].as_array().map(Vec::len), Some(1));
+        }).await;
+    }
+}
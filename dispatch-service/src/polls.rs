@@ -0,0 +1,134 @@
+// Long polls parked in ReceiveCommands, waiting for commands to be dispatched against their
+// target. The registry is shared by every worker thread, so a dispatch handled on one core
+// wakes polls parked on any other. Waking goes through oneshot channels, which do not care
+// which runtime the waiting task belongs to.
+//
+// A wake-up carries the id of the batch which was dispatched or resumed, since listing the
+// target's batches may not reflect the write yet on every backend.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use tokio::sync::oneshot;
+
+// Wakes a single parked poll, passing on the batch it is woken for if known.
+type Wake = oneshot::Sender<Option<String>>;
+
+#[derive(Default)]
+pub struct PollRegistry {
+    next_id: AtomicU64,
+    // Parked polls by target name, then by poll id.
+    waiting: Mutex<HashMap<String, HashMap<u64, Wake>>>,
+}
+
+// A registration for a single wake-up. Polls must register before checking for work,
+// otherwise a dispatch in between the check and the registration would be missed.
+// Unregisters when dropped.
+pub struct PollWaiter<'a> {
+    registry: &'a PollRegistry,
+    target_name: String,
+    id: u64,
+    receiver: oneshot::Receiver<Option<String>>,
+}
+
+impl PollRegistry {
+    pub fn register(&self, target_name: &str) -> PollWaiter<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.lock()
+            .entry(target_name.to_owned())
+            .or_default()
+            .insert(id, sender);
+        PollWaiter {
+            registry: self,
+            target_name: target_name.to_owned(),
+            id,
+            receiver,
+        }
+    }
+
+    // Wakes every poll currently parked on the target, returning how many there were.
+    // batch_id is the batch which now has work for the target, if known.
+    pub fn wake(&self, target_name: &str, batch_id: Option<&str>) -> usize {
+        let senders = self.lock().remove(target_name).unwrap_or_default();
+        let woken = senders.len();
+        for (_id, sender) in senders {
+            // The poll may have given up in the meantime.
+            let _ = sender.send(batch_id.map(str::to_owned));
+        }
+        woken
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, HashMap<u64, Wake>>> {
+        self.waiting.lock().expect("PollRegistry lock poisoned")
+    }
+}
+
+impl PollWaiter<'_> {
+    // Resolves once the target is woken, with the id of the batch it was woken for if
+    // known.
+    pub async fn wait(&mut self) -> Option<String> {
+        // The sender is only dropped without sending by Drop below, which cannot happen
+        // while this is borrowed, so any result means the poll was woken.
+        (&mut self.receiver).await.ok().flatten()
+    }
+}
+
+impl Drop for PollWaiter<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.registry.lock();
+        if let Some(polls) = waiting.get_mut(&self.target_name) {
+            polls.remove(&self.id);
+            if polls.is_empty() {
+                waiting.remove(&self.target_name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .expect("Failed to build tokio runtime")
+            .block_on(future)
+    }
+
+    #[test]
+    fn wakes_polls_on_other_threads() {
+        let registry = PollRegistry::default();
+        let mut first = registry.register("target");
+        let mut second = registry.register("target");
+        let mut other = registry.register("other");
+        thread::scope(|scope| {
+            scope.spawn(|| assert_eq!(registry.wake("target", Some("batch")), 2));
+        });
+        block_on(async {
+            let woken_for = timeout(Duration::from_secs(1), first.wait()).await.expect("First poll was not woken");
+            assert_eq!(woken_for.as_deref(), Some("batch"));
+            timeout(Duration::from_secs(1), second.wait()).await.expect("Second poll was not woken");
+            assert!(timeout(Duration::from_millis(10), other.wait()).await.is_err());
+        });
+    }
+
+    #[test]
+    fn dropped_waiters_unregister() {
+        let registry = PollRegistry::default();
+        drop(registry.register("target"));
+        let _waiter = registry.register("other");
+        assert_eq!(registry.wake("target", None), 0);
+        assert_eq!(registry.wake("other", None), 1);
+        assert!(registry.lock().is_empty());
+    }
+}
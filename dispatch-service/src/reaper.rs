@@ -0,0 +1,292 @@
+// Fails started attempts whose executor stopped heartbeating, eg because its host died in
+// the middle of a command, or which ran past their command's execution timeout. The
+// attempt is recorded as timed out, and the batch moves on exactly as if the executor had
+// completed it unsuccessfully: the command is retried if it has retries left, and otherwise
+// the batch fails or carries on per success_required.
+//
+// Also expires batches which passed their deadline while waiting for an executor, eg
+// because their target was decommissioned, and sends the completion notification of every
+// batch which has finished since the last pass, however it finished.
+//
+// Every host runs a reaper. They cannot step on each other, since the silence or timeout is
+// checked again inside the batch update which times the attempt out. Notifications are
+// sent at least once: two hosts may both send one before either records it as delivered.
+
+use crate::constraints;
+use crate::database::{Database, DatabaseError};
+use crate::dependencies;
+use crate::metrics::METRICS;
+use crate::notifications::Notifier;
+use crate::operations::now_epoch_millis;
+use crate::records::NotificationState;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tokio::time::delay_for;
+
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+// A notification which fails this many times in a row, once per pass, is given up on.
+const MAX_NOTIFICATION_FAILURES: usize = 30;
+
+// What a single pass of the reaper did.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+struct Reaped {
+    timed_out: usize,
+    expired: usize,
+    notifications_delivered: usize,
+    notifications_failed: usize,
+    notifications_abandoned: usize,
+}
+
+// Times out attempts which have gone heartbeat_timeout without a heartbeat (or without
+// one since being started).
+pub fn start_reaper_thread(database: Arc<Database>, notifier: Notifier, heartbeat_timeout: Duration) -> JoinHandle<()> {
+    std::thread::Builder::new()
+        .name("dispatch-reaper".to_owned())
+        .spawn(move || {
+            let mut rt = tokio::runtime::Builder::new()
+                .basic_scheduler()
+                .enable_all()
+                .build()
+                .expect("Failed to build tokio runtime on reaper thread");
+            rt.block_on(async move {
+                loop {
+                    delay_for(REAP_INTERVAL).await;
+                    match reap(&database, &notifier, heartbeat_timeout).await {
+                        Ok(reaped) => {
+                            METRICS.attempts_timed_out.fetch_add(reaped.timed_out, Ordering::Relaxed);
+                            METRICS.batches_expired.fetch_add(reaped.expired, Ordering::Relaxed);
+                            METRICS.notifications_delivered.fetch_add(reaped.notifications_delivered, Ordering::Relaxed);
+                            METRICS.notifications_failed.fetch_add(reaped.notifications_failed, Ordering::Relaxed);
+                            METRICS.notifications_abandoned.fetch_add(reaped.notifications_abandoned, Ordering::Relaxed);
+                        },
+                        Err(_err) => {
+                            // TODO: warn log
+                        }
+                    }
+                }
+            });
+        })
+        .expect("Failed to spawn reaper thread")
+}
+
+async fn reap(database: &Database, notifier: &Notifier, heartbeat_timeout: Duration) -> Result<Reaped, DatabaseError> {
+    let now = now_epoch_millis();
+    let silent_since = now.saturating_sub(heartbeat_timeout.as_millis() as usize);
+    let mut reaped = Reaped::default();
+    for batch in database.list_outstanding_batches().await? {
+        if let Some(notification) = batch.complete_notification.as_ref().filter(|_| batch.is_notification_pending()) {
+            let delivered = notifier.notify_complete(&batch, &notification.channel).await.is_ok();
+            // TODO: warn log when the notification failed
+            let recorded = database.update_batch(&batch.id, |batch| {
+                batch.record_notification(delivered, MAX_NOTIFICATION_FAILURES, now)
+            }).await?;
+            match recorded.flatten() {
+                Some(NotificationState::Delivered { .. }) => reaped.notifications_delivered += 1,
+                Some(NotificationState::Pending { .. }) => reaped.notifications_failed += 1,
+                Some(NotificationState::Abandoned) => {
+                    reaped.notifications_failed += 1;
+                    reaped.notifications_abandoned += 1;
+                },
+                None => (),
+            }
+            continue;
+        }
+        // Fails batches whose dependency failed, which polls leave alone.
+        let batch = match dependencies::settle(database, batch).await? {
+            Some(batch) => batch,
+            None => continue,
+        };
+        if batch.is_expired(now) {
+            if database.update_batch(&batch.id, |batch| batch.expire(now)).await? == Some(true) {
+                reaped.expired += 1;
+            }
+            continue;
+        }
+        if !batch.is_silent_since(silent_since) && !batch.is_overdue(now) {
+            continue;
+        }
+        let released = database.update_batch(&batch.id, |batch| batch.time_out(silent_since, now)).await?;
+        for (command_index, groups) in released.unwrap_or_default() {
+            constraints::release(database, &batch.target_name, &groups, &batch.id, command_index).await?;
+            reaped.timed_out += 1;
+        }
+    }
+    Ok(reaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{
+        AttemptState,
+        BatchRecord,
+        BatchState,
+        CommandDefinition,
+        CommandState,
+        CompleteNotification,
+        HeartbeatOutcome,
+        NotificationChannel,
+        RetryPolicy,
+        StartOutcome,
+    };
+
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use serde_json::Value;
+
+    fn notifier() -> Notifier {
+        Notifier::new(None, "us-west-2".to_owned(), None)
+    }
+
+    // Serves HTTP notifications, collecting every body it is sent.
+    fn notification_server() -> (SocketAddr, Arc<Mutex<Vec<Value>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let collected = received.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_conn| {
+            let collected = collected.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let collected = collected.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        collected.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+                        Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        }));
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (address, received)
+    }
+
+    fn definition(max_retries: usize) -> CommandDefinition {
+        CommandDefinition {
+            name: "deploy".to_owned(),
+            data: "v2".to_owned(),
+            max_retries,
+            success_required: true,
+            retry_policy: RetryPolicy::Immediate,
+            execution_timeout_millis: None,
+            parallel_with_previous: false,
+        }
+    }
+
+    async fn start(database: &Database, batch_id: &str, now_epoch_millis: usize) -> String {
+        match database.update_batch(batch_id, |batch| batch.start(0, "nonce", &[], |_| batch_id.to_string(), now_epoch_millis)).await {
+            Ok(Some(StartOutcome::Started { token })) => token,
+            _ => panic!("Expected the command to start"),
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out_silent_attempts() {
+        let database = Database::local();
+        let now = now_epoch_millis();
+        let (retried, failed, alive) = ("retried", "failed", "alive");
+        for (batch_id, max_retries) in [(retried, 1), (failed, 0), (alive, 0)] {
+            let batch = BatchRecord::new(batch_id.to_owned(), "target".to_owned(), vec![definition(max_retries)], now - 60_000);
+            assert!(database.create_batch(batch).await.unwrap());
+        }
+        start(&database, retried, now - 60_000).await;
+        let failed_token = start(&database, failed, now - 60_000).await;
+        let alive_token = start(&database, alive, now - 60_000).await;
+        database.update_batch(alive, |batch| batch.heartbeat(&alive_token, now)).await.unwrap();
+
+        assert_eq!(reap(&database, &notifier(), Duration::from_secs(30)).await.unwrap(),
+            Reaped { timed_out: 2, ..Reaped::default() });
+
+        let batch = database.read_batch(retried).await.unwrap().unwrap();
+        assert_eq!(batch.state, BatchState::Active);
+        assert!(matches!(batch.commands[0].attempts[0].state, AttemptState::Done { timed_out: true, .. }));
+        assert!(matches!(batch.commands[0].attempts[1].state, AttemptState::Available));
+
+        let batch = database.read_batch(failed).await.unwrap().unwrap();
+        assert_eq!(batch.state, BatchState::Done { succeeded: false });
+        assert_eq!(batch.commands[0].state, CommandState::Done { succeeded: false });
+        // The executor finding out late does not change anything.
+        assert_eq!(database.update_batch(failed, |batch| batch.heartbeat(&failed_token, now)).await.unwrap(),
+            Some(HeartbeatOutcome::Discard));
+
+        assert!(!database.read_batch(alive).await.unwrap().unwrap().is_silent_since(now - 30_000));
+        assert_eq!(reap(&database, &notifier(), Duration::from_secs(30)).await.unwrap(), Reaped::default());
+    }
+
+    #[tokio::test]
+    async fn expires_batches_past_their_deadline() {
+        let database = Database::local();
+        let now = now_epoch_millis();
+        let (unstarted, executing, pending) = ("unstarted", "executing", "pending");
+        for (batch_id, deadline) in [(unstarted, now - 1), (executing, now - 1), (pending, now + 60_000)] {
+            let mut batch = BatchRecord::new(batch_id.to_owned(), "target".to_owned(), vec![definition(0), definition(0)], now - 60_000);
+            batch.deadline_epoch_millis = Some(deadline);
+            assert!(database.create_batch(batch).await.unwrap());
+        }
+        let token = start(&database, executing, now - 60_000).await;
+        database.update_batch(executing, |batch| batch.heartbeat(&token, now)).await.unwrap();
+
+        assert_eq!(reap(&database, &notifier(), Duration::from_secs(30)).await.unwrap(),
+            Reaped { expired: 1, ..Reaped::default() });
+
+        let batch = database.read_batch(unstarted).await.unwrap().unwrap();
+        assert_eq!(batch.state, BatchState::Done { succeeded: false });
+        assert!(batch.commands.iter().all(|command| command.state == CommandState::Expired));
+        assert!(batch.commands[0].attempts.is_empty());
+        // The running attempt may finish, but the command after it never starts.
+        assert_eq!(database.read_batch(executing).await.unwrap().unwrap().state, BatchState::Active);
+        assert_eq!(database.read_batch(pending).await.unwrap().unwrap().state, BatchState::Active);
+    }
+
+    #[tokio::test]
+    async fn notifies_once_batches_finish() {
+        let database = Database::local();
+        let now = now_epoch_millis();
+        let (address, received) = notification_server();
+        let (expiring, unreachable) = ("expiring", "unreachable");
+        // Nothing is listening on the unreachable endpoint once the listener is dropped.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        for (batch_id, address) in [(expiring, address), (unreachable, closed)] {
+            let mut batch = BatchRecord::new(batch_id.to_owned(), "target".to_owned(), vec![definition(0)], now - 60_000);
+            batch.deadline_epoch_millis = Some(now - 1);
+            batch.complete_notification = Some(CompleteNotification {
+                channel: NotificationChannel::HTTP {
+                    endpoint: format!("http://{}/done", address),
+                    additional_headers: None,
+                },
+                state: NotificationState::Pending { failures: 0 },
+            });
+            assert!(database.create_batch(batch).await.unwrap());
+        }
+
+        // The notification is sent on the pass after the batch expires.
+        assert_eq!(reap(&database, &notifier(), Duration::from_secs(30)).await.unwrap(),
+            Reaped { expired: 2, ..Reaped::default() });
+        assert!(received.lock().unwrap().is_empty());
+        assert_eq!(reap(&database, &notifier(), Duration::from_secs(30)).await.unwrap(),
+            Reaped { notifications_delivered: 1, notifications_failed: 1, ..Reaped::default() });
+
+        let notifications = received.lock().unwrap().clone();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["batch_id"], "expiring");
+        assert_eq!(notifications[0]["target_name"], "target");
+        assert_eq!(notifications[0]["batch"]["status"], "done");
+        assert_eq!(notifications[0]["batch"]["failure"]["reason"], "expired");
+        let batch = database.read_batch(expiring).await.unwrap().unwrap();
+        assert!(matches!(batch.complete_notification.unwrap().state, NotificationState::Delivered { .. }));
+        let batch = database.read_batch(unreachable).await.unwrap().unwrap();
+        assert_eq!(batch.complete_notification.unwrap().state, NotificationState::Pending { failures: 1 });
+
+        // Only the failed notification is tried again.
+        assert_eq!(reap(&database, &notifier(), Duration::from_secs(30)).await.unwrap(),
+            Reaped { notifications_failed: 1, ..Reaped::default() });
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+}
@@ -1 +1,1510 @@
 // Different records that can be stored in the database.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// A batch of commands dispatched against a single target. The batch record owns its
+// command definitions and every attempt made at executing them, so that the whole
+// state machine for a batch can be advanced with a single read-modify-write.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BatchRecord {
+    // Unique id of the command batch.
+    pub id: String,
+    // The target the commands were dispatched against.
+    pub target_name: String,
+    // Overall state of the batch.
+    pub state: BatchState,
+    // Index of the first command of the step that is currently being worked on. Equal to
+    // commands.len() once every command is done.
+    pub active_command: usize,
+    // Every command in the batch, in the order in which they must be executed. Commands
+    // marked parallel_with_previous make up a step with the commands before them, and the
+    // commands of a step may be executed concurrently.
+    pub commands: Vec<CommandRecord>,
+    // Epoch of the most recently started attempt. Every start takes the next epoch, so an
+    // attempt whose epoch is lower than that of a later attempt at the same command has been
+    // superseded and can no longer advance the batch.
+    #[serde(default)]
+    pub attempt_epoch: usize,
+    // Who cancelled the batch and why, once it is cancelled.
+    #[serde(default)]
+    pub cancellation: Option<Cancellation>,
+    // Every time the batch was paused, oldest first. The batch is paused while the last
+    // pause has not been resumed.
+    #[serde(default)]
+    pub pauses: Vec<Pause>,
+    // Once this passes, every command which has not started yet expires, failing the batch.
+    // None for no deadline.
+    #[serde(default)]
+    pub deadline_epoch_millis: Option<usize>,
+    // Batches which must succeed before the first command may start. Each is dropped once
+    // it has succeeded, so the batch is waiting while any are left.
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    // Why the batch failed, when it was not down to a command failing.
+    #[serde(default)]
+    pub failure: Option<BatchFailure>,
+    // The first step's commands only become available once this passes, eg the start of a
+    // change window. None to be available straight away.
+    #[serde(default)]
+    pub not_before_epoch_millis: Option<usize>,
+    // Higher priority batches are handed out by ReceiveCommands first. May be negative, eg
+    // for background maintenance.
+    #[serde(default)]
+    pub priority: i32,
+    // When the batch was dispatched. Zero for batches dispatched before this was recorded.
+    #[serde(default)]
+    pub dispatched_epoch_millis: usize,
+    // Sent once the batch is done or cancelled. None if the dispatcher did not ask for one.
+    #[serde(default)]
+    pub complete_notification: Option<CompleteNotification>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum BatchState {
+    Active,
+    Done {
+        succeeded: bool
+    },
+    // Stopped by CancelBatch before it was done. Nothing more is started or completed.
+    Cancelled,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum BatchFailure {
+    // The deadline passed with commands left unstarted.
+    Expired,
+    // The dependency failed, was cancelled or was deleted before it succeeded.
+    DependencyFailed {
+        batch_id: String
+    },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct CompleteNotification {
+    pub channel: NotificationChannel,
+    pub state: NotificationState,
+}
+
+// Where a notification is sent.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[allow(clippy::upper_case_acronyms)]
+pub enum NotificationChannel {
+    // POSTed as JSON to the endpoint.
+    #[serde(rename = "http")]
+    HTTP {
+        endpoint: String,
+        additional_headers: Option<HashMap<String, String>>,
+    },
+    #[serde(rename = "aws_sqs")]
+    SQS {
+        queue_url: String,
+    },
+    #[serde(rename = "aws_sns")]
+    SNS {
+        target_arn: String,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum NotificationState {
+    // Not sent yet, either because the batch is still going or because every attempt at
+    // sending it so far failed.
+    Pending {
+        failures: usize
+    },
+    Delivered {
+        delivered_epoch_millis: usize
+    },
+    // Given up on after too many failed attempts.
+    Abandoned,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Pause {
+    pub paused_by: String,
+    pub reason: String,
+    pub paused_epoch_millis: usize,
+    pub resumed_epoch_millis: Option<usize>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Cancellation {
+    pub cancelled_by: String,
+    pub reason: String,
+    pub cancelled_epoch_millis: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CommandRecord {
+    pub definition: CommandDefinition,
+    pub state: CommandState,
+    // Every attempt made at the command so far. The last attempt is the current one.
+    pub attempts: Vec<AttemptRecord>,
+}
+
+// The parts of a dispatched command which never change after dispatch.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CommandDefinition {
+    pub name: String,
+    pub data: String,
+    pub max_retries: usize,
+    pub success_required: bool,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    // How long each attempt may run for before it is timed out, however often its executor
+    // heartbeats. None for no limit.
+    #[serde(default)]
+    pub execution_timeout_millis: Option<usize>,
+    // If true, the command belongs to the same step as the command before it, and may be
+    // executed at the same time as it.
+    #[serde(default)]
+    pub parallel_with_previous: bool,
+}
+
+// How long a failed command waits before its next attempt becomes available.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RetryPolicy {
+    // The next attempt is available straight away.
+    #[default]
+    Immediate,
+    // The same delay before every retry.
+    Fixed {
+        delay_millis: usize,
+        // If true, each delay is picked at random between half and all of delay_millis, so
+        // that many targets failing at once do not all retry at once.
+        #[serde(default)]
+        jitter: bool,
+    },
+    // The delay doubles with every retry, starting at initial_delay_millis, up to
+    // max_delay_millis.
+    Exponential {
+        initial_delay_millis: usize,
+        max_delay_millis: usize,
+        // Same as for Fixed.
+        #[serde(default)]
+        jitter: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum CommandState {
+    Inactive,
+    Active,
+    Done {
+        succeeded: bool
+    },
+    // The batch's deadline passed before the command (or its pending retry) was started.
+    Expired,
+    // Never started, because a dependency of the batch failed.
+    Skipped,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AttemptRecord {
+    // When the attempt became available to be started.
+    pub available_epoch_millis: usize,
+    pub state: AttemptState,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum AttemptState {
+    Available,
+    Started {
+        // Nonce provided by the executor in StartCommand, used to make starting idempotent.
+        nonce: String,
+        // Token handed to the executor, which must be presented on heartbeat and completion.
+        token: String,
+        #[serde(default)]
+        epoch: usize,
+        heartbeats: usize,
+        start_epoch_millis: usize,
+        #[serde(default)]
+        last_heartbeat_epoch_millis: Option<usize>,
+        // Constraint groups in which the attempt holds an execution slot.
+        #[serde(default)]
+        constraint_groups: Vec<String>,
+    },
+    Done {
+        token: String,
+        #[serde(default)]
+        epoch: usize,
+        succeeded: bool,
+        data: Option<String>,
+        heartbeats: usize,
+        start_epoch_millis: usize,
+        complete_epoch_millis: usize,
+        // True if the attempt was failed by the service because the executor stopped
+        // heartbeating, in which case complete_epoch_millis is when that was noticed.
+        #[serde(default)]
+        timed_out: bool,
+    }
+}
+
+// Left behind when a batch is deleted, so that an executor still working on it is told to
+// discard the batch rather than finding that it does not exist.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct BatchTombstoneRecord {
+    pub batch_id: String,
+    pub deleted_epoch_millis: usize,
+}
+
+// Remembers which batch was created for a DispatchCommands nonce, so that retries of the
+// same dispatch return the original batch instead of creating another.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    // Unique key derived from the operation and the client's nonce.
+    pub key: String,
+    // Hash of the request parameters, used to detect a nonce being reused for a different
+    // request.
+    pub request_hash: String,
+    pub batch_id: String,
+    pub created_epoch_millis: usize,
+}
+
+// Records that a target can be reached in an account. Keyed by target name and account id,
+// and never edited once created.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct ConnectionRecord {
+    pub target_name: String,
+    pub account_id: String,
+    pub created_epoch_millis: usize,
+}
+
+// Records that a dispatch-service host has ReceiveCommands polls parked for a target, so
+// that dispatches handled by other hosts know to interrupt them.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct PollPresenceRecord {
+    pub target_name: String,
+    // Address other hosts can reach the polling host on, eg 10.0.0.12:43316.
+    pub node_address: String,
+    // The host may have stopped polling after this.
+    pub expires_epoch_millis: usize,
+}
+
+// Limits how many members of a group of targets may be executing commands at once. Targets
+// become members by declaring it when polling in ReceiveCommands.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConstraintGroupRecord {
+    pub name: String,
+    pub limit: GroupLimit,
+    // Members by target name, with when they last polled.
+    pub members: HashMap<String, usize>,
+    // Commands currently being executed by members, by target name. A member counts
+    // against the limit once no matter how many of its batches are executing.
+    pub holders: HashMap<String, Vec<GroupHolder>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GroupLimit {
+    // At most this many members executing at once.
+    Count {
+        count: usize
+    },
+    // At most this percentage of members executing at once, rounded down. At least one
+    // member may always execute.
+    Percentage {
+        percentage: usize
+    },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct GroupHolder {
+    pub batch_id: String,
+    pub command_index: usize,
+    pub acquired_epoch_millis: usize,
+}
+
+// Dispatches the same commands against a target on a recurring cron schedule, eg nightly
+// maintenance.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScheduleRecord {
+    pub name: String,
+    pub target_name: String,
+    // Five field cron expression, evaluated in UTC. See the cron module.
+    pub cron: String,
+    pub commands: Vec<CommandDefinition>,
+    // Priority of every run's batch.
+    #[serde(default)]
+    pub priority: i32,
+    // Every run due up to and including this time has been dispatched, or skipped because
+    // it was missed.
+    pub last_run_epoch_millis: usize,
+}
+
+pub enum StartOutcome {
+    // The attempt was started, or had already been started with the same nonce.
+    Started {
+        token: String
+    },
+    // The current attempt is a retry which is still waiting out its retry delay.
+    NotYetAvailable {
+        available_epoch_millis: usize
+    },
+    // The batch is already done, so the executor should discard it.
+    Discard,
+    // The batch is paused, so no attempt may be started until it is resumed.
+    Paused,
+    // The batch is waiting for its dependencies to succeed.
+    Waiting,
+    // No command exists at the given index.
+    NoSuchCommand,
+    // The command is not one the batch is currently waiting on.
+    NotActive,
+    // The current attempt was already started with a different nonce.
+    AlreadyStarted,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HeartbeatOutcome {
+    Continue,
+    Discard,
+    // The attempt had run past its execution timeout, and this heartbeat timed it out.
+    TimedOut,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CancelOutcome {
+    // The batch was cancelled. Holds the command index and constraint groups of every
+    // attempt which was executing at the time.
+    Cancelled {
+        interrupted: Vec<(usize, Vec<String>)>
+    },
+    // The batch was cancelled before, and the original cancellation is kept.
+    AlreadyCancelled,
+    // The batch finished before it could be cancelled.
+    AlreadyDone,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompleteOutcome {
+    Discard,
+    NextCommand,
+    // The command will be retried once its next attempt becomes available.
+    SameCommand {
+        available_epoch_millis: usize
+    },
+}
+
+impl BatchRecord {
+    pub fn new(
+            id: String,
+            target_name: String,
+            definitions: Vec<CommandDefinition>,
+            now_epoch_millis: usize) -> Self {
+        let mut batch = Self {
+            id,
+            target_name,
+            state: BatchState::Active,
+            active_command: 0,
+            commands: definitions.into_iter()
+                .map(|definition| CommandRecord {
+                    definition,
+                    state: CommandState::Inactive,
+                    attempts: Vec::new(),
+                })
+                .collect(),
+            attempt_epoch: 0,
+            cancellation: None,
+            pauses: Vec::new(),
+            deadline_epoch_millis: None,
+            dependencies: Vec::new(),
+            failure: None,
+            not_before_epoch_millis: None,
+            priority: 0,
+            dispatched_epoch_millis: now_epoch_millis,
+            complete_notification: None,
+        };
+        batch.activate_step(now_epoch_millis);
+        batch
+    }
+
+    // Commands which have not finished yet, along with their index in the batch.
+    pub fn remaining_commands(&self) -> impl Iterator<Item = (usize, &CommandRecord)> {
+        self.commands.iter().enumerate().skip(self.active_command)
+            .filter(|(_index, command)| !matches!(command.state, CommandState::Done { .. }))
+    }
+
+    // Indexes of the commands in the step that is currently being worked on. Empty once
+    // every command is done.
+    pub fn current_step(&self) -> Range<usize> {
+        let end = self.step_end(self.active_command);
+        self.active_command.min(end)..end
+    }
+
+    // Index of the first command after the step which the command belongs to.
+    fn step_end(&self, command_index: usize) -> usize {
+        self.commands.iter().enumerate()
+            .skip(command_index + 1)
+            .find(|(_index, command)| !command.definition.parallel_with_previous)
+            .map_or(self.commands.len(), |(index, _command)| index)
+    }
+
+    // True if the command is in the current step and has not finished yet.
+    pub fn is_active(&self, command_index: usize) -> bool {
+        self.state == BatchState::Active
+            && self.current_step().contains(&command_index)
+            && self.commands[command_index].state == CommandState::Active
+    }
+
+    // mint_token is given the index of the attempt within the command. Tokens only need to
+    // be unique within the batch, since the attempt's epoch is recorded alongside its token.
+    pub fn start(
+            &mut self,
+            command_index: usize,
+            nonce: &str,
+            constraint_groups: &[String],
+            mint_token: impl FnOnce(usize) -> String,
+            now_epoch_millis: usize) -> StartOutcome {
+        if command_index >= self.commands.len() {
+            return StartOutcome::NoSuchCommand;
+        }
+        if self.state != BatchState::Active {
+            return StartOutcome::Discard;
+        }
+        if !self.is_active(command_index) {
+            return StartOutcome::NotActive;
+        }
+        if self.expire(now_epoch_millis) {
+            return StartOutcome::Discard;
+        }
+        if self.is_waiting() {
+            return StartOutcome::Waiting;
+        }
+        let paused = self.is_paused();
+        let attempts = &mut self.commands[command_index].attempts;
+        let attempt_index = attempts.len().checked_sub(1).expect("Active command has no attempts");
+        let attempt = &mut attempts[attempt_index];
+        match &attempt.state {
+            AttemptState::Available if paused => StartOutcome::Paused,
+            AttemptState::Available if attempt.available_epoch_millis > now_epoch_millis => {
+                StartOutcome::NotYetAvailable { available_epoch_millis: attempt.available_epoch_millis }
+            },
+            AttemptState::Available => {
+                self.attempt_epoch += 1;
+                let token = mint_token(attempt_index);
+                attempt.state = AttemptState::Started {
+                    nonce: nonce.to_owned(),
+                    token: token.clone(),
+                    epoch: self.attempt_epoch,
+                    heartbeats: 0,
+                    start_epoch_millis: now_epoch_millis,
+                    last_heartbeat_epoch_millis: None,
+                    constraint_groups: constraint_groups.to_vec(),
+                };
+                StartOutcome::Started { token }
+            },
+            AttemptState::Started { nonce: started_nonce, token, .. } => {
+                if started_nonce == nonce {
+                    StartOutcome::Started { token: token.clone() }
+                } else {
+                    StartOutcome::AlreadyStarted
+                }
+            },
+            AttemptState::Done { .. } => StartOutcome::AlreadyStarted,
+        }
+    }
+
+    // Times the attempt out instead of recording the heartbeat if it has run past its
+    // execution timeout.
+    pub fn heartbeat(&mut self, token: &str, now_epoch_millis: usize) -> HeartbeatOutcome {
+        if self.state != BatchState::Active {
+            return HeartbeatOutcome::Discard;
+        }
+        let command_index = match self.find_current_attempt(token) {
+            Some(command_index) => command_index,
+            None => return HeartbeatOutcome::Discard,
+        };
+        if self.is_command_overdue(command_index, now_epoch_millis) {
+            self.time_out_command(command_index, now_epoch_millis);
+            return HeartbeatOutcome::TimedOut;
+        }
+        let latest_epoch = self.latest_epoch(command_index);
+        let attempt = self.commands[command_index].attempts.last_mut().expect("Current attempt not found");
+        match &mut attempt.state {
+            AttemptState::Started { token: started_token, epoch, heartbeats, last_heartbeat_epoch_millis, .. }
+                    if started_token == token && *epoch == latest_epoch => {
+                *heartbeats += 1;
+                *last_heartbeat_epoch_millis = Some(now_epoch_millis);
+                HeartbeatOutcome::Continue
+            },
+            _ => HeartbeatOutcome::Discard,
+        }
+    }
+
+    // While the batch is paused the attempt is still recorded as done, but the executor is
+    // told to discard the batch rather than to carry on with it.
+    pub fn complete(
+            &mut self,
+            token: &str,
+            success: bool,
+            data: Option<String>,
+            now_epoch_millis: usize) -> CompleteOutcome {
+        let outcome = self.complete_attempt(token, success, data, now_epoch_millis);
+        if self.is_paused() {
+            CompleteOutcome::Discard
+        } else {
+            outcome
+        }
+    }
+
+    fn complete_attempt(
+            &mut self,
+            token: &str,
+            success: bool,
+            data: Option<String>,
+            now_epoch_millis: usize) -> CompleteOutcome {
+        if self.state == BatchState::Cancelled {
+            return CompleteOutcome::Discard;
+        }
+        let (command_index, attempt_index) = match self.find_attempt(token) {
+            Some(position) => position,
+            None => return CompleteOutcome::Discard,
+        };
+        let latest_epoch = self.latest_epoch(command_index);
+        let attempt = &mut self.commands[command_index].attempts[attempt_index];
+        let (epoch, heartbeats, start_epoch_millis) = match &attempt.state {
+            // A later attempt has been started since, and only it may advance the batch.
+            AttemptState::Started { epoch, .. } | AttemptState::Done { epoch, .. }
+                if *epoch < latest_epoch => return CompleteOutcome::Discard,
+            AttemptState::Started { epoch, heartbeats, start_epoch_millis, .. } =>
+                (*epoch, *heartbeats, *start_epoch_millis),
+            // The service gave up on the attempt, and has moved on without it.
+            AttemptState::Done { timed_out: true, .. } => return CompleteOutcome::Discard,
+            // The executor is retrying a completion which already went through. Tell it
+            // whatever the batch has moved on to since then.
+            _ => return self.replay_complete(command_index, attempt_index),
+        };
+        // Another command of the same step failed the batch while this one was running.
+        if self.state != BatchState::Active {
+            return CompleteOutcome::Discard;
+        }
+        attempt.state = AttemptState::Done {
+            token: token.to_owned(),
+            epoch,
+            succeeded: success,
+            data,
+            heartbeats,
+            start_epoch_millis,
+            complete_epoch_millis: now_epoch_millis,
+            timed_out: false,
+        };
+        self.finish_attempt(command_index, success, now_epoch_millis)
+    }
+
+    // Stops the batch from moving on to another attempt until it is resumed. An attempt
+    // which is executing may still finish. Returns false if the batch is done or cancelled.
+    pub fn pause(&mut self, paused_by: &str, reason: &str, now_epoch_millis: usize) -> bool {
+        if self.state != BatchState::Active {
+            return false;
+        }
+        // Pausing is idempotent, and only the first pause is recorded.
+        if !self.is_paused() {
+            self.pauses.push(Pause {
+                paused_by: paused_by.to_owned(),
+                reason: reason.to_owned(),
+                paused_epoch_millis: now_epoch_millis,
+                resumed_epoch_millis: None,
+            });
+        }
+        true
+    }
+
+    // Returns false if the batch is done or cancelled. Resuming a batch which is not paused
+    // does nothing.
+    pub fn resume(&mut self, now_epoch_millis: usize) -> bool {
+        if self.state != BatchState::Active {
+            return false;
+        }
+        if let Some(pause) = self.pauses.last_mut().filter(|pause| pause.resumed_epoch_millis.is_none()) {
+            pause.resumed_epoch_millis = Some(now_epoch_millis);
+        }
+        true
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pauses.last().is_some_and(|pause| pause.resumed_epoch_millis.is_none())
+    }
+
+    // Stops the batch where it is. Attempts which are executing are left as they are, but
+    // their executors are told to discard the batch at their next heartbeat or completion.
+    pub fn cancel(&mut self, cancelled_by: &str, reason: &str, now_epoch_millis: usize) -> CancelOutcome {
+        match self.state {
+            BatchState::Active => (),
+            BatchState::Done { .. } => return CancelOutcome::AlreadyDone,
+            BatchState::Cancelled => return CancelOutcome::AlreadyCancelled,
+        }
+        let interrupted = self.started_attempt_groups();
+        self.state = BatchState::Cancelled;
+        self.cancellation = Some(Cancellation {
+            cancelled_by: cancelled_by.to_owned(),
+            reason: reason.to_owned(),
+            cancelled_epoch_millis: now_epoch_millis,
+        });
+        CancelOutcome::Cancelled { interrupted }
+    }
+
+    // Fails every current attempt which was started but whose executor has not heartbeated
+    // since silent_since_epoch_millis, or which has run past its execution timeout. Returns
+    // the index of the command and the constraint groups the attempt held slots in for every
+    // attempt which was timed out.
+    pub fn time_out(
+            &mut self,
+            silent_since_epoch_millis: usize,
+            now_epoch_millis: usize) -> Vec<(usize, Vec<String>)> {
+        let mut timed_out = Vec::new();
+        for command_index in self.current_step() {
+            let silent = self.commands[command_index].attempts.last()
+                .is_some_and(|attempt| attempt.is_silent_since(silent_since_epoch_millis));
+            if silent || self.is_command_overdue(command_index, now_epoch_millis) {
+                timed_out.extend(self.time_out_command(command_index, now_epoch_millis));
+            }
+        }
+        timed_out
+    }
+
+    fn time_out_command(&mut self, command_index: usize, now_epoch_millis: usize) -> Option<(usize, Vec<String>)> {
+        if !self.is_executing(command_index) {
+            return None;
+        }
+        let attempt = self.commands[command_index].attempts.last_mut()?;
+        let (token, epoch, heartbeats, start_epoch_millis, constraint_groups) = match &attempt.state {
+            AttemptState::Started { token, epoch, heartbeats, start_epoch_millis, constraint_groups, .. } =>
+                (token.clone(), *epoch, *heartbeats, *start_epoch_millis, constraint_groups.clone()),
+            _ => return None,
+        };
+        attempt.state = AttemptState::Done {
+            token,
+            epoch,
+            succeeded: false,
+            data: None,
+            heartbeats,
+            start_epoch_millis,
+            complete_epoch_millis: now_epoch_millis,
+            timed_out: true,
+        };
+        self.finish_attempt(command_index, false, now_epoch_millis);
+        Some((command_index, constraint_groups))
+    }
+
+    // Moves the batch on after the command's current attempt is done, either to a retry of
+    // the command, to the next step, or to the end of the batch. The next step only starts
+    // once every command in the current one is done.
+    fn finish_attempt(&mut self, command_index: usize, success: bool, now_epoch_millis: usize) -> CompleteOutcome {
+        let outcome = self.move_on(command_index, success, now_epoch_millis);
+        // Whatever comes next would have to start after the deadline.
+        if self.expire(now_epoch_millis) {
+            CompleteOutcome::Discard
+        } else {
+            outcome
+        }
+    }
+
+    fn move_on(&mut self, command_index: usize, success: bool, now_epoch_millis: usize) -> CompleteOutcome {
+        let command = &mut self.commands[command_index];
+        if success {
+            command.state = CommandState::Done { succeeded: true };
+            self.advance(now_epoch_millis);
+            return CompleteOutcome::NextCommand;
+        }
+        if command.attempts.len() <= command.definition.max_retries {
+            let random = (Uuid::new_v4().as_u128() % 1024) as f64 / 1024.0;
+            let delay_millis = command.definition.retry_policy.delay_millis(command.attempts.len(), random);
+            let available_epoch_millis = now_epoch_millis.saturating_add(delay_millis);
+            command.attempts.push(AttemptRecord {
+                available_epoch_millis,
+                state: AttemptState::Available,
+            });
+            return CompleteOutcome::SameCommand { available_epoch_millis };
+        }
+        // Retries are exhausted. Whether the batch can carry on without this command is up
+        // to the dispatcher.
+        command.state = CommandState::Done { succeeded: false };
+        if command.definition.success_required {
+            self.state = BatchState::Done { succeeded: false };
+            return CompleteOutcome::Discard;
+        }
+        self.advance(now_epoch_millis);
+        CompleteOutcome::NextCommand
+    }
+
+    // Fails the batch if it is past its deadline, unless any current attempt is still
+    // executing, in which case those attempts may finish first. Every command which has not
+    // started expires, along with a pending retry of the current command. Returns true if
+    // the batch expired.
+    pub fn expire(&mut self, now_epoch_millis: usize) -> bool {
+        if !self.is_expired(now_epoch_millis) {
+            return false;
+        }
+        self.abandon(CommandState::Expired, BatchFailure::Expired);
+        true
+    }
+
+    // Fails the batch without running the rest of its commands, which are left in the given
+    // state.
+    fn abandon(&mut self, command_state: CommandState, failure: BatchFailure) {
+        for command in &mut self.commands[self.active_command..] {
+            if matches!(command.state, CommandState::Done { .. }) {
+                continue;
+            }
+            if command.attempts.last().is_some_and(|attempt| matches!(attempt.state, AttemptState::Available)) {
+                command.attempts.pop();
+            }
+            command.state = command_state;
+        }
+        self.state = BatchState::Done { succeeded: false };
+        self.failure = Some(failure);
+    }
+
+    // Holds back the first attempts of the current step until not_before_epoch_millis. Only
+    // meant for freshly created batches.
+    pub fn hold_until(&mut self, not_before_epoch_millis: usize) {
+        self.not_before_epoch_millis = Some(not_before_epoch_millis);
+        for command_index in self.current_step() {
+            for attempt in &mut self.commands[command_index].attempts {
+                attempt.available_epoch_millis = attempt.available_epoch_millis.max(not_before_epoch_millis);
+            }
+        }
+    }
+
+    // True once the batch's not-before time has passed, if it had one.
+    pub fn is_due(&self, now_epoch_millis: usize) -> bool {
+        self.not_before_epoch_millis.is_none_or(|not_before| now_epoch_millis >= not_before)
+    }
+
+    // The batch's priority plus one for every aging_millis it has waited since dispatch, so
+    // that a steady stream of higher priority batches cannot starve it forever.
+    pub fn effective_priority(&self, aging_millis: usize, now_epoch_millis: usize) -> i64 {
+        let waited_millis = now_epoch_millis.saturating_sub(self.dispatched_epoch_millis);
+        i64::from(self.priority) + (waited_millis / aging_millis.max(1)) as i64
+    }
+
+    // True once the batch is done or cancelled, if its completion notification has not been
+    // sent or given up on yet.
+    pub fn is_notification_pending(&self) -> bool {
+        self.state != BatchState::Active
+            && matches!(&self.complete_notification,
+                Some(CompleteNotification { state: NotificationState::Pending { .. }, .. }))
+    }
+
+    // True while the batch still needs background work, ie while it is active or its
+    // completion notification is pending.
+    pub fn is_outstanding(&self) -> bool {
+        self.state == BatchState::Active || self.is_notification_pending()
+    }
+
+    // Records an attempt at sending the completion notification, abandoning it once it
+    // has failed max_failures times. Returns the new state, or None if the notification was
+    // not pending, eg because another host already sent it.
+    pub fn record_notification(
+            &mut self,
+            delivered: bool,
+            max_failures: usize,
+            now_epoch_millis: usize) -> Option<NotificationState> {
+        if !self.is_notification_pending() {
+            return None;
+        }
+        let notification = self.complete_notification.as_mut()?;
+        notification.state = match notification.state {
+            _ if delivered => NotificationState::Delivered { delivered_epoch_millis: now_epoch_millis },
+            NotificationState::Pending { failures } if failures + 1 >= max_failures => NotificationState::Abandoned,
+            NotificationState::Pending { failures } => NotificationState::Pending { failures: failures + 1 },
+            state => state,
+        };
+        Some(notification.state)
+    }
+
+    // True while any of the batch's dependencies has not succeeded yet.
+    pub fn is_waiting(&self) -> bool {
+        !self.dependencies.is_empty()
+    }
+
+    // Stops waiting on the dependencies which have succeeded, or fails the batch if one
+    // never will. Does nothing unless the batch is active.
+    pub fn settle_dependencies(&mut self, succeeded: &[String], failed: Option<&str>) {
+        if self.state != BatchState::Active {
+            return;
+        }
+        match failed {
+            Some(batch_id) if self.dependencies.iter().any(|dependency| dependency == batch_id) => {
+                self.abandon(CommandState::Skipped, BatchFailure::DependencyFailed { batch_id: batch_id.to_owned() });
+            },
+            _ => self.dependencies.retain(|dependency| !succeeded.contains(dependency)),
+        }
+    }
+
+    // True if the batch is past its deadline and none of its current attempts are
+    // executing, so that expire would fail it.
+    pub fn is_expired(&self, now_epoch_millis: usize) -> bool {
+        self.state == BatchState::Active
+            && self.deadline_epoch_millis.is_some_and(|deadline| now_epoch_millis >= deadline)
+            && !self.is_step_executing()
+    }
+
+    // If the command's current attempt is a retry which is still waiting out its retry
+    // delay, when it becomes available.
+    pub fn waiting_until(&self, command_index: usize, now_epoch_millis: usize) -> Option<usize> {
+        self.commands.get(command_index)
+            .and_then(|command| command.attempts.last())
+            .filter(|attempt| matches!(attempt.state, AttemptState::Available))
+            .map(|attempt| attempt.available_epoch_millis)
+            .filter(|available_epoch_millis| *available_epoch_millis > now_epoch_millis)
+    }
+
+    // True if the command is in the current step and its current attempt has been started
+    // but not completed.
+    pub fn is_executing(&self, command_index: usize) -> bool {
+        self.state == BatchState::Active
+            && self.current_step().contains(&command_index)
+            && self.commands[command_index].attempts.last()
+                .is_some_and(|attempt| matches!(attempt.state, AttemptState::Started { .. }))
+    }
+
+    // True if any command in the current step is executing.
+    pub fn is_step_executing(&self) -> bool {
+        self.current_step().any(|command_index| self.is_executing(command_index))
+    }
+
+    // True if a current attempt was started but the executor has not heartbeated since
+    // silent_since_epoch_millis.
+    pub fn is_silent_since(&self, silent_since_epoch_millis: usize) -> bool {
+        self.state == BatchState::Active
+            && self.current_step()
+                .filter_map(|command_index| self.commands[command_index].attempts.last())
+                .any(|attempt| attempt.is_silent_since(silent_since_epoch_millis))
+    }
+
+    // True if a current attempt was started longer ago than its command's execution
+    // timeout.
+    pub fn is_overdue(&self, now_epoch_millis: usize) -> bool {
+        self.current_step().any(|command_index| self.is_command_overdue(command_index, now_epoch_millis))
+    }
+
+    fn is_command_overdue(&self, command_index: usize, now_epoch_millis: usize) -> bool {
+        let command = &self.commands[command_index];
+        self.state == BatchState::Active
+            && match (command.definition.execution_timeout_millis, command.attempts.last()) {
+                (Some(timeout_millis), Some(AttemptRecord { state: AttemptState::Started { start_epoch_millis, .. }, .. })) =>
+                    now_epoch_millis >= start_epoch_millis.saturating_add(timeout_millis),
+                _ => false,
+            }
+    }
+
+    // The command index of every current attempt which was started and has not completed,
+    // and the constraint groups in which it holds an execution slot. Unlike is_executing,
+    // includes attempts which were still running when the batch was cancelled.
+    pub fn started_attempt_groups(&self) -> Vec<(usize, Vec<String>)> {
+        self.current_step()
+            .filter_map(|command_index| match &self.commands[command_index].attempts.last()?.state {
+                AttemptState::Started { constraint_groups, .. } => Some((command_index, constraint_groups.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The command index of the started attempt with the given token, and the constraint
+    // groups in which it holds an execution slot. None if there is no such started attempt.
+    pub fn held_constraint_groups(&self, token: &str) -> Option<(usize, Vec<String>)> {
+        let (command_index, attempt_index) = self.find_attempt(token)?;
+        match &self.commands[command_index].attempts[attempt_index].state {
+            AttemptState::Started { constraint_groups, .. } => Some((command_index, constraint_groups.clone())),
+            _ => None,
+        }
+    }
+
+    fn replay_complete(&self, command_index: usize, attempt_index: usize) -> CompleteOutcome {
+        if self.state == (BatchState::Done { succeeded: false }) {
+            CompleteOutcome::Discard
+        } else if matches!(self.commands[command_index].state, CommandState::Done { .. }) {
+            // Includes every command of a batch which succeeded.
+            CompleteOutcome::NextCommand
+        } else if let Some(next) = self.commands[command_index].attempts.get(attempt_index + 1) {
+            CompleteOutcome::SameCommand { available_epoch_millis: next.available_epoch_millis }
+        } else {
+            CompleteOutcome::Discard
+        }
+    }
+
+    // Moves on to the next step once every command in the current one is done.
+    fn advance(&mut self, now_epoch_millis: usize) {
+        let step = self.current_step();
+        if !self.commands[step.clone()].iter().all(|command| matches!(command.state, CommandState::Done { .. })) {
+            return;
+        }
+        self.active_command = step.end;
+        self.activate_step(now_epoch_millis);
+    }
+
+    fn activate_step(&mut self, now_epoch_millis: usize) {
+        let step = self.current_step();
+        if step.is_empty() {
+            self.state = BatchState::Done { succeeded: true };
+        }
+        for command in &mut self.commands[step] {
+            command.state = CommandState::Active;
+            command.attempts.push(AttemptRecord {
+                available_epoch_millis: now_epoch_millis,
+                state: AttemptState::Available,
+            });
+        }
+    }
+
+    // Index of the command in the current step whose current attempt has the given token.
+    fn find_current_attempt(&self, token: &str) -> Option<usize> {
+        self.current_step().find(|command_index| self.commands[*command_index].attempts.last()
+            .is_some_and(|attempt| attempt.token() == Some(token)))
+    }
+
+    // Epoch of the latest attempt to have been started at the command or at any command in a
+    // later step. Attempts at the other commands of the same step run alongside the
+    // command's, so do not supersede them.
+    fn latest_epoch(&self, command_index: usize) -> usize {
+        let later_steps = &self.commands[self.step_end(command_index)..];
+        std::iter::once(&self.commands[command_index]).chain(later_steps)
+            .flat_map(|command| &command.attempts)
+            .filter_map(|attempt| match attempt.state {
+                AttemptState::Started { epoch, .. } | AttemptState::Done { epoch, .. } => Some(epoch),
+                AttemptState::Available => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn find_attempt(&self, token: &str) -> Option<(usize, usize)> {
+        self.commands.iter().enumerate().find_map(|(command_index, command)| {
+            command.attempts.iter()
+                .position(|attempt| attempt.token() == Some(token))
+                .map(|attempt_index| (command_index, attempt_index))
+        })
+    }
+}
+
+impl RetryPolicy {
+    // Delay before the given retry, where the first retry is 1. random must be in [0, 1),
+    // and is only used for jitter.
+    pub fn delay_millis(&self, retry: usize, random: f64) -> usize {
+        let (delay_millis, jitter) = match *self {
+            Self::Immediate => return 0,
+            Self::Fixed { delay_millis, jitter } => (delay_millis, jitter),
+            Self::Exponential { initial_delay_millis, max_delay_millis, jitter } => {
+                let doublings = retry.saturating_sub(1).min(usize::BITS as usize - 1) as u32;
+                (initial_delay_millis.saturating_mul(1 << doublings).min(max_delay_millis), jitter)
+            },
+        };
+        if jitter {
+            delay_millis - (delay_millis as f64 / 2.0 * random) as usize
+        } else {
+            delay_millis
+        }
+    }
+}
+
+impl AttemptRecord {
+    fn is_silent_since(&self, silent_since_epoch_millis: usize) -> bool {
+        match &self.state {
+            AttemptState::Started { start_epoch_millis, last_heartbeat_epoch_millis, .. } =>
+                last_heartbeat_epoch_millis.unwrap_or(*start_epoch_millis) < silent_since_epoch_millis,
+            _ => false,
+        }
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        match &self.state {
+            AttemptState::Available => None,
+            AttemptState::Started { token, .. } | AttemptState::Done { token, .. } => Some(token),
+        }
+    }
+}
+
+impl ConstraintGroupRecord {
+    pub fn new(name: String, limit: GroupLimit) -> Self {
+        Self {
+            name,
+            limit,
+            members: HashMap::new(),
+            holders: HashMap::new(),
+        }
+    }
+
+    // How many members may be executing at once.
+    pub fn max_executing(&self) -> usize {
+        match self.limit {
+            GroupLimit::Count { count } => count,
+            GroupLimit::Percentage { percentage } => (self.members.len() * percentage / 100).max(1),
+        }
+    }
+
+    // True if the target may not start executing another command, because it is not
+    // executing one already and the group is at its limit.
+    pub fn is_full_for(&self, target_name: &str) -> bool {
+        !self.holders.contains_key(target_name) && self.holders.len() >= self.max_executing()
+    }
+
+    // Records that the target polled, and forgets members which have not polled since
+    // members_seen_after.
+    pub fn refresh_member(&mut self, target_name: &str, members_seen_after: usize, now_epoch_millis: usize) {
+        self.members.retain(|_target_name, last_seen| *last_seen >= members_seen_after);
+        self.members.insert(target_name.to_owned(), now_epoch_millis);
+    }
+
+    // Takes an execution slot for the command unless the group is full. Returns false if
+    // the group is full, and true if the slot was taken now or had been already.
+    pub fn acquire(&mut self, target_name: &str, batch_id: &str, command_index: usize, now_epoch_millis: usize) -> bool {
+        if self.holds(target_name, batch_id, command_index) {
+            return true;
+        }
+        if self.is_full_for(target_name) {
+            return false;
+        }
+        self.holders.entry(target_name.to_owned()).or_default().push(GroupHolder {
+            batch_id: batch_id.to_owned(),
+            command_index,
+            acquired_epoch_millis: now_epoch_millis,
+        });
+        true
+    }
+
+    pub fn release(&mut self, target_name: &str, batch_id: &str, command_index: usize) {
+        self.remove_holders(target_name, |holder| holder.batch_id == batch_id && holder.command_index == command_index);
+    }
+
+    // Releases exactly the given slot, leaving alone any slot taken again by the same
+    // command since.
+    pub fn reclaim(&mut self, target_name: &str, stale: &GroupHolder) {
+        self.remove_holders(target_name, |holder| holder == stale);
+    }
+
+    fn remove_holders(&mut self, target_name: &str, remove: impl Fn(&GroupHolder) -> bool) {
+        if let Some(holders) = self.holders.get_mut(target_name) {
+            holders.retain(|holder| !remove(holder));
+            if holders.is_empty() {
+                self.holders.remove(target_name);
+            }
+        }
+    }
+
+    pub fn holds(&self, target_name: &str, batch_id: &str, command_index: usize) -> bool {
+        self.holders.get(target_name).is_some_and(|holders| holders.iter()
+            .any(|holder| holder.batch_id == batch_id && holder.command_index == command_index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delays() {
+        assert_eq!(RetryPolicy::Immediate.delay_millis(3, 0.5), 0);
+
+        let fixed = RetryPolicy::Fixed { delay_millis: 1000, jitter: false };
+        assert_eq!(fixed.delay_millis(1, 0.5), 1000);
+        assert_eq!(fixed.delay_millis(5, 0.5), 1000);
+
+        let exponential = RetryPolicy::Exponential { initial_delay_millis: 100, max_delay_millis: 1000, jitter: false };
+        let delays = (1..=6).map(|retry| exponential.delay_millis(retry, 0.5)).collect::<Vec<_>>();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(exponential.delay_millis(usize::MAX, 0.5), 1000);
+
+        let jittered = RetryPolicy::Exponential { initial_delay_millis: 100, max_delay_millis: 1000, jitter: true };
+        assert_eq!(jittered.delay_millis(2, 0.0), 200);
+        assert_eq!(jittered.delay_millis(2, 0.5), 150);
+        assert_eq!(jittered.delay_millis(2, 0.999), 101);
+    }
+
+    fn definition(max_retries: usize, success_required: bool) -> CommandDefinition {
+        CommandDefinition {
+            name: "deploy".to_owned(),
+            data: String::new(),
+            max_retries,
+            success_required,
+            retry_policy: RetryPolicy::Immediate,
+            execution_timeout_millis: None,
+            parallel_with_previous: false,
+        }
+    }
+
+    fn start(batch: &mut BatchRecord, command_index: usize, now_epoch_millis: usize) -> String {
+        let nonce = format!("nonce-{}", now_epoch_millis);
+        let mint_token = |attempt| format!("token-{}-{}", command_index, attempt);
+        match batch.start(command_index, &nonce, &[], mint_token, now_epoch_millis) {
+            StartOutcome::Started { token } => token,
+            _ => panic!("Expected command {} to start", command_index),
+        }
+    }
+
+    // Every combination of the attempt's result, success_required, whether the command has
+    // retries left, and whether it is the last command in the batch.
+    #[test]
+    fn complete_decisions() {
+        for success in [true, false] {
+            for success_required in [true, false] {
+                for retries_left in [true, false] {
+                    for is_last in [true, false] {
+                        let case = format!("success={} success_required={} retries_left={} is_last={}",
+                            success, success_required, retries_left, is_last);
+                        let mut definitions = vec![definition(if retries_left { 1 } else { 0 }, success_required)];
+                        if !is_last {
+                            definitions.push(definition(0, true));
+                        }
+                        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), definitions, 1000);
+                        let token = start(&mut batch, 0, 2000);
+                        let outcome = batch.complete(&token, success, Some("output".to_owned()), 3000);
+
+                        match &batch.commands[0].attempts[0].state {
+                            AttemptState::Done { succeeded, data, complete_epoch_millis, timed_out, .. } => {
+                                assert_eq!(*succeeded, success, "{}", case);
+                                assert_eq!(data.as_deref(), Some("output"), "{}", case);
+                                assert_eq!(*complete_epoch_millis, 3000, "{}", case);
+                                assert!(!timed_out, "{}", case);
+                            },
+                            _ => panic!("Attempt was not recorded as done: {}", case),
+                        }
+
+                        let (expected_outcome, command_state, batch_state, active_command) = if success {
+                            let batch_state = if is_last { BatchState::Done { succeeded: true } } else { BatchState::Active };
+                            (CompleteOutcome::NextCommand, CommandState::Done { succeeded: true }, batch_state, 1)
+                        } else if retries_left {
+                            (CompleteOutcome::SameCommand { available_epoch_millis: 3000 }, CommandState::Active,
+                                BatchState::Active, 0)
+                        } else if success_required {
+                            (CompleteOutcome::Discard, CommandState::Done { succeeded: false },
+                                BatchState::Done { succeeded: false }, 0)
+                        } else {
+                            // The failure is tolerated, so the batch carries on as if it had succeeded.
+                            let batch_state = if is_last { BatchState::Done { succeeded: true } } else { BatchState::Active };
+                            (CompleteOutcome::NextCommand, CommandState::Done { succeeded: false }, batch_state, 1)
+                        };
+                        assert_eq!(outcome, expected_outcome, "{}", case);
+                        assert_eq!(batch.commands[0].state, command_state, "{}", case);
+                        assert_eq!(batch.state, batch_state, "{}", case);
+                        assert_eq!(batch.active_command, active_command, "{}", case);
+
+                        // The attempt which replaces a failed one, or the next command's
+                        // first attempt, is available straight away.
+                        if batch.state == BatchState::Active {
+                            let command = &batch.commands[batch.active_command];
+                            assert_eq!(command.state, CommandState::Active, "{}", case);
+                            let attempt = command.attempts.last().unwrap();
+                            assert!(matches!(attempt.state, AttemptState::Available), "{}", case);
+                            assert_eq!(attempt.available_epoch_millis, 3000, "{}", case);
+                        }
+
+                        // Retrying the completion, even with a different result, repeats the
+                        // instruction without changing anything.
+                        assert_eq!(batch.complete(&token, !success, None, 4000), expected_outcome, "{}", case);
+                        assert_eq!(batch.commands[0].state, command_state, "{}", case);
+                        assert_eq!(batch.state, batch_state, "{}", case);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn retries_are_consumed() {
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), vec![definition(2, true)], 1000);
+        for attempt in 0..2 {
+            let token = start(&mut batch, 0, 2000 + 2000 * attempt);
+            assert!(matches!(batch.complete(&token, false, None, 3000 + 2000 * attempt), CompleteOutcome::SameCommand { .. }));
+        }
+        let token = start(&mut batch, 0, 6000);
+        assert_eq!(batch.complete(&token, false, None, 7000), CompleteOutcome::Discard);
+        assert_eq!(batch.commands[0].attempts.len(), 3);
+        assert_eq!(batch.state, BatchState::Done { succeeded: false });
+    }
+
+    #[test]
+    fn stale_completions() {
+        let definitions = vec![definition(1, true), definition(0, true)];
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), definitions, 1000);
+        assert_eq!(batch.complete("unknown", true, None, 2000), CompleteOutcome::Discard);
+
+        // An earlier attempt of a command which has since moved on is told where it went,
+        // until a later attempt is started.
+        let first = start(&mut batch, 0, 2000);
+        assert!(matches!(batch.complete(&first, false, None, 3000), CompleteOutcome::SameCommand { .. }));
+        let second = start(&mut batch, 0, 4000);
+        assert_eq!(batch.complete(&first, false, None, 4500), CompleteOutcome::Discard);
+        assert_eq!(batch.complete(&second, true, None, 5000), CompleteOutcome::NextCommand);
+        assert_eq!(batch.complete(&second, true, None, 6000), CompleteOutcome::NextCommand);
+
+        // A timed out attempt is discarded, whatever happened to the batch since.
+        let third = start(&mut batch, 1, 7000);
+        assert!(!batch.time_out(8000, 9000).is_empty());
+        assert_eq!(batch.complete(&third, true, None, 10_000), CompleteOutcome::Discard);
+        assert_eq!(batch.state, BatchState::Done { succeeded: false });
+    }
+
+    #[test]
+    fn retries_wait_out_their_delay() {
+        let definition = CommandDefinition {
+            name: "deploy".to_owned(),
+            data: String::new(),
+            max_retries: 2,
+            success_required: true,
+            retry_policy: RetryPolicy::Fixed { delay_millis: 500, jitter: false },
+            execution_timeout_millis: None,
+            parallel_with_previous: false,
+        };
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), vec![definition], 1000);
+        let token = match batch.start(0, "first", &[], |attempt| format!("token-{}", attempt), 1000) {
+            StartOutcome::Started { token } => token,
+            _ => panic!("Expected the first attempt to start"),
+        };
+        assert_eq!(batch.complete(&token, false, None, 2000),
+            CompleteOutcome::SameCommand { available_epoch_millis: 2500 });
+        assert_eq!(batch.waiting_until(0, 2499), Some(2500));
+        assert!(matches!(batch.start(0, "second", &[], |attempt| format!("token-{}", attempt), 2499),
+            StartOutcome::NotYetAvailable { available_epoch_millis: 2500 }));
+        assert_eq!(batch.waiting_until(0, 2500), None);
+        // A retried completion is told the same.
+        assert_eq!(batch.complete(&token, false, None, 2500),
+            CompleteOutcome::SameCommand { available_epoch_millis: 2500 });
+        assert!(matches!(batch.start(0, "second", &[], |attempt| format!("token-{}", attempt), 2500), StartOutcome::Started { .. }));
+    }
+
+    #[test]
+    fn attempts_time_out_after_their_execution_timeout() {
+        let mut definition = definition(1, true);
+        definition.execution_timeout_millis = Some(1000);
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), vec![definition], 1000);
+
+        // Heartbeating does not keep an attempt going past its timeout.
+        let first = start(&mut batch, 0, 2000);
+        assert_eq!(batch.heartbeat(&first, 2999), HeartbeatOutcome::Continue);
+        assert!(!batch.is_overdue(2999));
+        assert!(batch.is_overdue(3000));
+        assert_eq!(batch.heartbeat(&first, 3000), HeartbeatOutcome::TimedOut);
+        assert!(matches!(batch.commands[0].attempts[0].state, AttemptState::Done { timed_out: true, .. }));
+        assert_eq!(batch.complete(&first, true, None, 3001), CompleteOutcome::Discard);
+
+        // The retry gets a timeout of its own, which the reaper enforces too.
+        let second = start(&mut batch, 0, 4000);
+        assert_eq!(batch.heartbeat(&second, 4500), HeartbeatOutcome::Continue);
+        assert!(batch.time_out(4000, 4999).is_empty());
+        assert_eq!(batch.time_out(4000, 5000), vec![(0, Vec::new())]);
+        assert_eq!(batch.state, BatchState::Done { succeeded: false });
+    }
+
+    #[test]
+    fn cancelled_batches_discard_everything() {
+        let definitions = vec![definition(1, true), definition(0, true)];
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), definitions, 1000);
+        let token = start(&mut batch, 0, 2000);
+        assert_eq!(batch.cancel("operator", "bad rollout", 3000),
+            CancelOutcome::Cancelled { interrupted: vec![(0, Vec::new())] });
+        assert_eq!(batch.state, BatchState::Cancelled);
+        assert!(!batch.is_executing(0));
+
+        assert_eq!(batch.heartbeat(&token, 4000), HeartbeatOutcome::Discard);
+        assert_eq!(batch.complete(&token, true, None, 4000), CompleteOutcome::Discard);
+        assert!(matches!(batch.start(0, "other", &[], |_| "other".to_owned(), 4000), StartOutcome::Discard));
+        assert_eq!(batch.active_command, 0);
+        assert!(batch.time_out(5000, 5000).is_empty());
+
+        // Only the first cancellation is recorded.
+        assert_eq!(batch.cancel("someone else", "also bad", 6000), CancelOutcome::AlreadyCancelled);
+        assert_eq!(batch.cancellation, Some(Cancellation {
+            cancelled_by: "operator".to_owned(),
+            reason: "bad rollout".to_owned(),
+            cancelled_epoch_millis: 3000,
+        }));
+
+        let mut done = BatchRecord::new("done".to_owned(), "target".to_owned(), vec![definition(0, true)], 1000);
+        let token = start(&mut done, 0, 2000);
+        done.complete(&token, true, None, 3000);
+        assert_eq!(done.cancel("operator", "too late", 4000), CancelOutcome::AlreadyDone);
+        assert_eq!(done.cancellation, None);
+    }
+
+    #[test]
+    fn paused_batches_only_finish_the_running_attempt() {
+        let definitions = vec![definition(1, true), definition(0, true)];
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), definitions, 1000);
+        let token = start(&mut batch, 0, 2000);
+        assert!(batch.pause("operator", "incident", 3000));
+        assert!(batch.pause("someone else", "same incident", 3500));
+        assert!(batch.is_paused());
+        assert_eq!(batch.pauses.len(), 1);
+
+        // The running attempt carries on and its result counts, but the executor is sent
+        // away rather than on to the next command.
+        assert_eq!(batch.heartbeat(&token, 4000), HeartbeatOutcome::Continue);
+        assert_eq!(batch.complete(&token, true, None, 5000), CompleteOutcome::Discard);
+        assert_eq!(batch.commands[0].state, CommandState::Done { succeeded: true });
+        assert_eq!(batch.active_command, 1);
+        assert!(matches!(batch.start(1, "nonce", &[], |_| "token".to_owned(), 6000), StartOutcome::Paused));
+
+        assert!(batch.resume(7000));
+        assert!(!batch.is_paused());
+        assert_eq!(batch.pauses, vec![Pause {
+            paused_by: "operator".to_owned(),
+            reason: "incident".to_owned(),
+            paused_epoch_millis: 3000,
+            resumed_epoch_millis: Some(7000),
+        }]);
+        assert_eq!(batch.complete(&token, true, None, 7500), CompleteOutcome::NextCommand);
+        let token = start(&mut batch, 1, 8000);
+        assert_eq!(batch.complete(&token, true, None, 9000), CompleteOutcome::NextCommand);
+        assert!(!batch.pause("operator", "too late", 10_000));
+        assert!(!batch.resume(10_000));
+    }
+
+    #[test]
+    fn batches_expire_once_the_running_attempt_finishes() {
+        let definitions = vec![definition(1, true), definition(0, true)];
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), definitions, 1000);
+        batch.deadline_epoch_millis = Some(5000);
+        let token = start(&mut batch, 0, 2000);
+        assert!(!batch.is_expired(6000));
+        assert!(!batch.expire(6000));
+
+        // The retry would start after the deadline, so it never does.
+        assert_eq!(batch.complete(&token, false, None, 7000), CompleteOutcome::Discard);
+        assert_eq!(batch.state, BatchState::Done { succeeded: false });
+        assert_eq!(batch.failure, Some(BatchFailure::Expired));
+        assert_eq!(batch.commands[0].state, CommandState::Expired);
+        assert_eq!(batch.commands[0].attempts.len(), 1);
+        assert_eq!(batch.commands[1].state, CommandState::Expired);
+        assert!(matches!(batch.start(0, "nonce", &[], |_| "token".to_owned(), 8000), StartOutcome::Discard));
+    }
+
+    #[test]
+    fn held_batches_start_once_due() {
+        let mut definitions = vec![definition(0, true), definition(0, true), definition(0, true)];
+        definitions[1].parallel_with_previous = true;
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), definitions, 1000);
+        batch.hold_until(5000);
+        assert!(!batch.is_due(4999));
+        for command_index in 0..2 {
+            assert_eq!(batch.waiting_until(command_index, 4999), Some(5000));
+            assert!(matches!(batch.start(command_index, "nonce", &[], |_| "token".to_owned(), 4999),
+                StartOutcome::NotYetAvailable { available_epoch_millis: 5000 }));
+        }
+        assert!(batch.is_due(5000));
+        start(&mut batch, 0, 5000);
+        start(&mut batch, 1, 5000);
+    }
+
+    #[test]
+    fn waiting_batches_gain_priority() {
+        let mut maintenance = BatchRecord::new("maintenance".to_owned(), "target".to_owned(), vec![], 1000);
+        maintenance.priority = -1;
+        let mut hotfix = BatchRecord::new("hotfix".to_owned(), "target".to_owned(), vec![], 5000);
+        hotfix.priority = 5;
+        assert_eq!(maintenance.effective_priority(1000, 1999), -1);
+        assert_eq!(maintenance.effective_priority(1000, 2000), 0);
+        assert!(hotfix.effective_priority(1000, 5000) > maintenance.effective_priority(1000, 5000));
+        // Once it has waited long enough, newly dispatched hotfixes no longer jump ahead.
+        let mut later_hotfix = BatchRecord::new("later-hotfix".to_owned(), "target".to_owned(), vec![], 12_000);
+        later_hotfix.priority = 5;
+        assert!(later_hotfix.effective_priority(1000, 12_000) < maintenance.effective_priority(1000, 12_000));
+    }
+
+    #[test]
+    fn parallel_steps_finish_together() {
+        let parallel = |max_retries, success_required| CommandDefinition {
+            parallel_with_previous: true,
+            ..definition(max_retries, success_required)
+        };
+        let definitions = vec![definition(0, true), parallel(1, true), parallel(0, false), definition(0, true)];
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), definitions, 1000);
+        assert_eq!(batch.current_step(), 0..3);
+        let first = start(&mut batch, 0, 2000);
+        let second = start(&mut batch, 1, 2000);
+        let third = start(&mut batch, 2, 2000);
+        assert!(matches!(batch.start(3, "nonce", &[], |_| "token".to_owned(), 2000), StartOutcome::NotActive));
+
+        // Starting the others does not supersede the first.
+        assert_eq!(batch.heartbeat(&first, 3000), HeartbeatOutcome::Continue);
+        assert_eq!(batch.complete(&first, true, None, 4000), CompleteOutcome::NextCommand);
+        assert_eq!(batch.complete(&third, false, None, 4000), CompleteOutcome::NextCommand);
+        assert_eq!(batch.remaining_commands().map(|(index, _command)| index).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(batch.complete(&second, false, None, 5000), CompleteOutcome::SameCommand { available_epoch_millis: 5000 });
+        assert_eq!(batch.current_step(), 0..3);
+        let second = start(&mut batch, 1, 6000);
+        assert_eq!(batch.complete(&second, true, None, 7000), CompleteOutcome::NextCommand);
+        assert_eq!(batch.current_step(), 3..4);
+        assert_eq!(batch.commands[3].state, CommandState::Active);
+
+        // A required command failing fails the batch straight away, and its siblings are
+        // told to discard it.
+        let definitions = vec![definition(0, true), parallel(0, true)];
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), definitions, 1000);
+        let first = start(&mut batch, 0, 2000);
+        let second = start(&mut batch, 1, 2000);
+        assert_eq!(batch.started_attempt_groups(), vec![(0, Vec::new()), (1, Vec::new())]);
+        assert_eq!(batch.complete(&first, false, None, 3000), CompleteOutcome::Discard);
+        assert_eq!(batch.state, BatchState::Done { succeeded: false });
+        assert_eq!(batch.heartbeat(&second, 3000), HeartbeatOutcome::Discard);
+        assert_eq!(batch.complete(&second, true, None, 4000), CompleteOutcome::Discard);
+        assert_eq!(batch.state, BatchState::Done { succeeded: false });
+        assert_eq!(batch.active_command, 0);
+    }
+
+    #[test]
+    fn superseded_attempts_are_fenced() {
+        let definitions = vec![definition(2, true), definition(0, true)];
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), definitions, 1000);
+        let zombie = start(&mut batch, 0, 2000);
+        assert!(!batch.time_out(3000, 4000).is_empty());
+        let retry = start(&mut batch, 0, 5000);
+        assert_eq!(batch.attempt_epoch, 2);
+
+        // The executor of the timed out attempt wakes up, but can neither heartbeat nor
+        // complete, and changes nothing by trying.
+        let before = serde_json::to_string(&batch).unwrap();
+        assert_eq!(batch.heartbeat(&zombie, 6000), HeartbeatOutcome::Discard);
+        assert_eq!(batch.complete(&zombie, true, None, 6000), CompleteOutcome::Discard);
+        assert_eq!(batch.complete(&zombie, false, None, 6000), CompleteOutcome::Discard);
+        assert_eq!(serde_json::to_string(&batch).unwrap(), before);
+
+        // Only the latest attempt advances the batch, and epochs carry on increasing across
+        // commands.
+        assert_eq!(batch.heartbeat(&retry, 6000), HeartbeatOutcome::Continue);
+        assert_eq!(batch.complete(&retry, true, None, 7000), CompleteOutcome::NextCommand);
+        let next = start(&mut batch, 1, 8000);
+        assert_eq!(batch.attempt_epoch, 3);
+        assert_eq!(batch.complete(&retry, true, None, 9000), CompleteOutcome::Discard);
+        assert_eq!(batch.complete(&next, true, None, 9000), CompleteOutcome::NextCommand);
+        assert_eq!(batch.state, BatchState::Done { succeeded: true });
+    }
+
+    #[test]
+    fn completion_notifications_are_pending_until_sent() {
+        let channel = NotificationChannel::SQS { queue_url: "queue".to_owned() };
+        let mut batch = BatchRecord::new("batch".to_owned(), "target".to_owned(), vec![definition(0, true)], 1000);
+        batch.complete_notification = Some(CompleteNotification {
+            channel,
+            state: NotificationState::Pending { failures: 0 },
+        });
+        assert!(!batch.is_notification_pending());
+        assert_eq!(batch.record_notification(true, 3, 1500), None);
+
+        let token = start(&mut batch, 0, 2000);
+        batch.complete(&token, false, None, 3000);
+        assert!(batch.is_notification_pending());
+        assert!(batch.is_outstanding());
+        assert_eq!(batch.record_notification(false, 3, 4000), Some(NotificationState::Pending { failures: 1 }));
+        assert_eq!(batch.record_notification(true, 3, 5000),
+            Some(NotificationState::Delivered { delivered_epoch_millis: 5000 }));
+        assert!(!batch.is_outstanding());
+        // Delivering it again, eg from another host's reaper, changes nothing.
+        assert_eq!(batch.record_notification(true, 3, 6000), None);
+
+        let mut cancelled = BatchRecord::new("cancelled".to_owned(), "target".to_owned(), vec![definition(0, true)], 1000);
+        cancelled.complete_notification = batch.complete_notification.clone()
+            .map(|notification| CompleteNotification { state: NotificationState::Pending { failures: 1 }, ..notification });
+        cancelled.cancel("operator", "bad rollout", 2000);
+        assert_eq!(cancelled.record_notification(false, 3, 3000), Some(NotificationState::Pending { failures: 2 }));
+        assert_eq!(cancelled.record_notification(false, 3, 4000), Some(NotificationState::Abandoned));
+        assert!(!cancelled.is_outstanding());
+    }
+}
@@ -0,0 +1,128 @@
+// Dispatches a batch for every schedule whose next run has come due. Every host runs a
+// scheduler. A run is dispatched under an idempotency key made from the schedule name and
+// the run's time, so hosts racing to dispatch the same run still only create one batch.
+//
+// Runs missed while no host was up are not made up for one by one. The first of them is
+// dispatched late, and the rest are skipped.
+
+use crate::cron::Cron;
+use crate::database::DatabaseError;
+use crate::metrics::METRICS;
+use crate::operations::{Context, dispatch_once, now_epoch_millis};
+use crate::records::BatchRecord;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tokio::task::LocalSet;
+use tokio::time::delay_for;
+
+// Runs are dispatched up to this late.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn start_scheduler_thread(context: Arc<Context>) -> JoinHandle<()> {
+    std::thread::Builder::new()
+        .name("dispatch-scheduler".to_owned())
+        .spawn(move || {
+            let mut rt = tokio::runtime::Builder::new()
+                .basic_scheduler()
+                .enable_all()
+                .build()
+                .expect("Failed to build tokio runtime on scheduler thread");
+            // Interrupting polls on other hosts spawns local tasks.
+            LocalSet::new().block_on(&mut rt, async move {
+                loop {
+                    delay_for(SCHEDULE_INTERVAL).await;
+                    match dispatch_due_runs(&context, now_epoch_millis()).await {
+                        Ok(dispatched) => {
+                            METRICS.scheduled_runs_dispatched.fetch_add(dispatched, Ordering::Relaxed);
+                        },
+                        Err(_err) => {
+                            // TODO: warn log
+                        }
+                    }
+                }
+            });
+        })
+        .expect("Failed to spawn scheduler thread")
+}
+
+// Returns how many runs were dispatched, including ones which another host had already
+// dispatched.
+async fn dispatch_due_runs(context: &Context, now: usize) -> Result<usize, DatabaseError> {
+    let mut dispatched = 0;
+    for schedule in context.database.list_schedules().await? {
+        // PutSchedule only accepts valid expressions which match at some point.
+        let run = Cron::parse(&schedule.cron).ok()
+            .and_then(|cron| cron.next_after(schedule.last_run_epoch_millis));
+        let run = match run {
+            Some(run) if run <= now => run,
+            _ => continue,
+        };
+        // The key alone identifies the run, so there is no request to compare hashes of.
+        let key = format!("Schedule#{}#{}", schedule.name, run);
+        dispatch_once(context, key, String::new(), now, |batch_id| async {
+            let mut batch = BatchRecord::new(batch_id, schedule.target_name.clone(), schedule.commands.clone(), now);
+            batch.deadline_epoch_millis = context.default_deadline_epoch_millis(now);
+            batch.priority = schedule.priority;
+            Ok(Some(batch))
+        }).await?;
+        context.database.update_schedule(&schedule.name, |schedule| {
+            schedule.last_run_epoch_millis = schedule.last_run_epoch_millis.max(now);
+        }).await?;
+        dispatched += 1;
+    }
+    Ok(dispatched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operations::testing;
+    use crate::records::{CommandDefinition, RetryPolicy, ScheduleRecord};
+
+    #[tokio::test]
+    async fn dispatches_each_run_once() {
+        let mut context = testing::context();
+        context.default_batch_deadline = Some(Duration::from_secs(60));
+        // 2024-01-01T00:00:00Z.
+        let midnight = 1_704_067_200_000;
+        let schedule = ScheduleRecord {
+            name: "nightly".to_owned(),
+            target_name: "target".to_owned(),
+            cron: "0 0 * * *".to_owned(),
+            commands: vec![CommandDefinition {
+                name: "vacuum".to_owned(),
+                data: String::new(),
+                max_retries: 0,
+                success_required: true,
+                retry_policy: RetryPolicy::Immediate,
+                execution_timeout_millis: None,
+                parallel_with_previous: false,
+            }],
+            priority: -10,
+            last_run_epoch_millis: midnight - 60_000,
+        };
+        assert!(context.database.create_schedule(schedule).await.unwrap());
+
+        assert_eq!(dispatch_due_runs(&context, midnight - 1).await.unwrap(), 0);
+        assert_eq!(dispatch_due_runs(&context, midnight + 5_000).await.unwrap(), 1);
+        assert_eq!(dispatch_due_runs(&context, midnight + 10_000).await.unwrap(), 0);
+        // Another host which read the schedule before the run was recorded dispatches the
+        // same batch.
+        context.database.update_schedule("nightly", |schedule| schedule.last_run_epoch_millis = midnight - 60_000).await.unwrap();
+        assert_eq!(dispatch_due_runs(&context, midnight + 10_000).await.unwrap(), 1);
+        let batches = context.database.list_active_batches("target").await.unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].deadline_epoch_millis, Some(midnight + 65_000));
+        assert_eq!(batches[0].priority, -10);
+
+        // Missed runs are dispatched once.
+        let three_days_later = midnight + 3 * 24 * 60 * 60 * 1000 + 5_000;
+        assert_eq!(dispatch_due_runs(&context, three_days_later).await.unwrap(), 1);
+        assert_eq!(dispatch_due_runs(&context, three_days_later).await.unwrap(), 0);
+        assert_eq!(context.database.list_active_batches("target").await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,55 @@
+// Background cleanup of records which are no longer needed. Runs on its own thread, so
+// that slow database scans never hold up request handling on the worker threads.
+
+use crate::database::Database;
+use crate::metrics::METRICS;
+use crate::operations::now_epoch_millis;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tokio::time::delay_for;
+
+const MAX_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Periodically deletes idempotency records and batch tombstones older than
+// idempotency_window, along with expired poll presence records. Until an idempotency record
+// is deleted it is still honored, so the window is a lower bound on how long a nonce is
+// remembered, and likewise for how long executors of a deleted batch are told to discard it.
+pub fn start_sweeper_thread(database: Arc<Database>, idempotency_window: Duration) -> JoinHandle<()> {
+    let sweep_interval = idempotency_window.clamp(MIN_SWEEP_INTERVAL, MAX_SWEEP_INTERVAL);
+    std::thread::Builder::new()
+        .name("dispatch-sweeper".to_owned())
+        .spawn(move || {
+            let mut rt = tokio::runtime::Builder::new()
+                .basic_scheduler()
+                .enable_all()
+                .build()
+                .expect("Failed to build tokio runtime on sweeper thread");
+            rt.block_on(async move {
+                loop {
+                    delay_for(sweep_interval).await;
+                    let created_before = now_epoch_millis()
+                        .saturating_sub(idempotency_window.as_millis() as usize);
+                    match database.delete_idempotency_records(created_before).await {
+                        Ok(collected) => {
+                            METRICS.idempotency_records_collected.fetch_add(collected, Ordering::Relaxed);
+                        },
+                        Err(_err) => {
+                            // TODO: warn log
+                        }
+                    }
+                    if let Err(_err) = database.delete_batch_tombstones(created_before).await {
+                        // TODO: warn log
+                    }
+                    if let Err(_err) = database.delete_poll_presence(now_epoch_millis()).await {
+                        // TODO: warn log
+                    }
+                }
+            });
+        })
+        .expect("Failed to spawn sweeper thread")
+}
@@ -0,0 +1,164 @@
+// Attempt tokens handed out by StartCommand. Tokens are signed with HMAC-SHA256, so
+// heartbeats and completions carrying a forged token, or a token issued for another batch,
+// are rejected without reading the batch.
+//
+// The keyring holds the active key, which signs new tokens, and any number of previous
+// keys, which are only used to verify tokens signed before a rotation. Every token names
+// the key it was signed with. To rotate, make the new key active and keep the old one as a
+// previous key until every attempt started with it is done.
+//
+// A token is <key id>.<hex encoded JSON claims>.<hex encoded signature>.
+
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+pub struct Keyring {
+    active: Key,
+    previous: Vec<Key>,
+}
+
+struct Key {
+    id: String,
+    secret: Vec<u8>,
+}
+
+// What a token vouches for.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct AttemptClaims {
+    pub batch_id: String,
+    pub command_index: usize,
+    // 0-based index of the attempt within the command.
+    pub attempt: usize,
+    pub issued_epoch_millis: usize,
+}
+
+impl Keyring {
+    // Parses a comma separated list of <key id>=<secret>, the first of which is the active
+    // key.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut keys = spec.split(',')
+            .map(|key| {
+                let (id, secret) = key.trim().split_once('=')
+                    .ok_or_else(|| format!("Key {:?} is not of the form <key id>=<secret>", key))?;
+                if id.is_empty() || id.contains('.') {
+                    return Err(format!("Key id {:?} is empty or contains a '.'", id));
+                }
+                if secret.is_empty() {
+                    return Err(format!("Key {:?} has an empty secret", id));
+                }
+                Ok(Key { id: id.to_owned(), secret: secret.as_bytes().to_vec() })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let active = keys.remove(0);
+        Ok(Self { active, previous: keys })
+    }
+
+    // A keyring with a single random key, which only works while every token is verified
+    // by the same process that issued it.
+    pub fn random() -> Self {
+        let mut secret = Uuid::new_v4().as_bytes().to_vec();
+        secret.extend_from_slice(Uuid::new_v4().as_bytes());
+        Self {
+            active: Key { id: "random".to_owned(), secret },
+            previous: Vec::new(),
+        }
+    }
+
+    pub fn issue(&self, claims: &AttemptClaims) -> String {
+        let claims = hex::encode(serde_json::to_vec(claims).expect("Attempt claims always encode"));
+        let signature = hex::encode(self.active.mac(&claims).finalize().into_bytes());
+        format!("{}.{}.{}", self.active.id, claims, signature)
+    }
+
+    // The claims of a token signed by any key in the keyring, or None if the token was not
+    // issued by this keyring.
+    pub fn verify(&self, token: &str) -> Option<AttemptClaims> {
+        let mut parts = token.split('.');
+        let (key_id, claims, signature) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        let key = std::iter::once(&self.active)
+            .chain(&self.previous)
+            .find(|key| key.id == key_id)?;
+        key.mac(claims).verify(&hex::decode(signature).ok()?).ok()?;
+        serde_json::from_slice(&hex::decode(claims).ok()?).ok()
+    }
+}
+
+impl Key {
+    fn mac(&self, claims: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.secret).expect("HMAC accepts keys of any length");
+        // Binds the key id too, so a token cannot be moved between keys sharing a secret.
+        mac.update(self.id.as_bytes());
+        mac.update(b".");
+        mac.update(claims.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims() -> AttemptClaims {
+        AttemptClaims {
+            batch_id: "batch".to_owned(),
+            command_index: 1,
+            attempt: 2,
+            issued_epoch_millis: 1000,
+        }
+    }
+
+    #[test]
+    fn verifies_issued_tokens() {
+        let keyring = Keyring::parse("current=secret").unwrap();
+        let token = keyring.issue(&claims());
+        assert!(token.starts_with("current."));
+        assert_eq!(keyring.verify(&token), Some(claims()));
+    }
+
+    #[test]
+    fn rejects_forged_tokens() {
+        let keyring = Keyring::parse("current=secret").unwrap();
+        let token = keyring.issue(&claims());
+        let parts = token.split('.').collect::<Vec<_>>();
+
+        let mut other = claims();
+        other.batch_id = "other".to_owned();
+        let other_claims = hex::encode(serde_json::to_vec(&other).unwrap());
+        assert_eq!(keyring.verify(&format!("{}.{}.{}", parts[0], other_claims, parts[2])), None);
+        assert_eq!(keyring.verify(&format!("{}.{}.{}", parts[0], parts[1], "00")), None);
+        assert_eq!(keyring.verify(&format!("{}.{}.{}.", parts[0], parts[1], parts[2])), None);
+        assert_eq!(keyring.verify("garbage"), None);
+        assert_eq!(keyring.verify(""), None);
+
+        let other_keyring = Keyring::parse("current=other-secret").unwrap();
+        assert_eq!(other_keyring.verify(&token), None);
+    }
+
+    #[test]
+    fn rotates_keys() {
+        let old = Keyring::parse("old=first").unwrap();
+        let rotated = Keyring::parse("new=second, old=first").unwrap();
+        let retired = Keyring::parse("new=second").unwrap();
+        let old_token = old.issue(&claims());
+        let new_token = rotated.issue(&claims());
+        assert!(new_token.starts_with("new."));
+        assert_eq!(rotated.verify(&old_token), Some(claims()));
+        assert_eq!(rotated.verify(&new_token), Some(claims()));
+        assert_eq!(retired.verify(&old_token), None);
+        assert_eq!(retired.verify(&new_token), Some(claims()));
+    }
+
+    #[test]
+    fn rejects_invalid_keyrings() {
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("id").is_err());
+        assert!(Keyring::parse("id=").is_err());
+        assert!(Keyring::parse("a.b=secret").is_err());
+        assert!(Keyring::parse("a=secret,b").is_err());
+    }
+}